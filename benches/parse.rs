@@ -0,0 +1,31 @@
+//! Baseline performance numbers for the parser.
+//! Run with `cargo bench`; Criterion writes reports under target/criterion/
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use earleybird::builtin_grammars::{ParserTestSet, SmokeNT, SmokeWiki};
+use earleybird::ixml_grammar::{ixml_grammar, ixml_str_to_grammar};
+use earleybird::parser::Parser;
+
+/// building the hand-coded bootstrap grammar
+fn bench_bootstrap_grammar(c: &mut Criterion) {
+    c.bench_function("bootstrap ixml grammar", |b| b.iter(ixml_grammar));
+}
+
+/// a medium-sized arithmetic expression against the wiki grammar
+fn bench_wiki_parse(c: &mut Criterion) {
+    let grammar = SmokeWiki{}.get_grammar();
+    let input = "1+2*3+4".repeat(20).replace("41", "4+1");
+    c.bench_function("parse wiki expression", |b| b.iter(|| {
+        let mut parser = Parser::new(grammar.clone());
+        parser.parse(black_box(&input)).unwrap()
+    }));
+}
+
+/// parsing ixml grammar source with the bootstrap grammar
+fn bench_ixml_parse(c: &mut Criterion) {
+    let ixml = SmokeNT{}.get_ixml();
+    c.bench_function("parse ixml grammar", |b| b.iter(|| ixml_str_to_grammar(black_box(ixml)).unwrap()));
+}
+
+criterion_group!(benches, bench_bootstrap_grammar, bench_wiki_parse, bench_ixml_parse);
+criterion_main!(benches);
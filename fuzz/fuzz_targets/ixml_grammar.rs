@@ -0,0 +1,10 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use earleybird::ixml_grammar::ixml_str_to_grammar;
+
+// Any malformed grammar should come back as an Err, never a panic
+fuzz_target!(|data: &[u8]| {
+    if let Ok(ixml) = std::str::from_utf8(data) {
+        let _ = ixml_str_to_grammar(ixml);
+    }
+});
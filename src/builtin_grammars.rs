@@ -33,12 +33,18 @@ impl SmokeTests {
         let grammar = testset.get_grammar();
         let ins_outs = testset.get_inputs_expected();
         for (input, expected) in ins_outs {
+            // an empty expectation means the input shouldn't parse at all
+            let expected = if expected.is_empty() {
+                TestResult::AssertNotASentence
+            } else {
+                TestResult::AssertXml(expected.to_string())
+            };
             self.tests.push(
                 TestCase {
                     name: name.to_owned(),
                     grammars: vec![TestGrammar::Unparsed(ixml.to_string()), TestGrammar::Parsed(grammar.clone())],
                     input: input.to_string(),
-                    expected: vec![TestResult::AssertXml(expected.to_string())],
+                    expected: vec![expected],
                 }
             )
        }
@@ -68,6 +74,7 @@ pub fn all_builtin_tests() -> SmokeTests {
     tests.add(&SmokeElem {});
     tests.add(&SmokeAttr {});
     tests.add(&SmokeMute {});
+    tests.add(&SmokeComment {});
     tests.add(&SmokeWiki {});
     tests
 }
@@ -86,6 +93,7 @@ pub struct SmokePlusSep {}
 pub struct SmokeElem {}
 pub struct SmokeAttr {}
 pub struct SmokeMute {}
+pub struct SmokeComment {}
 
 // test suites
 pub struct SmokeWiki {}
@@ -405,6 +413,34 @@ impl ParserTestSet for SmokeMute {
 }
 
 
+/// comments go anywhere whitespace can, nest, and leave no trace in the grammar
+impl ParserTestSet for SmokeComment {
+    fn get_name(&self) -> &'static str { "SmokeComment" }
+    fn get_ixml(&self) -> &'static str {
+        indoc! {r#"
+            {leading comment} doc = a, {inline {nested} comment} b.
+            {between rules; with "punctuation" & such}
+            a = "a".
+            b = "b". {trailing comment}
+        "#}
+    }
+    fn get_grammar(&self) -> Grammar {
+        let mut g = Grammar::new();
+        let ctx = RuleContext::new("doc");
+        g.define("doc", ctx.seq().nt("a").nt("b") );
+        let ctx = RuleContext::new("a");
+        g.define("a", ctx.seq().ch('a') );
+        let ctx = RuleContext::new("b");
+        g.define("b", ctx.seq().ch('b') );
+        g
+    }
+    fn get_inputs_expected(&self) -> Vec<(&'static str, &'static str)> {
+        vec![
+            ("ab", "<doc><a>a</a><b>b</b></doc>"),
+            ]
+    }
+}
+
 /// The example grammar from https://en.wikipedia.org/wiki/Earley_parser
 impl ParserTestSet for SmokeWiki {
     fn get_name(&self) -> &'static str { "SmokeWiki" }
@@ -1,5 +1,6 @@
-use std::ffi::OsString;
+use std::{ffi::OsString, fs, path::{Path, PathBuf}, process};
 use argh::FromArgs;
+use earleybird::{ixml_grammar::ixml_str_to_grammar, parser::Parser};
 
 #[derive(FromArgs)]
 /// Read an ixml file and parse another file with that grammar
@@ -9,34 +10,134 @@ pub struct Parse {
     #[argh(option, short = 'g')]
     grammar: OsString,
 
-    /// input document
+    /// input document, or a glob like "data/*.txt" to parse every matching file.
+    /// A single document's output goes to stdout; with a glob, each file's goes next to it, with an .xml extension
     #[argh(option, short = 'i')]
     input: OsString,
 
-    /// output format
+    /// output format: XML (compact, the default) or XML-PRETTY (indented, with an XML declaration)
     #[argh(option, short = 'o', default = "default_output_fmt()")]
     out_format: String,
+
+    /// after each parse, report (on stderr) the rules that accounted for the most parser tasks
+    #[argh(switch)]
+    measure: bool,
 }
 
+/// how many of the busiest rules `--measure` reports
+const MEASURE_TOP: usize = 10;
+
 impl Parse {
     pub fn run(self) {
-        todo!()
         // 1. Read ixml grammar file
+        let ixml = fs::read_to_string(&self.grammar).unwrap_or_else(|e| {
+            eprintln!("can't read {}: {e}", self.grammar.to_string_lossy());
+            process::exit(1);
+        });
 
         // 1.5 Validate grammar
-
         // 2. Parse ixml grammar file
-
         // 3. Generate target grammar
+        let grammar = ixml_str_to_grammar(&ixml).unwrap_or_else(|e| {
+            eprintln!("{e}");
+            process::exit(1);
+        });
+        let mut parser = Parser::new(grammar);
+        let pretty = match self.out_format.as_str() {
+            "XML" => false,
+            "XML-PRETTY" => true,
+            other => {
+                eprintln!("unsupported output format {other}");
+                process::exit(1);
+            }
+        };
+
+        let pattern = self.input.to_string_lossy();
+        if !pattern.contains(['*', '?', '[']) {
+            match parse_file(&mut parser, Path::new(&self.input), pretty, self.measure) {
+                Ok(output) => print!("{output}"),
+                Err(e) => {
+                    eprintln!("{e}");
+                    process::exit(1);
+                }
+            }
+            return;
+        }
 
-        // 4. Read input file
+        let paths = glob::glob(&pattern).unwrap_or_else(|e| {
+            eprintln!("bad input pattern {pattern}: {e}");
+            process::exit(1);
+        }).filter_map(Result::ok).filter(|path| path.is_file()).collect::<Vec<_>>();
+        if paths.is_empty() {
+            eprintln!("no files match {pattern}");
+            process::exit(1);
+        }
 
-        // 5. Parse input file against target grammar
+        // one parser, and the grammar compiled once above, for every file
+        let mut failures = 0;
+        for path in paths {
+            match convert_file(&mut parser, &path, pretty, self.measure) {
+                Ok(out_path) => println!("{} -> {}", path.display(), out_path.display()),
+                Err(e) => {
+                    eprintln!("{e}");
+                    failures += 1;
+                }
+            }
+        }
+        if failures > 0 {
+            process::exit(1);
+        }
+    }
+}
+
+/// parse one input file, returning the formatted output, ending in a newline
+fn parse_file(parser: &mut Parser, path: &Path, pretty: bool, measure: bool) -> Result<String, String> {
+    // 4. Read input file
+    let input = fs::read_to_string(path).map_err(|e| format!("can't read {}: {e}", path.display()))?;
+
+    // 5. Parse input file against target grammar
+    let tree = parser.parse(&input).map_err(|e| format!("{}: {e}", path.display()))?;
+    if measure {
+        report_task_counts(parser, path);
+    }
 
-        // 6. Format output
+    // 6. Format output
+    if pretty {
+        Ok(Parser::tree_to_xml(&tree, true))
+    } else {
+        Ok(Parser::tree_to_testfmt(&tree) + "\n")
+    }
+}
+
+/// parse one file of a batch, writing the output alongside it. Returns where the output went
+fn convert_file(parser: &mut Parser, path: &Path, pretty: bool, measure: bool) -> Result<PathBuf, String> {
+    let output = parse_file(parser, path, pretty, measure)?;
+    let out_path = output_path(path);
+    fs::write(&out_path, output).map_err(|e| format!("can't write {}: {e}", out_path.display()))?;
+    Ok(out_path)
+}
+
+/// print the rules with the most tasks, busiest first
+fn report_task_counts(parser: &Parser, path: &Path) {
+    let mut counts = parser.rule_task_counts().into_iter().collect::<Vec<_>>();
+    counts.sort_by(|(name1, count1), (name2, count2)| count2.cmp(count1).then(name1.cmp(name2)));
+    let total: usize = counts.iter().map(|(_, count)| count).sum();
+    eprintln!("{}: {total} tasks", path.display());
+    for (name, count) in counts.into_iter().take(MEASURE_TOP) {
+        eprintln!("{count:>10}  {name}");
+    }
+}
+
+/// where a batch-parsed file's output goes: alongside it, with an .xml extension (never overwriting the input itself)
+fn output_path(input: &Path) -> PathBuf {
+    let out_path = input.with_extension("xml");
+    if out_path == input {
+        input.with_extension("out.xml")
+    } else {
+        out_path
     }
 }
 
 fn default_output_fmt() -> String {
     "XML".to_string()
-}
\ No newline at end of file
+}
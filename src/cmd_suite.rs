@@ -1,7 +1,8 @@
 
 use argh::FromArgs;
-use earleybird::{testsuite_utils::{self, xml_canonicalize, TestGrammar}, parser::Parser, ixml_grammar::ixml_str_to_grammar, grammar::{Grammar, Rule, RuleContext}};
+use earleybird::{testsuite_utils::{self, xml_canonicalize, TestGrammar, TestResult}, parser::{Parser, ParseError}, ixml_grammar::ixml_str_to_grammar};
 use crate::cmd_suite::testsuite_utils::TestResult::*;
+use log::{info, debug};
 
 #[derive(FromArgs)]
 /// Run the test suite in the specified directory
@@ -13,9 +14,9 @@ pub struct RunSuite {
 }
 
 fn run(dir: String) {
-    println!("_{}_", dir);
+    info!("_{}_", dir);
 
-    let tests = testsuite_utils::read_test_catalog(dir);
+    let tests = testsuite_utils::read_test_catalog_iter(dir);
 
     // stats
     let mut count = 0;
@@ -26,56 +27,42 @@ fn run(dir: String) {
 
     for test in tests {
         let name = test.name;
-        println!("🧪 Test {name}");
+        info!("🧪 Test {name}");
 
         count += 1;
         let grammar = test.grammars.into_iter().next().expect("no grammars available for this test");
-        println!("{grammar}");
+        debug!("{grammar}");
         let target_grammar = match grammar {
-            TestGrammar::Parsed(g) => g,
-            TestGrammar::Unparsed(ixml) => {
-                ixml_str_to_grammar(&ixml).unwrap_or_else(|e| {
-                     abort+=1;
-                     failures.push(name.clone());
-                     println!("{e}");
-                     let mut g = Grammar::new();
-                     let ctx = RuleContext::new("error");
-                     g.define("error", ctx.seq().ch_in(&e.to_string())); // hack
-                     g
-                })
-            }
+            TestGrammar::Parsed(g) => Ok(g),
+            TestGrammar::Unparsed(ixml) => ixml_str_to_grammar(&ixml),
         };
+        let grammar_failed = target_grammar.is_err();
+        let result = target_grammar.and_then(|g| {
+            let mut target_parser = Parser::new(g);
+            target_parser.parse(&test.input).map(|tree| Parser::tree_to_testfmt(&tree))
+        });
+        if let Err(e) = &result {
+            info!("{e}");
+        }
 
-        let mut target_parser = Parser::new(target_grammar);
-        let input = test.input;
-        let target_tree = match target_parser.parse(&input) {
-            Ok(tree) => tree,
-            Err(e) => {
-                println!("{e}");
-                fail += 1;
-                failures.push(name.clone());
-                break;
+        // the expected results are alternatives: any one of them matching is a pass
+        let mut mismatches: Vec<String> = Vec::new();
+        let passed = test.expected.iter().any(|expected| match check_result(expected, &result) {
+            Ok(()) => true,
+            Err(mismatch) => {
+                mismatches.push(mismatch);
+                false
             }
-        };
-        let target_xml = Parser::tree_to_testfmt(&target_tree);
-
-        let expecteds = test.expected;
-
-        // TODO: package this better
-        for expected in expecteds {
-            let passed = match expected {
-                AssertNotASentence => (todo!()),
-                AssertDynamicError(_de) => todo!(),
-                AssertXml(x) => {
-                    xml_canonicalize(&target_xml) == xml_canonicalize(&x)
-                }
-            };
-            if passed {
-                pass += 1;
+        });
+        if passed {
+            pass += 1;
+        } else {
+            if grammar_failed {
+                abort += 1;
             } else {
                 fail += 1;
-                failures.push(name.clone());
             }
+            failures.push(format!("{name}: {}", mismatches.join("; ")));
         }
     }
 
@@ -84,6 +71,24 @@ fn run(dir: String) {
     println!("{}", failures.join("\n"));
 }
 
+/// Compare one expected result against what the test actually produced, describing any mismatch
+fn check_result(expected: &TestResult, result: &Result<String, ParseError>) -> Result<(), String> {
+    match (expected, result) {
+        (AssertNotASentence, Err(ParseError::DynamicError(_))) => Ok(()),
+        (AssertNotASentence, Err(e)) => Err(format!("expected no parse, got {e}")),
+        (AssertNotASentence, Ok(_)) => Err("expected no parse, but it parsed".to_string()),
+        (AssertDynamicError(code), Err(e)) => match e.code() {
+            Some(actual) if actual.to_string() == *code => Ok(()),
+            Some(actual) => Err(format!("expected error {code}, got {actual}")),
+            None => Err(format!("expected error {code}, got {e}")),
+        },
+        (AssertDynamicError(code), Ok(_)) => Err(format!("expected error {code}, but it parsed")),
+        (AssertXml(x), Ok(xml)) if xml_canonicalize(xml) == xml_canonicalize(x) => Ok(()),
+        (AssertXml(_), Ok(_)) => Err("output differs from expected XML".to_string()),
+        (AssertXml(_), Err(e)) => Err(format!("expected XML, got {e}")),
+    }
+}
+
 impl RunSuite {
     pub fn run(self) {
         let _result = run(self.dir);
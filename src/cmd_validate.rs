@@ -0,0 +1,32 @@
+use std::{ffi::OsString, fs, process};
+use argh::FromArgs;
+use earleybird::ixml_grammar::ixml_str_to_grammar;
+
+#[derive(FromArgs)]
+/// Compile an ixml grammar and report any problems, without parsing an input document
+#[argh(subcommand, name = "validate")]
+pub struct Validate {
+    /// ixml grammar file
+    #[argh(option, short = 'g')]
+    grammar: OsString,
+}
+
+impl Validate {
+    pub fn run(self) {
+        let ixml = fs::read_to_string(&self.grammar).unwrap_or_else(|e| {
+            eprintln!("can't read {}: {e}", self.grammar.to_string_lossy());
+            process::exit(1);
+        });
+        let grammar = ixml_str_to_grammar(&ixml).unwrap_or_else(|e| {
+            eprintln!("{e}");
+            process::exit(1);
+        });
+
+        // everything below is a warning: the grammar is still usable
+        for name in grammar.find_unused_rules() {
+            println!("warning: rule {name} is never used");
+        }
+        print!("{}", grammar.conflict_report());
+        println!("{}: {} rules OK", self.grammar.to_string_lossy(), grammar.get_rule_count());
+    }
+}
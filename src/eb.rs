@@ -1,10 +1,15 @@
 use argh::FromArgs;
 mod cmd_parse;
 mod cmd_suite;
+mod cmd_validate;
 
 #[derive(FromArgs)]
 /// An experimental ixml implementation in Rust
 struct Args {
+    /// suppress all logging, regardless of RUST_LOG. Only the requested output goes to stdout
+    #[argh(switch, short = 'q')]
+    quiet: bool,
+
     #[argh(subcommand)]
     subcommand: Subcommand,
 }
@@ -15,6 +20,7 @@ struct Args {
 enum Subcommand {
     Parse(cmd_parse::Parse),
     Suite(cmd_suite::RunSuite),
+    Validate(cmd_validate::Validate),
 }
 
 impl Subcommand {
@@ -22,13 +28,20 @@ impl Subcommand {
         match self {
             Subcommand::Parse(cmd) => cmd.run(),
             Subcommand::Suite(cmd) => cmd.run(),
+            Subcommand::Validate(cmd) => cmd.run(),
         }
     }
 }
 
 fn main() {
-    env_logger::init();
+    let args = argh::from_env::<Args>();
+
+    let mut logger = env_logger::Builder::from_default_env();
+    if args.quiet {
+        logger.filter_level(log::LevelFilter::Off);
+    }
+    logger.init();
 
-    argh::from_env::<Args>().subcommand.run();
+    args.subcommand.run();
 }
 
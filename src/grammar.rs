@@ -23,21 +23,33 @@
 //! More complicated structures like x? or x+ or x* or x++y or x**y
 //! are built from the existing primitives and recursive definitions
 //! 
+//! These synthesized rules are always muted, and named `--{rulename}.{hint}{n}` where
+//! `rulename` is the rule being built, `n` is a counter from its `RuleContext`, and `hint` is one of
+//! `f-option` (?), `f-star` (*), `f-plus` (+), `f-star-sep` (**), `f-plus-sep` (++), or `f-opt` (parenthesized alts)
+//! 
 //! This module includes an ergonomic interface for building grammars by hand,
 //! or from the output of upstream processes (including ixml parsing!)
 
-use std::{fmt, collections::HashMap, cell::Cell};
+use std::{fmt, collections::{HashMap, HashSet, VecDeque}, cell::Cell};
 use smol_str::SmolStr;
-use crate::{parser::DotNotation, unicode_ranges::UnicodeRange};
+use indexmap::IndexMap;
+use indextree::{Arena, NodeId};
+use log::{debug, warn};
+use crate::{parser::{DotNotation, Content}, unicode_ranges::UnicodeRange};
 
 // TODO: Optimization: add CharMatchers at the Grammar level
 
 /// the primary owner of all grammar data structures
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Grammar {
     definitions: HashMap<SmolStr, BranchingRule>,
     /// remember insertion order of rules (used for tests & comparing grammars)
     pub defn_order: Vec<SmolStr>,
+    /// match every literal regardless of case
+    case_insensitive: bool,
+    /// rules whose matches come out as comments rather than elements
+    comment_rules: HashSet<SmolStr>,
 }
 
 impl Grammar {
@@ -45,9 +57,47 @@ impl Grammar {
         Self {
             definitions: HashMap::new(),
             defn_order: Vec::new(),
+            case_insensitive: false,
+            comment_rules: HashSet::new(),
         }
     }
 
+    /// Make every terminal in the grammar match without regard to case, e.g. "select" also matches "SELECT" or "Select".
+    /// Takes effect at match time, so it applies to rules defined before or after this call
+    pub fn set_case_insensitive(&mut self, case_insensitive: bool) {
+        self.case_insensitive = case_insensitive;
+    }
+
+    pub fn is_case_insensitive(&self) -> bool {
+        self.case_insensitive
+    }
+
+    /// Emit whatever the named rule matches as a `Content::Comment` holding its text, instead of as an element
+    pub fn set_comment_rule(&mut self, rule_name: &str) {
+        self.comment_rules.insert(SmolStr::new(rule_name));
+    }
+
+    pub fn is_comment_rule(&self, rule_name: &str) -> bool {
+        self.comment_rules.contains(rule_name)
+    }
+
+    /// The grammar that parses ixml grammars. Useful for ixml-aware tooling (editors, validators)
+    /// The output of parsing with this can be turned into a `Grammar` via `ixml_grammar::ixml_tree_to_grammar`
+    pub fn ixml() -> Self {
+        crate::ixml_grammar::ixml_grammar()
+    }
+
+    /// Like `Grammar::ixml`, but each `{...}` comment comes out as a `Content::Comment`, for tools like formatters
+    /// that need to keep them. The tree still turns into the same `Grammar`
+    pub fn ixml_with_comments() -> Self {
+        crate::ixml_grammar::ixml_grammar_with_comments()
+    }
+
+    /// The source text, in ixml compact syntax, of the grammar that `Grammar::ixml` hand-codes
+    pub fn ixml_source_string() -> &'static str {
+        crate::ixml_grammar::IXML_SOURCE
+    }
+
     pub fn get_rule_count(&self) -> usize {
         assert_eq!(self.definitions.len(), self.defn_order.len());
         self.definitions.len()
@@ -75,9 +125,7 @@ impl Grammar {
         branching_rule.add_alt_branch(main_rule);
         
         // 2) synthesized rules
-        //for (syn_name, builders) in sb.syn_rules {
-        for syn_name in sb.defn_order {
-            let builders = sb.syn_rules[&syn_name].to_vec(); // TODO: NOT copy
+        for (syn_name, builders) in sb.syn_rules {
             for builder in builders {
                 let syn_branching_rule = self.definitions.entry(syn_name.clone())
                     .or_insert_with(|| {
@@ -89,6 +137,30 @@ impl Grammar {
         }
     }
 
+    /// All sugar (?, *, +, **, ++, and parenthesized alts) is expanded into explicit synthesized rules
+    /// as soon as a `SeqBuilder` is merged via `define`/`mark_define`, so nothing here is lazy.
+    /// Returns the names of every synthesized rule, in definition order
+    pub fn expand_sugar(&self) -> Vec<String> {
+        self.defn_order.iter()
+            .filter(|name| Self::is_synthesized_name(name))
+            .map(smol_str::SmolStr::to_string)
+            .collect()
+    }
+
+    /// Synthesized rule names start with "--", which can never begin an ixml name.
+    /// They are also not valid XML names, so the parser must never emit them as elements or attributes
+    pub fn is_synthesized_name(name: &str) -> bool {
+        name.starts_with("--")
+    }
+
+    /// attach a pragma (name and data) to an already-defined rule. Pragmas are metadata only; they don't affect parsing
+    pub fn add_pragma(&mut self, rule_name: &str, name: &str, data: &str) {
+        match self.definitions.get_mut(rule_name) {
+            Some(rule) => rule.pragmas.push((name.to_string(), data.to_string())),
+            None => warn!("pragma {name} on missing rule named {rule_name}"),
+        }
+    }
+
     pub fn get_root_definition_name(&self) -> Option<String> {
         self.defn_order.get(0).map(smol_str::SmolStr::to_string)
     }
@@ -97,39 +169,850 @@ impl Grammar {
         self.defn_order.get(0).map(|s| self.get_definition(s))
     }
     
+    pub fn has_definition(&self, name: &str) -> bool {
+        self.definitions.contains_key(name)
+    }
+
     pub fn get_definition_mark(&self, name: &str) -> Mark {
         if !self.definitions.contains_key(name) {
             // TODO: make this return a ParseError...
-            println!("missing rule named {name}!");
+            warn!("missing rule named {name}!");
         }
         self.definitions[name].mark
     }
 
     pub fn get_definition(&self, name: &str) -> &BranchingRule {
         if !self.definitions.contains_key(name) {
-            println!("Where is {name}???");
+            warn!("Where is {name}???");
         }
         assert!(self.definitions.contains_key(name));
         &self.definitions[name]
     }
 }
 
-impl fmt::Display for Grammar {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        let mut builder = string_builder::Builder::default();
-        // iterate in insertion order...
+/// Rough size and shape of a grammar, for spotting ones likely to be slow to parse with. See `Grammar::complexity`
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct GrammarMetrics {
+    /// factors across every alternative of every rule, synthesized rules included
+    pub total_factors: usize,
+    /// the most alternatives any one rule has
+    pub max_alts: usize,
+    /// the most rules in a chain of references from the root, each rule counted at most once
+    pub recursion_depth: usize,
+    /// rules that can match the empty string
+    pub nullable_rules: usize,
+}
+
+/// grammar analysis
+impl Grammar {
+    pub fn complexity(&self) -> GrammarMetrics {
+        let rules = self.definitions.values();
+        let mut memo = HashMap::new();
+        GrammarMetrics {
+            total_factors: rules.clone().flat_map(|r| r.alts.iter()).map(Rule::len).sum(),
+            max_alts: rules.map(|r| r.alts.len()).max().unwrap_or(0),
+            recursion_depth: self.defn_order.first()
+                .map_or(0, |root| self.chain_depth(root, &mut HashSet::new(), &mut memo)),
+            nullable_rules: self.nullable_rules().len(),
+        }
+    }
+
+    /// Length of the longest reference chain starting at `name`, not revisiting anything already on the chain
+    fn chain_depth<'a>(&'a self, name: &'a SmolStr, on_chain: &mut HashSet<&'a SmolStr>, memo: &mut HashMap<&'a SmolStr, usize>) -> usize {
+        if let Some(depth) = memo.get(name) {
+            return *depth;
+        }
+        let Some(rule) = self.definitions.get(name) else { return 0 };
+        on_chain.insert(name);
+        let mut deepest = 0;
+        for factor in rule.alts.iter().flat_map(|alt| alt.factors.iter()) {
+            if let Factor::Nonterm(_, nt) = factor {
+                if !on_chain.contains(nt) {
+                    deepest = deepest.max(self.chain_depth(nt, on_chain, memo));
+                }
+            }
+        }
+        on_chain.remove(name);
+        memo.insert(name, deepest + 1);
+        deepest + 1
+    }
+
+    /// Every character some terminal of the grammar can match.
+    /// Unicode categories stay symbolic; an exclusion contributes everything outside its explicit characters
+    pub fn alphabet(&self) -> CharSet {
+        let mut alphabet = CharSet::new();
+        let lits = self.definitions.values()
+            .flat_map(|r| r.alts.iter())
+            .flat_map(|alt| alt.factors.iter())
+            .filter_map(|f| match f {
+                Factor::Terminal(_, lit) => Some(lit),
+                Factor::Nonterm(..) | Factor::Insertion(_) => None,
+            });
+        for lit in lits {
+            if lit.is_exclude {
+                alphabet = alphabet.union(&lit.chars.complement_ranges());
+            } else {
+                alphabet = alphabet.union(&lit.chars);
+            }
+        }
+        alphabet
+    }
+
+    /// Up to `max` distinct strings the grammar accepts, shortest derivations first.
+    /// Each character class contributes one representative character, and recursion is cut off after a bounded number of expansions,
+    /// so this always terminates, even for grammars with infinitely many sentences
+    pub fn generate_examples(&self, max: usize) -> Vec<String> {
+        const MAX_EXPANSIONS: usize = 24;
+        const MAX_STATES: usize = 100_000;
+        let mut examples: Vec<String> = Vec::new();
+        let Some(root) = self.defn_order.first() else { return examples };
+        // (text so far, factors still to expand, expansions used)
+        let mut queue: VecDeque<(String, Vec<&Factor>, usize)> = self.definitions[root].alts.iter()
+            .map(|alt| (String::new(), alt.factors.iter().collect(), 0))
+            .collect();
+        let mut states = 0;
+        while let Some((mut text, mut remaining, expansions)) = queue.pop_front() {
+            states += 1;
+            if examples.len() >= max || states > MAX_STATES {
+                break;
+            }
+            // consume leading terminals; insertions match no input, so they contribute nothing
+            let mut stuck = false;
+            loop {
+                match remaining.first() {
+                    Some(Factor::Terminal(_, lit)) => match lit.example_char() {
+                        Some(ch) => text.push(ch),
+                        None => stuck = true,
+                    },
+                    Some(Factor::Insertion(_)) => {}
+                    _ => break,
+                }
+                remaining.remove(0);
+            }
+            if stuck {
+                continue;
+            }
+            match remaining.first() {
+                None => {
+                    if !examples.contains(&text) {
+                        examples.push(text);
+                    }
+                }
+                Some(Factor::Nonterm(_, name)) => {
+                    if expansions >= MAX_EXPANSIONS {
+                        continue;
+                    }
+                    let Some(rule) = self.definitions.get(name) else { continue };
+                    for alt in &rule.alts {
+                        let expanded = alt.factors.iter().chain(remaining[1..].iter().copied()).collect();
+                        queue.push_back((text.clone(), expanded, expansions + 1));
+                    }
+                }
+                Some(Factor::Terminal(..) | Factor::Insertion(_)) => unreachable!("leading terminals and insertions were consumed above"),
+            }
+        }
+        examples
+    }
+
+    /// Rules that can refer back to themselves, directly or through other rules.
+    /// Synthesized repetition rules count too
+    pub fn recursive_rules(&self) -> HashSet<SmolStr> {
+        self.defn_order.iter()
+            .filter(|name| self.reaches(name, name))
+            .cloned()
+            .collect()
+    }
+
+    /// The set of rules that can match the empty string
+    fn nullable_rules(&self) -> HashSet<SmolStr> {
+        let mut nullable: HashSet<SmolStr> = HashSet::new();
+        // iterate to a fixed point
+        loop {
+            let before = nullable.len();
+            for name in &self.defn_order {
+                if nullable.contains(name) {
+                    continue;
+                }
+                let is_nullable = self.definitions[name].alts.iter()
+                    .any(|alt| alt.factors.iter().all(|f| match f {
+                        Factor::Terminal(..) => false,
+                        Factor::Nonterm(_, nt) => nullable.contains(nt),
+                        Factor::Insertion(_) => true,
+                    }));
+                if is_nullable {
+                    nullable.insert(name.clone());
+                }
+            }
+            if nullable.len() == before {
+                return nullable;
+            }
+        }
+    }
+
+    /// The terminals that can start a sequence of factors, given the FIRST sets computed so far
+    fn first_of_factors(factors: &[Factor], firsts: &HashMap<SmolStr, Vec<Lit>>, nullable: &HashSet<SmolStr>) -> Vec<Lit> {
+        let mut result: Vec<Lit> = Vec::new();
+        for factor in factors {
+            match factor {
+                Factor::Terminal(_, lit) => {
+                    if !result.contains(lit) {
+                        result.push(lit.clone());
+                    }
+                    break;
+                }
+                Factor::Nonterm(_, nt) => {
+                    for lit in firsts.get(nt).into_iter().flatten() {
+                        if !result.contains(lit) {
+                            result.push(lit.clone());
+                        }
+                    }
+                    if !nullable.contains(nt) {
+                        break;
+                    }
+                }
+                Factor::Insertion(_) => {}
+            }
+        }
+        result
+    }
+
+    /// For each rule, the terminals that can start it
+    fn first_sets(&self, nullable: &HashSet<SmolStr>) -> HashMap<SmolStr, Vec<Lit>> {
+        let mut firsts: HashMap<SmolStr, Vec<Lit>> = HashMap::new();
+        // iterate to a fixed point
+        loop {
+            let mut changed = false;
+            for name in &self.defn_order {
+                let mut rule_first = firsts.get(name).cloned().unwrap_or_default();
+                for alt in &self.definitions[name].alts {
+                    for lit in Self::first_of_factors(&alt.factors, &firsts, nullable) {
+                        if !rule_first.contains(&lit) {
+                            rule_first.push(lit);
+                            changed = true;
+                        }
+                    }
+                }
+                firsts.insert(name.clone(), rule_first);
+            }
+            if !changed {
+                return firsts;
+            }
+        }
+    }
+
+    /// Check that the grammar can actually be used: it has a root rule that isn't an attribute, and every nonterminal refers to a defined rule.
+    /// The error lists each undefined name along with the rule that refers to it
+    pub fn validate(&self) -> Result<(), crate::parser::ParseError> {
+        let Some(root) = self.get_root_definition() else {
+            return Err(crate::parser::ParseError::static_err("grammar has no rules"));
+        };
+        if root.mark() == Mark::Attr {
+            return Err(crate::parser::ParseError::static_err("root rule cannot be an attribute"));
+        }
+        let mut problems: Vec<String> = Vec::new();
+        for name in &self.defn_order {
+            for factor in self.definitions[name].alts.iter().flat_map(|alt| alt.factors.iter()) {
+                if let Factor::Nonterm(_, nt) = factor {
+                    if !self.definitions.contains_key(nt) {
+                        let problem = format!("{nt} (referenced from {})", Self::user_rule_name(name));
+                        if !problems.contains(&problem) {
+                            problems.push(problem);
+                        }
+                    }
+                }
+            }
+        }
+        if !problems.is_empty() {
+            return Err(crate::parser::ParseError::static_err(&format!("{}: undefined rules: {}", crate::parser::ErrorCode::D01, problems.join(", "))));
+        }
+        let mut unknown_categories: Vec<String> = Vec::new();
+        for name in &self.defn_order {
+            for factor in self.definitions[name].alts.iter().flat_map(|alt| alt.factors.iter()) {
+                if let Factor::Terminal(_, lit) = factor {
+                    for matcher in &lit.matchers {
+                        let (found, what) = match matcher {
+                            CharMatcher::Range(bot, top) if bot > top => (&mut problems, matcher.to_string()),
+                            CharMatcher::UnknownCategory(code) => (&mut unknown_categories, code.to_string()),
+                            _ => continue,
+                        };
+                        let problem = format!("{what} (in {})", Self::user_rule_name(name));
+                        if !found.contains(&problem) {
+                            found.push(problem);
+                        }
+                    }
+                }
+            }
+        }
+        if !problems.is_empty() {
+            Err(crate::parser::ParseError::static_err(&format!("reversed character ranges: {}", problems.join(", "))))
+        } else if !unknown_categories.is_empty() {
+            Err(crate::parser::ParseError::static_err(&format!("unknown Unicode categories: {}", unknown_categories.join(", "))))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Rules that can't be reached from the root rule, in definition order.
+    /// Synthesized rules are left out; they're only unreachable when the rule they came from is
+    pub fn find_unused_rules(&self) -> Vec<String> {
+        let Some(root) = self.defn_order.first() else { return Vec::new() };
+        let mut reachable: HashSet<&SmolStr> = HashSet::from([root]);
+        let mut stack = vec![root];
+        while let Some(name) = stack.pop() {
+            let Some(defn) = self.definitions.get(name) else { continue };
+            for factor in defn.alts.iter().flat_map(|alt| alt.factors.iter()) {
+                if let Factor::Nonterm(_, nt) = factor {
+                    if reachable.insert(nt) {
+                        stack.push(nt);
+                    }
+                }
+            }
+        }
+        self.defn_order.iter()
+            .filter(|name| !reachable.contains(name) && !Self::is_synthesized_name(name))
+            .map(smol_str::SmolStr::to_string)
+            .collect()
+    }
+
+    /// for messages: the user-written rule a (possibly synthesized) rule came from
+    fn user_rule_name(name: &str) -> &str {
+        match name.strip_prefix("--") {
+            Some(synthesized) => synthesized.split('.').next().unwrap_or(name),
+            None => name,
+        }
+    }
+
+    /// Do both grammars have the same rules, alternatives, and factors, ignoring every `Mark` and `TMark`?
+    /// Marks only affect the shape of the output, so grammars that compare equal here accept the same language.
+    /// Insertions match no input, so they're passed over too
+    pub fn same_language_shape(&self, other: &Grammar) -> bool {
+        fn same_factor(f1: &Factor, f2: &Factor) -> bool {
+            match (f1, f2) {
+                (Factor::Terminal(_, lit1), Factor::Terminal(_, lit2)) => lit1 == lit2,
+                (Factor::Nonterm(_, name1), Factor::Nonterm(_, name2)) => name1 == name2,
+                _ => false,
+            }
+        }
+        fn matching(alt: &Rule) -> Vec<&Factor> {
+            alt.factors.iter().filter(|f| !matches!(f, Factor::Insertion(_))).collect()
+        }
+        self.definitions.len() == other.definitions.len()
+            && self.definitions.iter().all(|(name, rule)| {
+                let Some(other_rule) = other.definitions.get(name) else { return false };
+                rule.alts.len() == other_rule.alts.len()
+                    && rule.alts.iter().zip(&other_rule.alts).all(|(alt1, alt2)| {
+                        let (factors1, factors2) = (matching(alt1), matching(alt2));
+                        factors1.len() == factors2.len()
+                            && factors1.iter().zip(&factors2).all(|(f1, f2)| same_factor(f1, f2))
+                    })
+            })
+    }
+
+    /// For authors debugging ambiguity: per rule with multiple alternatives, report pairs of alternatives
+    /// whose FIRST sets overlap. This isn't an LL parser, so overlap isn't fatal, but it's a strong hint
+    /// about where ambiguity might come from. Returns an empty string if nothing overlaps.
+    pub fn conflict_report(&self) -> String {
+        let nullable = self.nullable_rules();
+        let firsts = self.first_sets(&nullable);
+        let mut report = String::new();
+        for name in &self.defn_order {
+            let alts = &self.definitions[name].alts;
+            let alt_firsts = alts.iter()
+                .map(|alt| Self::first_of_factors(&alt.factors, &firsts, &nullable))
+                .collect::<Vec<_>>();
+            for i in 0..alt_firsts.len() {
+                for j in (i+1)..alt_firsts.len() {
+                    let shared = alt_firsts[i].iter()
+                        .flat_map(|a| alt_firsts[j].iter().map(move |b| (a, b)))
+                        .find_map(|(a, b)| a.overlap(b));
+                    if let Some(ch) = shared {
+                        report.push_str(&format!("{name}: alternatives {} and {} can both start with '{ch}' ({} | {})\n",
+                            i+1, j+1, alts[i], alts[j]));
+                    }
+                }
+            }
+        }
+        report
+    }
+}
+
+/// compact binary form, e.g. to cache a compiled grammar on disk
+#[cfg(feature = "bincode")]
+impl Grammar {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        bincode::serialize(self).expect("grammar serialization can't fail")
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Grammar, crate::parser::ParseError> {
+        bincode::deserialize(bytes)
+            .map_err(|e| crate::parser::ParseError::uncategorized_err(&format!("can't read compiled grammar: {e}")))
+    }
+}
+
+/// grammar rewriting
+impl Grammar {
+    /// Shrink the grammar by inlining rules that are referenced exactly once, where that can't change the output:
+    /// the reference must be effectively muted (so no element or attribute comes from it), and the rule must not be recursive.
+    /// Inlining a rule with several alternatives multiplies out the alternative that referenced it.
+    /// The root rule is never inlined
+    pub fn inline_single_use(&mut self) {
+        while let Some((name, host, alt_idx, factor_idx)) = self.find_inline_candidate() {
+            let inlined = self.definitions.remove(&name).unwrap();
+            self.defn_order.retain(|n| *n != name);
+            let host_rule = self.definitions.get_mut(&host).unwrap();
+            let alt = host_rule.alts.remove(alt_idx);
+            let expanded = inlined.alts.iter()
+                .map(|body| {
+                    let mut factors = alt.factors[..factor_idx].to_vec();
+                    factors.extend(body.factors.iter().cloned());
+                    factors.extend(alt.factors[factor_idx+1..].iter().cloned());
+                    Rule::new(factors)
+                })
+                .collect::<Vec<_>>();
+            host_rule.alts.splice(alt_idx..alt_idx, expanded);
+            debug!("inlined {name} into {host}");
+        }
+    }
+
+    /// Rename a rule, updating every reference to it. Synthesized rules that came from it are renamed along with it,
+    /// so `--old.f-star1` becomes `--new.f-star1`. Fails if there's no such rule, or the new name is already taken
+    pub fn rename_rule(&mut self, old: &str, new: &str) -> Result<(), crate::parser::ParseError> {
+        use crate::parser::ParseError;
+        if Self::is_synthesized_name(old) || Self::is_synthesized_name(new) {
+            return Err(ParseError::static_err("synthesized rules can't be renamed directly"));
+        }
+        if !self.definitions.contains_key(old) {
+            return Err(ParseError::static_err(&format!("can't rename {old}: no such rule")));
+        }
+        let new_prefix = format!("--{new}.");
+        if self.definitions.keys().any(|name| name == new || name.starts_with(&new_prefix)) {
+            return Err(ParseError::static_err(&format!("can't rename {old}: {new} already exists")));
+        }
+
+        let old_prefix = format!("--{old}.");
+        let renamed = |name: &SmolStr| -> Option<SmolStr> {
+            if name == old {
+                Some(SmolStr::new(new))
+            } else {
+                name.strip_prefix(&old_prefix).map(|rest| SmolStr::new(format!("{new_prefix}{rest}")))
+            }
+        };
+        for name in self.defn_order.iter_mut() {
+            if let Some(new_name) = renamed(name) {
+                let defn = self.definitions.remove(name).unwrap();
+                self.definitions.insert(new_name.clone(), defn);
+                *name = new_name;
+            }
+        }
+        for factor in self.definitions.values_mut().flat_map(|defn| defn.alts.iter_mut()).flat_map(|alt| alt.factors.iter_mut()) {
+            if let Factor::Nonterm(_, nt) = factor {
+                if let Some(new_name) = renamed(nt) {
+                    *nt = new_name;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// the next rule eligible for `inline_single_use`, with the location (host rule, alt index, factor index) of its only reference
+    fn find_inline_candidate(&self) -> Option<(SmolStr, SmolStr, usize, usize)> {
+        let mut refs: HashMap<&SmolStr, Vec<(&SmolStr, usize, usize, Mark)>> = HashMap::new();
+        for host in &self.defn_order {
+            for (alt_idx, alt) in self.definitions[host].alts.iter().enumerate() {
+                for (factor_idx, factor) in alt.factors.iter().enumerate() {
+                    if let Factor::Nonterm(mark, nt) = factor {
+                        refs.entry(nt).or_default().push((host, alt_idx, factor_idx, *mark));
+                    }
+                }
+            }
+        }
+        let root = self.defn_order.first()?;
+        for name in self.defn_order.iter().filter(|n| *n != root) {
+            if let Some([(host, alt_idx, factor_idx, mark)]) = refs.get(name).map(Vec::as_slice) {
+                let is_muted = Mark::combine(self.definitions[name].mark, *mark) == Mark::Mute;
+                if is_muted && !self.reaches(name, name) {
+                    return Some((name.clone(), (*host).clone(), *alt_idx, *factor_idx));
+                }
+            }
+        }
+        None
+    }
+
+    /// can `target` be reached by following nonterminal references from the definition of `from`?
+    fn reaches(&self, from: &SmolStr, target: &SmolStr) -> bool {
+        let mut seen: HashSet<&SmolStr> = HashSet::new();
+        let mut stack = vec![from];
+        while let Some(name) = stack.pop() {
+            let Some(defn) = self.definitions.get(name) else { continue };
+            for factor in defn.alts.iter().flat_map(|alt| alt.factors.iter()) {
+                if let Factor::Nonterm(_, nt) = factor {
+                    if nt == target {
+                        return true;
+                    }
+                    if seen.insert(nt) {
+                        stack.push(nt);
+                    }
+                }
+            }
+        }
+        false
+    }
+}
+
+/// What a synthesized rule was built from, recovered from its shape. See `Grammar::sugar_of`
+enum Sugar<'g> {
+    Option(&'g [Factor]),
+    Star(&'g [Factor]),
+    Plus(&'g [Factor]),
+    StarSep(&'g [Factor], &'g [Factor]),
+    PlusSep(&'g [Factor], &'g [Factor]),
+    Alts(&'g [Rule]),
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+enum Notation {
+    Ixml,
+    Ebnf,
+}
+
+/// conversion to other representations
+impl Grammar {
+    /// If `name` is a synthesized rule still in the shape `SeqBuilder` made it, the sugar it stands for,
+    /// plus any further synthesized rules that are part of the same construct
+    fn sugar_of<'g>(&'g self, name: &str) -> Option<(Sugar<'g>, Vec<&'g SmolStr>)> {
+        if !Self::is_synthesized_name(name) {
+            return None;
+        }
+        let hint = name.rsplit('.').next()?.trim_end_matches(|ch: char| ch.is_ascii_digit());
+        let alts = &self.definitions.get(name)?.alts;
+        let only_alt = match alts.as_slice() {
+            [alt] => Some(alt.factors.as_slice()),
+            _ => None,
+        };
+        // a construct that must be a single reference to another (recognized) synthesized rule
+        let nested = |factor: &'g Factor| match factor {
+            Factor::Nonterm(_, nt) => self.sugar_of(nt).map(|(sugar, mut parts)| {
+                parts.push(nt);
+                (sugar, parts)
+            }),
+            Factor::Terminal(..) | Factor::Insertion(_) => None,
+        };
+        match hint {
+            "f-option" => match alts.as_slice() {
+                [empty, sub] if empty.factors.is_empty() => Some((Sugar::Option(&sub.factors), Vec::new())),
+                _ => None,
+            },
+            "f-star" => match nested(only_alt?.first()?)? {
+                (Sugar::Option([sub @ .., Factor::Nonterm(_, again)]), parts) if again == name && only_alt?.len() == 1 =>
+                    Some((Sugar::Star(sub), parts)),
+                _ => None,
+            },
+            "f-plus" => {
+                let (last, sub) = only_alt?.split_last()?;
+                match nested(last)? {
+                    (Sugar::Star(repeated), parts) if repeated == sub => Some((Sugar::Plus(sub), parts)),
+                    _ => None,
+                }
+            }
+            "f-plus-sep" => {
+                let (last, sub) = only_alt?.split_last()?;
+                match nested(last)? {
+                    (Sugar::Star(repeated), parts) if repeated.ends_with(sub) && !sub.is_empty() =>
+                        Some((Sugar::PlusSep(sub, &repeated[..repeated.len() - sub.len()]), parts)),
+                    _ => None,
+                }
+            }
+            "f-star-sep" => match (only_alt?, nested(only_alt?.first()?)?) {
+                ([_], (Sugar::Option([inner]), mut parts)) => match nested(inner)? {
+                    (Sugar::PlusSep(sub, sep), inner_parts) => {
+                        parts.extend(inner_parts);
+                        Some((Sugar::StarSep(sub, sep), parts))
+                    }
+                    _ => None,
+                },
+                _ => None,
+            },
+            "f-opt" => Some((Sugar::Alts(alts), Vec::new())),
+            _ => None,
+        }
+    }
+
+    /// An approximate EBNF (in the W3C XML-spec style) rendering of the grammar, for sharing with other tools.
+    /// Synthesized rules are folded back into `?`, `*`, `+`, and parenthesized groups wherever their shape allows.
+    /// Marks aren't representable and are dropped; Unicode categories come out as `[\p{Nd}]`
+    pub fn to_ebnf(&self) -> String {
+        self.render(Notation::Ebnf)
+    }
+
+    /// one line per rule, with synthesized rules folded back into the sugar they came from where possible
+    fn render(&self, notation: Notation) -> String {
+        let mut folded: HashSet<&SmolStr> = HashSet::new();
+        let mut rendered: HashMap<&SmolStr, String> = HashMap::new();
+        for name in self.defn_order.iter().filter(|n| !Self::is_synthesized_name(n)) {
+            rendered.insert(name, self.render_alts(&self.definitions[name].alts, notation, &mut folded));
+        }
+        let mut lines: Vec<String> = Vec::new();
+        for name in &self.defn_order {
+            if folded.contains(name) {
+                continue;
+            }
+            let body = match rendered.remove(name) {
+                Some(body) => body,
+                None => self.render_alts(&self.definitions[name].alts, notation, &mut HashSet::new()),
+            };
+            lines.push(match notation {
+                Notation::Ixml => format!("{}{name} = {body}.", self.definitions[name].mark),
+                Notation::Ebnf => format!("{name} ::= {body}"),
+            });
+        }
+        lines.join("\n")
+    }
+
+    fn render_alts<'g>(&'g self, alts: &'g [Rule], notation: Notation, folded: &mut HashSet<&'g SmolStr>) -> String {
+        alts.iter()
+            .map(|alt| self.render_seq(&alt.factors, notation, folded))
+            .collect::<Vec<_>>()
+            .join(" | ")
+    }
+
+    /// a sequence, with runs of single characters (with the same tmark) joined up into strings
+    fn render_seq<'g>(&'g self, factors: &'g [Factor], notation: Notation, folded: &mut HashSet<&'g SmolStr>) -> String {
+        if factors.is_empty() && notation == Notation::Ebnf {
+            return "()".to_string();
+        }
+        let mut parts: Vec<String> = Vec::new();
+        let mut run: Option<(TMark, String)> = None;
+        let flush = |run: &mut Option<(TMark, String)>, parts: &mut Vec<String>| {
+            if let Some((tmark, txt)) = run.take() {
+                parts.push(match notation {
+                    Notation::Ixml => format!("{tmark}\"{}\"", txt.replace('"', "\"\"")),
+                    Notation::Ebnf => format!("\"{txt}\""),
+                });
+            }
+        };
+        for factor in factors {
+            let string_char = match factor {
+                Factor::Terminal(tmark, lit) => lit.single_char()
+                    .filter(|ch| !ch.is_control() && (notation == Notation::Ixml || *ch != '"'))
+                    .map(|ch| (*tmark, ch)),
+                Factor::Nonterm(..) | Factor::Insertion(_) => None,
+            };
+            match (string_char, &mut run) {
+                (Some((tmark, ch)), Some((run_tmark, txt))) if tmark == *run_tmark => txt.push(ch),
+                (Some((tmark, ch)), _) => {
+                    flush(&mut run, &mut parts);
+                    run = Some((tmark, ch.to_string()));
+                }
+                (None, _) => {
+                    flush(&mut run, &mut parts);
+                    parts.push(self.render_factor(factor, notation, folded).0);
+                }
+            }
+        }
+        flush(&mut run, &mut parts);
+        match notation {
+            Notation::Ixml => parts.join(", "),
+            Notation::Ebnf => parts.join(" "),
+        }
+    }
+
+    /// the rendering, and whether it can take a postfix operator without parentheses
+    fn render_factor<'g>(&'g self, factor: &'g Factor, notation: Notation, folded: &mut HashSet<&'g SmolStr>) -> (String, bool) {
+        match factor {
+            Factor::Terminal(tmark, lit) => match notation {
+                Notation::Ixml => (format!("{tmark}{}", Self::ixml_lit(lit)), true),
+                Notation::Ebnf => (Self::ebnf_lit(lit), true),
+            },
+            // EBNF has no way to produce text without matching it, so an insertion survives only as a comment
+            Factor::Insertion(text) => match notation {
+                Notation::Ixml => Self::ixml_insertion(text),
+                Notation::Ebnf => (format!("/* {} */", Self::ixml_insertion(text).0), true),
+            },
+            Factor::Nonterm(mark, nt) => match self.sugar_of(nt) {
+                None if notation == Notation::Ixml => (format!("{mark}{nt}"), true),
+                None => (nt.to_string(), true),
+                Some((sugar, parts)) => {
+                    folded.insert(nt);
+                    folded.extend(parts);
+                    let group = |factors: &'g [Factor], folded: &mut HashSet<&'g SmolStr>| match factors {
+                        [single] => match self.render_factor(single, notation, folded) {
+                            (txt, true) => txt,
+                            (txt, false) => format!("({txt})"),
+                        },
+                        _ => format!("({})", self.render_seq(factors, notation, folded)),
+                    };
+                    let rendered = match (sugar, notation) {
+                        (Sugar::Option(sub), _) => format!("{}?", group(sub, folded)),
+                        (Sugar::Star(sub), _) => format!("{}*", group(sub, folded)),
+                        (Sugar::Plus(sub), _) => format!("{}+", group(sub, folded)),
+                        (Sugar::PlusSep(sub, sep), Notation::Ixml) => format!("{}++{}", group(sub, folded), group(sep, folded)),
+                        (Sugar::StarSep(sub, sep), Notation::Ixml) => format!("{}**{}", group(sub, folded), group(sep, folded)),
+                        (Sugar::PlusSep(sub, sep), Notation::Ebnf) => {
+                            let sub_txt = group(sub, folded);
+                            format!("{sub_txt} ({} {sub_txt})*", self.render_seq(sep, notation, folded))
+                        }
+                        (Sugar::StarSep(sub, sep), Notation::Ebnf) => {
+                            let sub_txt = group(sub, folded);
+                            format!("({sub_txt} ({} {sub_txt})*)?", self.render_seq(sep, notation, folded))
+                        }
+                        (Sugar::Alts(alts), _) => return (format!("({})", self.render_alts(alts, notation, folded)), true),
+                    };
+                    (rendered, false)
+                }
+            },
+        }
+    }
+
+    /// a character class in ixml syntax, or a string if it's just one character
+    fn ixml_lit(lit: &Lit) -> String {
+        let quoted = |chrs: &str| if chrs.chars().any(char::is_control) {
+            chrs.chars().map(|ch| format!("#{:x}", ch as u32)).collect::<Vec<_>>().join("; ")
+        } else {
+            format!("\"{}\"", chrs.replace('"', "\"\""))
+        };
+        if let Some(ch) = lit.single_char() {
+            return quoted(&ch.to_string());
+        }
+        let members = lit.matchers.iter()
+            .map(|m| match m {
+                CharMatcher::Exact(ch) => quoted(&ch.to_string()),
+                CharMatcher::OneOf(chrs) => quoted(chrs),
+                CharMatcher::Range(bot, top) => format!("{}-{}", quoted(&bot.to_string()), quoted(&top.to_string())),
+                CharMatcher::UnicodeRange(range) => range.to_string(),
+                CharMatcher::UnknownCategory(name) => name.to_string(),
+            })
+            .collect::<Vec<_>>()
+            .join("; ");
+        let negate = if lit.is_exclude { "~" } else { "" };
+        format!("{negate}[{members}]")
+    }
+
+    /// `+"text"`, or a run of single-character insertions when the text holds control characters
+    fn ixml_insertion(text: &str) -> (String, bool) {
+        if !text.chars().any(char::is_control) {
+            return (format!("+\"{}\"", text.replace('"', "\"\"")), true);
+        }
+        let parts = text.chars()
+            .map(|ch| if ch.is_control() {
+                format!("+#{:x}", ch as u32)
+            } else {
+                format!("+\"{}\"", ch.to_string().replace('"', "\"\""))
+            })
+            .collect::<Vec<_>>();
+        let single = parts.len() == 1;
+        (parts.join(", "), single)
+    }
+
+    fn ebnf_lit(lit: &Lit) -> String {
+        let escape = |ch: char| if ch.is_alphanumeric() || (ch.is_ascii_punctuation() && !"[]^-\\".contains(ch)) {
+            ch.to_string()
+        } else {
+            format!("#x{:X}", ch as u32)
+        };
+        if let Some(ch) = lit.single_char() {
+            return match ch {
+                '"' => "'\"'".to_string(),
+                ch if ch.is_control() => format!("#x{:X}", ch as u32),
+                ch => format!("\"{ch}\""),
+            };
+        }
+        let members = lit.matchers.iter()
+            .map(|m| match m {
+                CharMatcher::Exact(ch) => escape(*ch),
+                CharMatcher::OneOf(chrs) => chrs.chars().map(escape).collect(),
+                CharMatcher::Range(bot, top) => format!("{}-{}", escape(*bot), escape(*top)),
+                CharMatcher::UnicodeRange(range) => format!("\\p{{{range}}}"),
+                CharMatcher::UnknownCategory(name) => format!("\\p{{{name}}}"),
+            })
+            .collect::<String>();
+        let negate = if lit.is_exclude { "^" } else { "" };
+        format!("[{negate}{members}]")
+    }
+
+    /// The inverse of `ixml_grammar::ixml_tree_to_grammar`: build the `<ixml>` parse tree for this grammar,
+    /// in the same shape the ixml parser produces, so it can be inspected or rewritten with the `Parser` tree helpers.
+    /// Synthesized rules are included as ordinary (muted) rules
+    pub fn to_parse_tree(&self) -> Arena<Content> {
+        fn append(arena: &mut Arena<Content>, parent: NodeId, content: Content) -> NodeId {
+            let node = arena.new_node(content);
+            parent.append(node, arena);
+            node
+        }
+        fn append_attr(arena: &mut Arena<Content>, parent: NodeId, name: &str, value: impl ToString) {
+            let value = value.to_string();
+            if !value.is_empty() {
+                append(arena, parent, Content::Attribute(name.to_string(), value));
+            }
+        }
+
+        let mut arena = Arena::new();
+        let root = arena.new_node(Content::Root);
+        let ixml = append(&mut arena, root, Content::Element("ixml".to_string()));
         for name in &self.defn_order {
             let branching_rule = &self.definitions[name];
-            builder.append(branching_rule.mark.to_string());
-            builder.append(name.to_string());
-            builder.append("= ");
-            let rules: Vec<String> = branching_rule.alts.clone().iter()
-                .map(std::string::ToString::to_string)
-                .collect();
-            builder.append(rules.join(" | "));
-            builder.append(".\n");
+            let rule = append(&mut arena, ixml, Content::Element("rule".to_string()));
+            append_attr(&mut arena, rule, "mark", branching_rule.mark);
+            append_attr(&mut arena, rule, "name", name);
+            for (pname, pdata) in &branching_rule.pragmas {
+                let pragma = append(&mut arena, rule, Content::Element("pragma".to_string()));
+                append_attr(&mut arena, pragma, "pname", pname);
+                append_attr(&mut arena, pragma, "pdata", pdata);
+            }
+            for alt in &branching_rule.alts {
+                let alt_node = append(&mut arena, rule, Content::Element("alt".to_string()));
+                for factor in &alt.factors {
+                    match factor {
+                        Factor::Nonterm(mark, nt) => {
+                            let node = append(&mut arena, alt_node, Content::Element("nonterminal".to_string()));
+                            append_attr(&mut arena, node, "mark", mark);
+                            append_attr(&mut arena, node, "name", nt);
+                        }
+                        Factor::Insertion(text) => {
+                            let node = append(&mut arena, alt_node, Content::Element("insertion".to_string()));
+                            append_attr(&mut arena, node, "string", text);
+                        }
+                        Factor::Terminal(tmark, lit) => {
+                            if let Some(ch) = lit.single_char() {
+                                let node = append(&mut arena, alt_node, Content::Element("literal".to_string()));
+                                append_attr(&mut arena, node, "tmark", tmark);
+                                append_attr(&mut arena, node, "string", ch);
+                                continue;
+                            }
+                            let kind = if lit.is_exclude { "exclusion" } else { "inclusion" };
+                            let node = append(&mut arena, alt_node, Content::Element(kind.to_string()));
+                            append_attr(&mut arena, node, "tmark", tmark);
+                            for matcher in &lit.matchers {
+                                let member = append(&mut arena, node, Content::Element("member".to_string()));
+                                match matcher {
+                                    CharMatcher::Exact(ch) => append_attr(&mut arena, member, "string", ch),
+                                    CharMatcher::OneOf(chrs) => append_attr(&mut arena, member, "string", chrs),
+                                    CharMatcher::Range(bot, top) => {
+                                        append_attr(&mut arena, member, "from", bot);
+                                        append_attr(&mut arena, member, "to", top);
+                                    }
+                                    CharMatcher::UnicodeRange(range) => append_attr(&mut arena, member, "code", range),
+                                    CharMatcher::UnknownCategory(code) => append_attr(&mut arena, member, "code", code),
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        arena
+    }
+}
+
+/// ixml-style compact syntax, one rule per line, in insertion order.
+/// Synthesized rules are shown as the `?`, `*`, `+`, `++`, `**`, or `(...)` they came from, where their shape allows
+impl fmt::Display for Grammar {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let rendered = self.render(Notation::Ixml);
+        if rendered.is_empty() {
+            Ok(())
+        } else {
+            writeln!(f, "{rendered}")
         }
-        write!(f, "{}", builder.string().unwrap())
     }
 }
 
@@ -161,15 +1044,17 @@ impl<'a> Iterator for TermIter<'a> {
 /// For example doc = a | b. { the part after the = }
 /// would be repesented by two different entries in self.alts (each of which would be its own sequence of terms)
 #[derive(Debug, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct BranchingRule {
     mark: Mark,
     alts: Vec<Rule>,
     is_internal: bool,
+    pragmas: Vec<(String, String)>,
 }
 
 impl BranchingRule {
     pub fn new(mark: Mark) -> Self {
-        Self { mark, alts: Vec::new(), is_internal: false }
+        Self { mark, alts: Vec::new(), is_internal: false, pragmas: Vec::new() }
     }
 
     fn add_alt_branch(&mut self, alt: Rule) {
@@ -183,6 +1068,11 @@ impl BranchingRule {
     pub fn mark(&self) -> Mark {
         self.mark
     }
+
+    /// (name, data) of each pragma attached to this rule, in source order
+    pub fn pragmas(&self) -> &[(String, String)] {
+        &self.pragmas
+    }
 }
 
 /// Representation of marks on rules or nonterminal references.
@@ -190,7 +1080,8 @@ impl BranchingRule {
 /// @ for attribute
 /// - for hidden
 /// ^ for visible (default)
-#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Mark {
     Default,
     Unmute,
@@ -198,6 +1089,21 @@ pub enum Mark {
     Attr,
 }
 
+impl Mark {
+    /// We can have a Mark at the point of definiton, as well as at the point of reference...
+    /// This figures out the effective Mark for all possible combinations.
+    /// Per the ixml spec, a mark on the reference overrides the mark on the definition.
+    /// Resolution happens per reference, so one rule can appear as an attribute in one place and an element in another.
+    /// n.b. muting a rule only drops its tag; its content is still serialized,
+    /// so an @-marked reference inside a muted rule still becomes an attribute (of the nearest enclosing element)
+    pub fn combine(defn: Mark, reference: Mark) -> Mark {
+        match (defn, reference) {
+            (defn, Mark::Default) => defn,
+            (_, reference) => reference,
+        }
+    }
+}
+
 impl fmt::Display for Mark {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
@@ -215,6 +1121,7 @@ impl fmt::Display for Mark {
 /// - for hidden
 /// ^ for visible (default)
 #[derive(Debug, Clone, Copy, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum TMark {
     Default,
     Unmute,
@@ -232,6 +1139,7 @@ impl fmt::Display for TMark {
 }
 
 #[derive(Debug, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Rule {
     pub factors: Vec<Factor>,
 }
@@ -264,34 +1172,32 @@ impl Rule {
     }
 }
 
+/// the factors in ixml syntax, comma-separated, as they'd appear in one alternative of a rule
 impl fmt::Display for Rule {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        let s: String = self.factors.clone().iter()
-            .map(|f| {
-                format!("{:?}", f)
-            })
+        let s: String = self.factors.iter()
+            .map(std::string::ToString::to_string)
             .collect::<Vec<_>>().join(", ");
-        write!(f,"{}", s)
+        write!(f, "{s}")
     }
 }
 
 
-/// At this low level, an individual `Factor` is either a terminal or a nonterminal
-/// TODO: insertions
+/// At this low level, an individual `Factor` is a terminal, a nonterminal, or an insertion.
+/// An insertion matches no input, but puts its text into the output; beneath an `@`-marked rule,
+/// that text becomes part of the attribute's value, like any other text
 #[derive(Debug, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Factor {
     Terminal(TMark, Lit),
     Nonterm(Mark, SmolStr),
+    Insertion(SmolStr),
 }
 
 impl Factor {
-    /// drain off the matchers from a `LitBuilder`, producing a new `Factor::Terminal`
+    /// take the finished `Lit` from a `LitBuilder`, producing a new `Factor::Terminal`
     fn new_lit(builder: LitBuilder, tmark: TMark) -> Self {
-        let is_exclude = builder.lit.is_exclude;
-        let mut lit = Lit::new();
-        lit.matchers = builder.lit.matchers;
-        lit.is_exclude = is_exclude;
-        Self::Terminal(tmark, lit)
+        Self::Terminal(tmark, builder.lit)
     }
 }
 
@@ -300,6 +1206,7 @@ impl fmt::Display for Factor {
         match self {
             Self::Terminal(tmark, lit) => write!(f, "{tmark}{lit}"),
             Self::Nonterm(mark, str) => write!(f, "{mark}{str}"),
+            Self::Insertion(text) => write!(f, "{}", Grammar::ixml_insertion(text).0),
         }
     }
 }
@@ -311,56 +1218,128 @@ struct CharMatchId(usize);
 /// A character matcher can be an arbitrarily long set of matchspecs (which are considered logically OR'd)
 /// e.g. ["0"-"9" | "?" | #64 | Nd]
 #[derive(Debug, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Lit {
+    /// as written, for display
     matchers: Vec<CharMatcher>,
+    /// the same characters as `matchers`, all together, for matching
+    chars: CharSet,
     /// negative matchers invert the overall match logic
     /// e.g. ~["0"-"9"]
     is_exclude: bool,
+    /// match as if by `accept_ignoring_case`, just for this `Lit`
+    ignore_case: bool,
 }
 
 impl Lit {
     fn new() -> Self {
-        Self { matchers: Vec::new(), is_exclude: false}
+        Self { matchers: Vec::new(), chars: CharSet::new(), is_exclude: false, ignore_case: false }
     }
 
     /// actually match the input char
     pub fn accept(&self, test: char) -> bool {
-        if self.is_exclude {
-            //self.matchers.iter().all(|m| !m.accept(test))
-            !self.matchers.iter().any(|m| m.accept(test))
-        } else {
-            self.matchers.iter().any(|m| m.accept(test))
+        if self.ignore_case {
+            return self.accept_ignoring_case(test);
+        }
+        self.chars.contains(test) != self.is_exclude
+    }
+
+    /// like `accept`, but a character also matches if its upper- or lowercase form does.
+    /// For an exclusion, none of them may match
+    pub fn accept_ignoring_case(&self, test: char) -> bool {
+        let included = Self::case_forms(test).any(|ch| self.chars.contains(ch));
+        included != self.is_exclude
+    }
+
+    /// `ch`, plus its lowercase and uppercase forms. A form that expands to several characters
+    /// (like the uppercase of 'ß', "SS") can't stand in for one character, so it's left out
+    fn case_forms(ch: char) -> impl Iterator<Item = char> {
+        fn only(mut forms: impl Iterator<Item = char>) -> Option<char> {
+            let first = forms.next();
+            if forms.next().is_none() { first } else { None }
+        }
+        std::iter::once(ch).chain(only(ch.to_lowercase())).chain(only(ch.to_uppercase()))
+    }
+
+    /// some character this matches, preferring printable ASCII
+    fn example_char(&self) -> Option<char> {
+        if !self.is_exclude {
+            let direct = self.matchers.iter().find_map(|m| match m {
+                CharMatcher::Exact(ch) => Some(*ch),
+                CharMatcher::OneOf(lst) => lst.chars().next(),
+                CharMatcher::Range(bot, _) => Some(*bot),
+                CharMatcher::UnicodeRange(_) | CharMatcher::UnknownCategory(_) => None,
+            });
+            if direct.is_some() {
+                return direct;
+            }
+        }
+        (0x20..0x7F).chain(0x80..=0xFFFF).filter_map(char::from_u32).find(|ch| self.accept(*ch))
+    }
+
+    /// if this matches exactly one particular character, that character
+    pub fn single_char(&self) -> Option<char> {
+        match (self.is_exclude, self.matchers.as_slice()) {
+            (false, [CharMatcher::Exact(ch)]) => Some(*ch),
+            _ => None,
         }
     }
 
     pub fn union() -> LitBuilder {
         LitBuilder::new()
     }
+
+    /// find a character (if any) accepted by both `Lit`s
+    /// exact unless there's an exclusion, which falls back to scanning the BMP
+    fn overlap(&self, other: &Lit) -> Option<char> {
+        if !self.is_exclude && !other.is_exclude && !self.ignore_case && !other.ignore_case {
+            return self.chars.intersect(&other.chars).ranges().first().map(|(bot, _)| *bot);
+        }
+        let mut candidates: Vec<char> = Vec::new();
+        let mut needs_scan = self.is_exclude || other.is_exclude || self.ignore_case || other.ignore_case;
+        for m in self.matchers.iter().chain(other.matchers.iter()) {
+            match m {
+                CharMatcher::Exact(ch) => candidates.push(*ch),
+                CharMatcher::OneOf(lst) => candidates.extend(lst.chars()),
+                CharMatcher::Range(bot, _) => candidates.push(*bot),
+                CharMatcher::UnicodeRange(_) => needs_scan = true,
+                CharMatcher::UnknownCategory(_) => {}
+            }
+        }
+        if needs_scan {
+            candidates.extend((0..=0xFFFF).filter_map(char::from_u32));
+        }
+        candidates.into_iter().find(|ch| self.accept(*ch) && other.accept(*ch))
+    }
 }
 
+/// ixml syntax: a quoted string for a single character, otherwise a character class like `~["0"-"9"; Nd]`
 impl fmt::Display for Lit {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        let s: String = self.matchers.iter().map(std::string::ToString::to_string).collect::<Vec<_>>().join(" | ");
-        let prefix = if self.is_exclude { "~" } else { "" };
-        write!(f, "{prefix}[{s}]")
+        write!(f, "{}", Grammar::ixml_lit(self))
     }
 }
 
 #[derive(Debug, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 enum CharMatcher {
     Exact(char),
     OneOf(SmolStr),
     Range(char, char),
-    UnicodeRange(SmolStr),
+    UnicodeRange(UnicodeRange),
+    /// a category name that isn't one; it matches nothing, but is kept for `Grammar::validate` to report
+    UnknownCategory(SmolStr),
 }
 
 impl CharMatcher {
-    pub fn accept(&self, test: char) -> bool {
+    /// add every character this matches to `chars`
+    fn add_to(&self, chars: &mut CharSet) {
         match self {
-            Self::Exact(ch) => *ch==test,
-            Self::OneOf(lst) => lst.contains(test),
-            Self::Range(bot, top) => test <= *top && test >= *bot,
-            Self::UnicodeRange(name) => UnicodeRange::new(name).accept(test),
+            Self::Exact(ch) => chars.insert(*ch),
+            Self::OneOf(lst) => lst.chars().for_each(|ch| chars.insert(ch)),
+            Self::Range(bot, top) => chars.insert_range(*bot, *top),
+            Self::UnicodeRange(range) => chars.insert_category(*range),
+            Self::UnknownCategory(_) => {}
         }
     }
 }
@@ -371,8 +1350,144 @@ impl fmt::Display for CharMatcher {
             Self::Exact(ch) => write!(f, "'{ch}'"),
             Self::OneOf(str) => write!(f, "[\"{str}\"]"),
             Self::Range(bot, top) => write!(f, "[\"{bot}\"-\"{top}\"]"),
-            Self::UnicodeRange(name) => write!(f, "Unicode range {name}"),
+            Self::UnicodeRange(range) => write!(f, "Unicode range {range}"),
+            Self::UnknownCategory(name) => write!(f, "Unicode range {name}"),
+        }
+    }
+}
+
+/// A set of characters, kept as sorted, non-overlapping inclusive ranges.
+/// Unicode categories (like `Nd`) are kept as categories rather than expanded
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct CharSet {
+    ranges: Vec<(char, char)>,
+    categories: Vec<UnicodeRange>,
+}
+
+impl CharSet {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn insert(&mut self, ch: char) {
+        self.insert_range(ch, ch);
+    }
+
+    /// add every character from `bot` to `top`, inclusive
+    pub fn insert_range(&mut self, bot: char, top: char) {
+        if bot > top {
+            return;
+        }
+        self.ranges.push((bot, top));
+        self.normalize();
+    }
+
+    /// sort the ranges, merging any that overlap or are directly adjacent
+    fn normalize(&mut self) {
+        self.ranges.sort_unstable();
+        let mut merged: Vec<(char, char)> = Vec::with_capacity(self.ranges.len());
+        for (bot, top) in self.ranges.drain(..) {
+            match merged.last_mut() {
+                // overlapping or directly adjacent
+                Some(last) if bot as u32 <= last.1 as u32 + 1 => last.1 = last.1.max(top),
+                _ => merged.push((bot, top)),
+            }
+        }
+        self.ranges = merged;
+    }
+
+    /// add a whole Unicode general category
+    pub fn insert_category(&mut self, range: UnicodeRange) {
+        if let Err(idx) = self.categories.binary_search(&range) {
+            self.categories.insert(idx, range);
+        }
+    }
+
+    pub fn union(&self, other: &CharSet) -> CharSet {
+        let mut result = self.clone();
+        for (bot, top) in &other.ranges {
+            result.insert_range(*bot, *top);
+        }
+        for range in &other.categories {
+            result.insert_category(*range);
+        }
+        result
+    }
+
+    /// the characters in both sets. Unicode categories get expanded into explicit ranges
+    pub fn intersect(&self, other: &CharSet) -> CharSet {
+        let (ours, theirs) = (self.expanded(), other.expanded());
+        let mut result = CharSet::new();
+        let (mut i, mut j) = (0, 0);
+        while i < ours.ranges.len() && j < theirs.ranges.len() {
+            let (our_bot, our_top) = ours.ranges[i];
+            let (their_bot, their_top) = theirs.ranges[j];
+            let (bot, top) = (our_bot.max(their_bot), our_top.min(their_top));
+            if bot <= top {
+                result.ranges.push((bot, top));
+            }
+            if our_top < their_top {
+                i += 1;
+            } else {
+                j += 1;
+            }
+        }
+        result
+    }
+
+    /// the same characters, with every Unicode category expanded into explicit ranges
+    fn expanded(&self) -> CharSet {
+        let mut ranges = self.ranges.clone();
+        for range in &self.categories {
+            ranges.extend(range.ranges()
+                .filter_map(|(bot, top)| Some((char::from_u32(bot)?, char::from_u32(top)?))));
+        }
+        let mut result = CharSet { ranges, categories: Vec::new() };
+        result.normalize();
+        result
+    }
+
+    pub fn contains(&self, ch: char) -> bool {
+        let in_ranges = self.ranges.binary_search_by(|(bot, top)| {
+            if *top < ch {
+                std::cmp::Ordering::Less
+            } else if *bot > ch {
+                std::cmp::Ordering::Greater
+            } else {
+                std::cmp::Ordering::Equal
+            }
+        }).is_ok();
+        in_ranges || self.categories.iter().any(|range| range.accept(ch))
+    }
+
+    /// the explicit ranges, in order
+    pub fn ranges(&self) -> &[(char, char)] {
+        &self.ranges
+    }
+
+    /// the Unicode categories
+    pub fn categories(&self) -> &[UnicodeRange] {
+        &self.categories
+    }
+
+    /// every character outside the explicit ranges. Categories aren't carried over
+    fn complement_ranges(&self) -> CharSet {
+        // step over the surrogate gap, where there are no chars
+        let after = |ch: char| if ch == '\u{D7FF}' { Some('\u{E000}') } else { char::from_u32(ch as u32 + 1) };
+        let before = |ch: char| if ch == '\u{E000}' { Some('\u{D7FF}') } else { (ch as u32).checked_sub(1).and_then(char::from_u32) };
+        let mut result = CharSet::new();
+        let mut next = Some('\0');
+        for (bot, top) in &self.ranges {
+            if let (Some(from), Some(to)) = (next, before(*bot)) {
+                result.insert_range(from, to);
+            }
+            next = after(*top);
         }
+        if let Some(from) = next {
+            result.insert_range(from, char::MAX);
+        }
+        result
     }
 }
 
@@ -387,28 +1502,38 @@ impl LitBuilder {
     }
 
     /// accept a single char
-    pub fn ch(mut self, ch: char) -> Self {
-        let matcher = CharMatcher::Exact(ch);
-        self.lit.matchers.push(matcher);
-        self
+    pub fn ch(self, ch: char) -> Self {
+        self.push(CharMatcher::Exact(ch))
     }
 
     /// accept a single char out of a list
-    pub fn ch_in(mut self, chrs: &str) -> Self {
-        let matcher = CharMatcher::OneOf(SmolStr::new(chrs));
-        self.lit.matchers.push(matcher);
-        self
+    pub fn ch_in(self, chrs: &str) -> Self {
+        self.push(CharMatcher::OneOf(SmolStr::new(chrs)))
     }
 
-    /// accept a single character within a range
-    pub fn ch_range(mut self, bot: char, top: char) -> Self {
-        let matcher = CharMatcher::Range(bot, top);
-        self.lit.matchers.push(matcher);
-        self
+    /// accept a single character within a range; a reversed range (`bot > top`) can never match,
+    /// so it is reported here and rejected by `Grammar::validate`
+    pub fn ch_range(self, bot: char, top: char) -> Self {
+        if bot > top {
+            warn!("reversed character range [\"{bot}\"-\"{top}\"] matches nothing");
+        }
+        self.push(CharMatcher::Range(bot, top))
     }
 
-    pub fn ch_unicode(mut self, range: &str) -> Self {
-        let matcher = CharMatcher::UnicodeRange(SmolStr::new(range));
+    /// accept a single character in a Unicode general category, like `Lu`; an unknown category can never match,
+    /// so it is reported here and rejected by `Grammar::validate`
+    pub fn ch_unicode(self, range: &str) -> Self {
+        match UnicodeRange::new(range) {
+            Ok(range) => self.push(CharMatcher::UnicodeRange(range)),
+            Err(_) => {
+                warn!("unknown Unicode category {range} matches nothing");
+                self.push(CharMatcher::UnknownCategory(SmolStr::new(range)))
+            }
+        }
+    }
+
+    fn push(mut self, matcher: CharMatcher) -> Self {
+        matcher.add_to(&mut self.lit.chars);
         self.lit.matchers.push(matcher);
         self
     }
@@ -417,6 +1542,13 @@ impl LitBuilder {
         self.lit.is_exclude = true;
         self
     }
+
+    /// match ignoring case, so `["a"-"z"]` also accepts "Q".
+    /// Applies to the whole `Lit`, like `Grammar::set_case_insensitive` does for every `Lit` in a grammar
+    pub fn ci(mut self) -> Self {
+        self.lit.ignore_case = true;
+        self
+    }
 }
 
 /// A general way to track specifics needed to name rules
@@ -459,9 +1591,8 @@ pub struct SeqBuilder<'a> {
     factors: Vec<Factor>,
 
     /// in the course of building a rule, we may end up synthesizing additional rules.
-    /// These need to eventually get added into the resulting grammar
-    syn_rules: HashMap<SmolStr, Vec<SeqBuilder<'a>>>,
-    defn_order: Vec<SmolStr>,
+    /// These need to eventually get added into the resulting grammar, in insertion order
+    syn_rules: IndexMap<SmolStr, Vec<SeqBuilder<'a>>>,
 
     context: &'a RuleContext,
 }
@@ -469,7 +1600,7 @@ pub struct SeqBuilder<'a> {
 impl<'a> SeqBuilder<'a> {
 
     fn new(context: &'a RuleContext) -> Self {
-        Self { factors: Vec::new(), syn_rules: HashMap::new(), defn_order: Vec::new(), context }
+        Self { factors: Vec::new(), syn_rules: IndexMap::new(), context }
     }
 
     /// Convenience function: accept a single char
@@ -484,6 +1615,31 @@ impl<'a> SeqBuilder<'a> {
         self
     }
 
+    /// Convenience function: accept a string, one char after another
+    pub fn str(self, string: &str) -> Self {
+        self.mark_str(string, TMark::Default)
+    }
+
+    /// Convenience function: accept a string, one char after another, each with the specified `TMark`
+    pub fn mark_str(mut self, string: &str, tmark: TMark) -> Self {
+        for ch in string.chars() {
+            self = self.mark_ch(ch, tmark);
+        }
+        self
+    }
+
+    /// Convenience function: accept a single char, in either case
+    pub fn ch_ci(self, ch: char) -> Self {
+        self.mark_ch_ci(ch, TMark::Default)
+    }
+
+    /// Convenience function: accept a single char in either case, with specified `TMark`
+    pub fn mark_ch_ci(mut self, ch: char, tmark: TMark) -> Self {
+        let factor = Factor::new_lit(Lit::union().ch(ch).ci(), tmark);
+        self.factors.push(factor);
+        self
+    }
+
     /// Convenience function: accept a single char out of a list
     pub fn ch_in(self, chrs: &str) -> Self {
         self.mark_ch_in(chrs, TMark::Default)
@@ -544,20 +1700,22 @@ impl<'a> SeqBuilder<'a> {
         self
     }
 
+    /// insertion: text that appears in the output without matching any input
+    pub fn insert(mut self, text: &str) -> Self {
+        self.factors.push(Factor::Insertion(SmolStr::new(text)));
+        self
+    }
+
     /// record an entirely new (internal, synthesized) named rule
     fn syn_rule(mut self, name: &str, mut rb: Self) -> Self {
         self = self.siphon(&mut rb);
-        let vec = self.syn_rules.entry(SmolStr::new(name)).or_insert(Vec::new());
-        vec.push(rb);
-        let smol_name = SmolStr::new(name);
-        if !self.defn_order.contains(&smol_name) {
-            self.defn_order.push(smol_name); // maintain insertion order
-        }
+        self.syn_rules.entry(SmolStr::new(name)).or_default().push(rb);
         self
     }
 
-    /// take primary rule from another `RuleBuilder`
+    /// take primary rule (and any synthesized rules) from another `SeqBuilder`
     pub fn expr(mut self, mut sub: Self) -> Self {
+        self = self.siphon(&mut sub);
         for t in sub.factors.drain(..) {
             self.factors.push(t)
         }
@@ -565,17 +1723,10 @@ impl<'a> SeqBuilder<'a> {
     }
     
     /// call this on any sub-rules to make sure any generated `syn_rules` get passed along.
+    /// Names and bodies live in the same `IndexMap`, so insertion order can't drift from the rules themselves
     fn siphon(mut self, sub: &mut Self) -> Self {
-        for name in sub.defn_order.drain(..) { // maintain insertion order
-            let rule = sub.syn_rules.remove(&name); //.expect("intenal syn_rules and defn_order out of sync");
-            if rule.is_none() {
-                println!("###### {name} ######");
-                dbg!(&self.defn_order);
-                dbg!(&self.syn_rules);
-            }
-            let rule = rule.expect("defn_order and syn_rules out of sync");
-            self.defn_order.push(name.clone());
-            self.syn_rules.insert(name, rule);
+        for (name, rules) in sub.syn_rules.drain(..) {
+            self.syn_rules.entry(name).or_default().extend(rules);
         }
         self
     }
@@ -669,6 +1820,12 @@ impl<'a> SeqBuilder<'a> {
         self.nt(f_opt)
     }
 
+    /// a line ending in any of the usual conventions: "\r\n", "\n", or "\r"
+    pub fn newline(self) -> Self {
+        let ctx = self.context;
+        self.alts(vec![ctx.seq().ch('\r').ch('\n'), ctx.seq().ch('\n'), ctx.seq().ch('\r')])
+    }
+
     /// internal identifier for synthesized rules
     /// all internal ids start with double hyphens
     fn mint_internal_id(&mut self, hint: &str) -> String {
@@ -680,3 +1837,507 @@ impl<'a> SeqBuilder<'a> {
 
 }
 
+
+#[test]
+fn test_mark_combine() {
+    use Mark::*;
+    // (definition, reference, expected)
+    let table = [
+        (Default, Default, Default),
+        (Default, Unmute, Unmute),
+        (Default, Mute, Mute),
+        (Default, Attr, Attr),
+        (Unmute, Default, Unmute),
+        (Unmute, Unmute, Unmute),
+        (Unmute, Mute, Mute),
+        (Unmute, Attr, Attr),
+        (Mute, Default, Mute),
+        (Mute, Unmute, Unmute), // ^ undoes -
+        (Mute, Mute, Mute),
+        (Mute, Attr, Attr),
+        (Attr, Default, Attr),
+        (Attr, Unmute, Unmute), // reference overrides definition
+        (Attr, Mute, Mute),
+        (Attr, Attr, Attr),
+    ];
+    for (defn, reference, expected) in table {
+        assert_eq!(Mark::combine(defn, reference), expected, "combining {defn:?} with {reference:?}");
+    }
+}
+
+#[test]
+fn test_conflict_report() {
+    // S = "a" | "a", "b".
+    let mut g = Grammar::new();
+    let ctx = RuleContext::new("S");
+    g.define("S", ctx.seq().ch('a'));
+    g.define("S", ctx.seq().ch('a').ch('b'));
+    let report = g.conflict_report();
+    assert!(report.starts_with("S: alternatives 1 and 2 can both start with 'a'"), "{report}");
+
+    // S = "a" | "b".
+    let mut g = Grammar::new();
+    let ctx = RuleContext::new("S");
+    g.define("S", ctx.seq().ch('a'));
+    g.define("S", ctx.seq().ch('b'));
+    assert_eq!(g.conflict_report(), "");
+}
+
+#[test]
+fn test_ixml_grammar_entry() {
+    use crate::parser::Parser;
+
+    let mut parser = Parser::new(Grammar::ixml());
+    let arena = parser.parse(r#"doc = "a"."#).unwrap();
+    assert_eq!(Parser::tree_to_testfmt(&arena), r#"<ixml><rule name="doc"><alt><literal string="a"></literal></alt></rule></ixml>"#);
+}
+
+#[test]
+fn test_to_parse_tree() {
+    use crate::parser::Parser;
+    use crate::ixml_grammar::{ixml_str_to_grammar, ixml_tree_to_grammar};
+
+    // the tree shape matches what the ixml parser produces
+    let mut g = Grammar::new();
+    let ctx = RuleContext::new("doc");
+    g.define("doc", ctx.seq().mark_nt("x", Mark::Attr).mark_ch('a', TMark::Mute));
+    let ctx = RuleContext::new("x");
+    g.define("x", ctx.seq().lit(Lit::union().ch_range('0', '9').ch_unicode("Lu").exclude()));
+    let tree = g.to_parse_tree();
+    assert_eq!(Parser::tree_to_testfmt(&tree),
+        concat!(r#"<ixml><rule name="doc"><alt><nonterminal mark="@" name="x"></nonterminal><literal tmark="-" string="a"></literal></alt></rule>"#,
+        r#"<rule name="x"><alt><exclusion><member from="0" to="9"></member><member code="Lu"></member></exclusion></alt></rule></ixml>"#));
+
+    // and round-trips
+    let g = ixml_str_to_grammar(r#"doc = "a", b?, -c+. b = "b". -c = -"c"."#).unwrap();
+    let round_tripped = ixml_tree_to_grammar(&g.to_parse_tree()).unwrap();
+    assert_eq!(round_tripped.to_string(), g.to_string());
+}
+
+#[test]
+fn test_inline_single_use() {
+    use crate::parser::Parser;
+
+    // doc = "a", helper, tail, "a"+. -helper = "b" | "c". tail = "d".
+    let mut g = Grammar::new();
+    let ctx = RuleContext::new("doc");
+    g.define("doc", ctx.seq().ch('a').nt("helper").nt("tail").repeat1(ctx.seq().ch('a')));
+    let ctx = RuleContext::new("helper");
+    g.mark_define(Mark::Mute, "helper", ctx.seq().ch('b'));
+    g.mark_define(Mark::Mute, "helper", ctx.seq().ch('c'));
+    let ctx = RuleContext::new("tail");
+    g.define("tail", ctx.seq().ch('d'));
+
+    let mut inlined = g.clone();
+    inlined.inline_single_use();
+    // helper and the outer synthesized "a"+ rule are gone; tail produces an element,
+    // and the synthesized "a"* rules beneath "a"+ are recursive, so those stay
+    assert_eq!(g.get_rule_count(), 6);
+    assert_eq!(inlined.get_rule_count(), 4);
+    assert_eq!(inlined.defn_order, vec!["doc", "--doc.f-option3", "--doc.f-star2", "tail"]);
+    assert_eq!(inlined.get_definition("doc").iter().count(), 2);
+
+    for input in ["abda", "acdaa"] {
+        let before = Parser::tree_to_testfmt(&Parser::new(g.clone()).parse(input).unwrap());
+        let after = Parser::tree_to_testfmt(&Parser::new(inlined.clone()).parse(input).unwrap());
+        assert_eq!(before, after);
+    }
+}
+
+#[cfg(feature = "bincode")]
+#[test]
+fn test_bytes_round_trip() {
+    use crate::builtin_grammars::{ParserTestSet, SmokeWiki};
+    use crate::parser::Parser;
+
+    let g = SmokeWiki{}.get_grammar();
+    let restored = Grammar::from_bytes(&g.to_bytes()).unwrap();
+    assert_eq!(restored.to_string(), g.to_string());
+    assert_eq!(restored.defn_order, g.defn_order);
+    let expected = Parser::tree_to_testfmt(&Parser::new(g).parse("1+2*3").unwrap());
+    assert_eq!(Parser::tree_to_testfmt(&Parser::new(restored).parse("1+2*3").unwrap()), expected);
+
+    assert!(Grammar::from_bytes(b"not a grammar").is_err());
+}
+
+#[test]
+fn test_newline() {
+    use crate::parser::Parser;
+
+    // doc = line++newline. line = ["a"-"z"]+.
+    let mut g = Grammar::new();
+    let ctx = RuleContext::new("doc");
+    g.define("doc", ctx.seq().repeat1_sep(ctx.seq().nt("line"), ctx.seq().newline()));
+    let ctx = RuleContext::new("line");
+    g.define("line", ctx.seq().repeat1(ctx.seq().ch_range('a', 'z')));
+
+    let mut parser = Parser::new(g);
+    let arena = parser.parse("ab\ncd\r\nef\rg").unwrap();
+    assert_eq!(Parser::tree_to_testfmt(&arena), "<doc><line>ab</line>\n<line>cd</line>\r\n<line>ef</line>\r<line>g</line></doc>");
+}
+
+#[test]
+fn test_same_language_shape() {
+    use crate::builtin_grammars::{ParserTestSet, SmokeElem, SmokeAttr, SmokeNT};
+
+    let elem = SmokeElem{}.get_grammar();
+    let attr = SmokeAttr{}.get_grammar();
+    assert_ne!(elem.to_string(), attr.to_string());
+    assert!(elem.same_language_shape(&attr));
+    assert!(attr.same_language_shape(&elem));
+    assert!(!elem.same_language_shape(&SmokeNT{}.get_grammar()));
+
+    // a muted terminal is still the same terminal
+    let mut g1 = Grammar::new();
+    let ctx = RuleContext::new("doc");
+    g1.define("doc", ctx.seq().ch('a'));
+    let mut g2 = Grammar::new();
+    let ctx = RuleContext::new("doc");
+    g2.define("doc", ctx.seq().mark_ch('a', TMark::Mute));
+    assert!(g1.same_language_shape(&g2));
+    let mut g3 = Grammar::new();
+    let ctx = RuleContext::new("doc");
+    g3.define("doc", ctx.seq().ch('b'));
+    assert!(!g1.same_language_shape(&g3));
+
+    // doc = "a", +"X".   insertions match nothing, so they don't change the shape
+    let mut g4 = Grammar::new();
+    let ctx = RuleContext::new("doc");
+    g4.define("doc", ctx.seq().ch('a').insert("X"));
+    assert!(g4.same_language_shape(&g4));
+    assert!(g4.same_language_shape(&g1));
+    assert!(g1.same_language_shape(&g4));
+}
+
+#[test]
+fn test_validate_and_unused() {
+    // doc = a, (b | c)?. a = "a". b = "b". orphan = "o".   (c is never defined)
+    let mut g = Grammar::new();
+    let ctx = RuleContext::new("doc");
+    g.define("doc", ctx.seq().nt("a").opt(ctx.seq().alts(vec![ctx.seq().nt("b"), ctx.seq().nt("c")])));
+    let ctx = RuleContext::new("a");
+    g.define("a", ctx.seq().ch('a'));
+    let ctx = RuleContext::new("b");
+    g.define("b", ctx.seq().ch('b'));
+    let ctx = RuleContext::new("orphan");
+    g.define("orphan", ctx.seq().ch('o'));
+
+    let err = g.validate().unwrap_err().to_string();
+    assert!(err.contains("c (referenced from doc)"), "{err}");
+    assert!(!err.contains(" a "), "{err}");
+    assert_eq!(g.find_unused_rules(), vec!["orphan"]);
+
+    let ctx = RuleContext::new("c");
+    g.define("c", ctx.seq().ch('c'));
+    assert!(g.validate().is_ok());
+    assert!(Grammar::new().validate().is_err());
+
+    // @doc = "a".
+    let mut g = Grammar::new();
+    let ctx = RuleContext::new("doc");
+    g.mark_define(Mark::Attr, "doc", ctx.seq().ch('a'));
+    let err = g.validate().unwrap_err().to_string();
+    assert!(err.contains("root rule cannot be an attribute"), "{err}");
+
+    // doc = ["9"-"0"].
+    let mut g = Grammar::new();
+    let ctx = RuleContext::new("doc");
+    g.define("doc", ctx.seq().lit(LitBuilder::new().ch_range('9', '0')));
+    let err = g.validate().unwrap_err().to_string();
+    assert!(err.contains(r#"["9"-"0"] (in doc)"#), "{err}");
+
+    // doc = [Xx].
+    let mut g = Grammar::new();
+    let ctx = RuleContext::new("doc");
+    g.define("doc", ctx.seq().ch_unicode("Xx"));
+    let err = g.validate().unwrap_err().to_string();
+    assert!(err.contains("unknown Unicode categories: Xx (in doc)"), "{err}");
+}
+
+#[test]
+fn test_nested_sugar_siphon() {
+    use crate::parser::Parser;
+    // doc = ((a | b)*, c?)++sep. a = "a". b = "b". c = "c". sep = ",".
+    let mut g = Grammar::new();
+    let ctx = RuleContext::new("doc");
+    let inner = ctx.seq()
+        .repeat0(ctx.seq().alts(vec![ctx.seq().nt("a"), ctx.seq().nt("b")]))
+        .opt(ctx.seq().nt("c"));
+    g.define("doc", ctx.seq().repeat1_sep(inner, ctx.seq().nt("sep")));
+    for (name, ch) in [("a", 'a'), ("b", 'b'), ("c", 'c'), ("sep", ',')] {
+        let ctx = RuleContext::new(name);
+        g.define(name, ctx.seq().ch(ch));
+    }
+    assert!(g.validate().is_ok(), "{}", g.validate().unwrap_err());
+    let synthesized = g.expand_sugar();
+    assert!(synthesized.iter().any(|name| name.contains("f-plus-sep")), "{synthesized:?}");
+    assert!(synthesized.iter().any(|name| name.contains("f-opt")), "{synthesized:?}");
+
+    let mut parser = Parser::new(g);
+    let result = Parser::tree_to_testfmt(&parser.parse("abc,,ba").unwrap());
+    assert_eq!(result, "<doc><a>a</a><b>b</b><c>c</c><sep>,</sep><sep>,</sep><b>b</b><a>a</a></doc>");
+}
+
+#[test]
+fn test_complexity() {
+    use crate::builtin_grammars::{ParserTestSet, SmokeWiki};
+
+    // doc = S. S = S, "+", M | M. M = M, "*", T | T. T = ["1234"].
+    let metrics = SmokeWiki{}.get_grammar().complexity();
+    assert_eq!(metrics, GrammarMetrics { total_factors: 10, max_alts: 2, recursion_depth: 4, nullable_rules: 0 });
+
+    // doc = "a"*.
+    let mut g = Grammar::new();
+    let ctx = RuleContext::new("doc");
+    g.define("doc", ctx.seq().repeat0(ctx.seq().ch('a')));
+    let metrics = g.complexity();
+    assert!(metrics.nullable_rules >= 2, "{metrics:?}");
+    assert!(metrics.recursion_depth >= 2, "{metrics:?}");
+}
+
+#[test]
+fn test_case_insensitive() {
+    use crate::parser::Parser;
+
+    // doc = "select", " ", ~["x"], ".".
+    let mut g = Grammar::new();
+    let ctx = RuleContext::new("doc");
+    g.define("doc", ctx.seq().ch('s').ch('e').ch('l').ch('e').ch('c').ch('t').ch(' ')
+        .lit(Lit::union().ch('x').exclude()).ch('.'));
+    assert!(Parser::new(g.clone()).parse("SeLeCt a.").is_err());
+
+    g.set_case_insensitive(true);
+    let arena = Parser::new(g.clone()).parse("SeLeCt a.").unwrap();
+    assert_eq!(Parser::tree_to_testfmt(&arena), "<doc>SeLeCt a.</doc>");
+    // the exclusion ignores case too
+    assert!(Parser::new(g).parse("select X.").is_err());
+}
+
+#[test]
+fn test_case_insensitive_lit() {
+    use crate::parser::Parser;
+
+    let lit = Lit::union().ch_range('a', 'z').ci().lit;
+    assert!(lit.accept('q') && lit.accept('Q') && !lit.accept('1'));
+    let lit = Lit::union().ch('x').exclude().ci().lit;
+    assert!(!lit.accept('x') && !lit.accept('X') && lit.accept('y'));
+    // the uppercase of 'ß' is "SS", which doesn't make it a match for "s"
+    let lit = Lit::union().ch('s').ci().lit;
+    assert!(lit.accept('S') && !lit.accept('ß'));
+    let lit = Lit::union().ch('ß').ci().lit;
+    assert!(lit.accept('ß') && !lit.accept('S'));
+
+    // doc = "a"i, "b".
+    let mut g = Grammar::new();
+    let ctx = RuleContext::new("doc");
+    g.define("doc", ctx.seq().ch_ci('a').ch('b'));
+    let arena = Parser::new(g.clone()).parse("Ab").unwrap();
+    assert_eq!(Parser::tree_to_testfmt(&arena), "<doc>Ab</doc>");
+    assert!(Parser::new(g.clone()).parse("ab").is_ok());
+    assert!(Parser::new(g).parse("AB").is_err());
+}
+
+#[test]
+fn test_alphabet() {
+    // doc = ["0"-"9"], "+".
+    let mut g = Grammar::new();
+    let ctx = RuleContext::new("doc");
+    g.define("doc", ctx.seq().ch_range('0', '9').ch('+'));
+    let alphabet = g.alphabet();
+    assert!(('0'..='9').all(|ch| alphabet.contains(ch)));
+    assert!(alphabet.contains('+'));
+    assert!(!alphabet.contains('a'));
+    assert_eq!(alphabet.ranges(), &[('+', '+'), ('0', '9')]);
+
+    // doc = ~["b"-"y"], [L].
+    let mut g = Grammar::new();
+    let ctx = RuleContext::new("doc");
+    g.define("doc", ctx.seq().lit(Lit::union().ch_range('b', 'y').exclude()).ch_unicode("L"));
+    let alphabet = g.alphabet();
+    assert!(alphabet.contains('a') && alphabet.contains('z') && alphabet.contains('m'));
+    assert_eq!(alphabet.categories(), &[UnicodeRange::L]);
+}
+
+#[test]
+fn test_charset() {
+    let mut lower = CharSet::new();
+    lower.insert_range('a', 'm');
+    let mut upper = CharSet::new();
+    upper.insert_range('h', 'z');
+    upper.insert('0');
+    let both = lower.union(&upper);
+    assert_eq!(both.ranges(), &[('0', '0'), ('a', 'z')]);
+    assert!(both.contains('a') && both.contains('q') && both.contains('0'));
+    assert!(!both.contains('1') && !both.contains('A'));
+    assert_eq!(lower.intersect(&upper).ranges(), &[('h', 'm')]);
+
+    let mut digits = CharSet::new();
+    digits.insert_category(UnicodeRange::Nd);
+    let mut ascii = CharSet::new();
+    ascii.insert_range('\0', '\u{7F}');
+    assert!(digits.contains('7') && !digits.contains('x'));
+    assert_eq!(digits.intersect(&ascii).ranges(), &[('0', '9')]);
+
+    // Lit matching goes through the same set
+    let lit = Factor::new_lit(Lit::union().ch_range('a', 'm').ch_range('h', 'z').ch('0'), TMark::Default);
+    let Factor::Terminal(_, lit) = lit else { unreachable!() };
+    assert_eq!(lit.chars, both);
+    assert!(lit.accept('q') && !lit.accept('1'));
+}
+
+#[test]
+fn test_category_resolved_when_built() {
+    use crate::parser::Parser;
+
+    // categories are looked up once, when the Lit is built, not on every character scanned
+    let lit = Lit::union().ch_unicode("Nd").ch_range('0', '9').lit;
+    assert_eq!(lit.matchers[0], CharMatcher::UnicodeRange(UnicodeRange::Nd));
+    assert_eq!(lit.chars.categories(), &[UnicodeRange::Nd]);
+
+    // and the resolved category matches just as a range does
+    // doc = ["0"-"9"]+. and doc = [Nd]+.
+    let input = "0123456789".repeat(20);
+    for lit in [Lit::union().ch_range('0', '9'), Lit::union().ch_unicode("Nd")] {
+        let mut g = Grammar::new();
+        let ctx = RuleContext::new("doc");
+        g.define("doc", ctx.seq().repeat1(ctx.seq().lit(lit)));
+        let arena = Parser::new(g).parse(&input).unwrap();
+        assert_eq!(Parser::tree_to_testfmt(&arena), format!("<doc>{input}</doc>"));
+    }
+}
+
+#[test]
+fn test_generate_examples() {
+    use crate::parser::Parser;
+
+    // doc = "a" | "b".
+    let mut g = Grammar::new();
+    let ctx = RuleContext::new("doc");
+    g.define("doc", ctx.seq().ch('a'));
+    g.define("doc", ctx.seq().ch('b'));
+    assert_eq!(g.generate_examples(10), vec!["a", "b"]);
+
+    // doc = ["0"-"9"]+, ("+", ["0"-"9"]+)*.   (infinitely many sentences)
+    let mut g = Grammar::new();
+    let ctx = RuleContext::new("doc");
+    g.define("doc", ctx.seq()
+        .repeat1(ctx.seq().ch_range('0', '9'))
+        .repeat0(ctx.seq().ch('+').repeat1(ctx.seq().ch_range('0', '9'))));
+    let examples = g.generate_examples(5);
+    assert_eq!(examples.len(), 5);
+    assert_eq!(examples[0], "0");
+    for example in examples {
+        let arena = Parser::new(g.clone()).parse(&example).unwrap();
+        assert_eq!(Parser::tree_to_testfmt(&arena), format!("<doc>{example}</doc>"));
+    }
+}
+
+#[test]
+fn test_recursive_rules() {
+    use crate::builtin_grammars::{ParserTestSet, SmokeWiki};
+
+    // doc = S. S = S, "+", M | M. M = M, "*", T | T. T = ["1234"].
+    let recursive = SmokeWiki{}.get_grammar().recursive_rules();
+    assert_eq!(recursive, HashSet::from([SmolStr::new("S"), SmolStr::new("M")]));
+
+    // a = b. b = "x", a | "y".   (mutual recursion)
+    let mut g = Grammar::new();
+    let ctx = RuleContext::new("a");
+    g.define("a", ctx.seq().nt("b"));
+    let ctx = RuleContext::new("b");
+    g.define("b", ctx.seq().ch('x').nt("a"));
+    g.define("b", ctx.seq().ch('y'));
+    assert_eq!(g.recursive_rules().len(), 2);
+}
+
+#[test]
+fn test_to_ebnf() {
+    // doc = "a"*.
+    let mut g = Grammar::new();
+    let ctx = RuleContext::new("doc");
+    g.define("doc", ctx.seq().repeat0(ctx.seq().ch('a')));
+    assert_eq!(g.to_ebnf(), r#"doc ::= "a"*"#);
+
+    // doc = "id:", name++",", ("!"; ".")?, ~[#22]*. name = ["a"-"z"; Nd]+.
+    let mut g = Grammar::new();
+    let ctx = RuleContext::new("doc");
+    g.define("doc", ctx.seq().ch('i').ch('d').ch(':')
+        .repeat1_sep(ctx.seq().nt("name"), ctx.seq().ch(','))
+        .opt(ctx.seq().alts(vec![ctx.seq().ch('!'), ctx.seq().ch('.')]))
+        .repeat0(ctx.seq().lit(Lit::union().ch('"').exclude())));
+    let ctx = RuleContext::new("name");
+    g.define("name", ctx.seq().repeat1(ctx.seq().lit(Lit::union().ch_range('a', 'z').ch_unicode("Nd"))));
+    assert_eq!(g.to_ebnf(), [
+        r#"doc ::= "id:" name ("," name)* ("!" | ".")? [^"]*"#,
+        r#"name ::= [a-z\p{Nd}]+"#,
+    ].join("\n"));
+}
+
+#[test]
+fn test_display_sugar() {
+    use crate::ixml_grammar::ixml_str_to_grammar;
+
+    let g = ixml_str_to_grammar(r#"doc = "a"*."#).unwrap();
+    assert_eq!(g.to_string(), "doc = \"a\"*.\n");
+
+    // doc = "id:", name++",", ("!"; ".")?, -~["x"]**-" ". @name = ["a"-"z"]+.
+    let mut g = Grammar::new();
+    let ctx = RuleContext::new("doc");
+    g.define("doc", ctx.seq().ch('i').ch('d').ch(':')
+        .repeat1_sep(ctx.seq().nt("name"), ctx.seq().ch(','))
+        .opt(ctx.seq().alts(vec![ctx.seq().ch('!'), ctx.seq().ch('.')]))
+        .repeat0_sep(ctx.seq().mark_lit(Lit::union().ch('x').exclude(), TMark::Mute), ctx.seq().mark_ch(' ', TMark::Mute)));
+    let ctx = RuleContext::new("name");
+    g.mark_define(Mark::Attr, "name", ctx.seq().repeat1(ctx.seq().ch_range('a', 'z')));
+    let expected = [
+        r#"doc = "id:", name++",", ("!" | ".")?, -~["x"]**-" "."#,
+        r#"@name = ["a"-"z"]+."#,
+    ].join("\n") + "\n";
+    assert_eq!(g.to_string(), expected);
+}
+
+#[test]
+fn test_display_round_trip() {
+    use crate::ixml_grammar::ixml_str_to_grammar;
+
+    let ixml = r##"doc = -"(", item**(-",", s), -")", +#a. item = ^"x" | ~["0"-"9"; Nd]+ | @id. @id = -"#", ["a"-"z"]. -s = " "*."##;
+    let g = ixml_str_to_grammar(ixml).unwrap();
+    let printed = g.to_string();
+    let reparsed = ixml_str_to_grammar(&printed).unwrap_or_else(|e| panic!("{e} in:\n{printed}"));
+    assert_eq!(reparsed.defn_order, g.defn_order);
+    assert_eq!(reparsed.to_string(), printed);
+
+    // a single alternative prints as its factors
+    let alt = &g.definitions["item"].alts[1];
+    assert!(!alt.to_string().contains("Terminal"), "{alt}");
+    let mut g = Grammar::new();
+    let ctx = RuleContext::new("doc");
+    g.define("doc", ctx.seq().mark_ch('a', TMark::Mute).nt("b").insert("c"));
+    assert_eq!(g.definitions["doc"].alts[0].to_string(), r#"-"a", b, +"c""#);
+}
+
+#[test]
+fn test_rename_rule() {
+    use crate::parser::Parser;
+
+    // doc = item, ("," , item)*. item = "a"+.
+    let mut g = Grammar::new();
+    let ctx = RuleContext::new("doc");
+    g.define("doc", ctx.seq().nt("item").repeat0(ctx.seq().ch(',').nt("item")));
+    let ctx = RuleContext::new("item");
+    g.define("item", ctx.seq().repeat1(ctx.seq().ch('a')));
+
+    g.rename_rule("item", "entry").unwrap();
+    assert!(!g.has_definition("item"));
+    assert!(g.has_definition("entry"));
+    assert!(g.defn_order.iter().all(|name| !name.contains("item")));
+    assert!(g.defn_order.iter().any(|name| name.starts_with("--entry.")));
+    assert!(g.validate().is_ok());
+
+    let mut parser = Parser::new(g.clone());
+    let arena = parser.parse("a,aa").unwrap();
+    assert_eq!(Parser::tree_to_testfmt(&arena), "<doc><entry>a</entry>,<entry>aa</entry></doc>");
+
+    assert!(g.rename_rule("entry", "doc").is_err());
+    assert!(g.rename_rule("nosuchrule", "x").is_err());
+}
@@ -1,9 +1,19 @@
+use indexmap::IndexMap;
 use indextree::{Arena, NodeId};
 
-use crate::{grammar::{Grammar, Mark, TMark, SeqBuilder, Lit, RuleContext}, parser::{Content, Parser, ParseError}};
+use crate::{grammar::{Grammar, Mark, TMark, SeqBuilder, Lit, RuleContext}, parser::{Content, Parser, ParseError}, unicode_ranges::UnicodeRange};
 
 /// Bootstrap ixml grammar; hand-coded definition
 pub fn ixml_grammar() -> Grammar {
+    bootstrap_grammar(false)
+}
+
+/// The bootstrap grammar, keeping comments as `Content::Comment` nodes instead of muting them
+pub fn ixml_grammar_with_comments() -> Grammar {
+    bootstrap_grammar(true)
+}
+
+fn bootstrap_grammar(keep_comments: bool) -> Grammar {
     let mut g = Grammar::new();
 
     // ixml: s, prolog?, rule++RS, s.
@@ -12,19 +22,17 @@ pub fn ixml_grammar() -> Grammar {
     g.define("ixml", ctx.seq().nt("s").repeat1_sep(ctx.seq().nt("rule"), ctx.seq().nt("RS")).nt("s"));
 
     // -s: (whitespace; comment)*. {Optional spacing}
-    // TODO: comment
     let ctx = RuleContext::new("s");
-    g.mark_define(Mark::Mute, "s", ctx.seq().repeat0(ctx.seq().nt("whitespace")));
+    g.mark_define(Mark::Mute, "s", ctx.seq().repeat0(ctx.seq().alts(vec![ctx.seq().nt("whitespace"), ctx.seq().nt("comment")])));
 
     // -RS: (whitespace; comment)+. {Required spacing}
-    // TODO: comment
     let ctx = RuleContext::new("RS");
-    g.mark_define(Mark::Mute, "RS", ctx.seq().repeat1( ctx.seq().nt("whitespace")));
+    g.mark_define(Mark::Mute, "RS", ctx.seq().repeat1(ctx.seq().alts(vec![ctx.seq().nt("whitespace"), ctx.seq().nt("comment")])));
 
     // -whitespace: -[Zs]; tab; lf; cr.
-    // TODO: Unicode
+    // (a single character class, so that e.g. a space isn't ambiguously matched by two alternatives)
     let ctx = RuleContext::new("whitespace");
-    g.mark_define(Mark::Mute, "whitespace", ctx.seq().mark_ch_in(" \u{0009}\u{000a}\u{000d}", TMark::Mute));
+    g.mark_define(Mark::Mute, "whitespace", ctx.seq().mark_lit(Lit::union().ch_unicode("Zs").ch_in("\u{0009}\u{000a}\u{000d}"), TMark::Mute));
 
     // -tab: -#9.
     // DNO = Deliberately Not Implemented
@@ -36,10 +44,38 @@ pub fn ixml_grammar() -> Grammar {
     // DNO = Deliberately Not Implemented
 
     // comment: -"{", (cchar; comment)*, -"}".
-    // TODO
-
-    // -cchar: ~["{}"].
-    // TODO
+    // (muted, text and all, so a commented grammar builds the same Grammar as an uncommented one)
+    if keep_comments {
+        // or else one comment node with the text between the outer braces, nested comments included as written:
+        // comment: -"{", (cchar; nested-comment)*, -"}".
+        let ctx = RuleContext::new("comment");
+        g.define("comment", ctx.seq()
+            .mark_ch('{', TMark::Mute)
+            .repeat0(ctx.seq().alts(vec![ctx.seq().nt("cchar"), ctx.seq().nt("nested-comment")]))
+            .mark_ch('}', TMark::Mute));
+        g.set_comment_rule("comment");
+
+        // -nested-comment: "{", (cchar; nested-comment)*, "}".
+        let ctx = RuleContext::new("nested-comment");
+        g.mark_define(Mark::Mute, "nested-comment", ctx.seq()
+            .ch('{')
+            .repeat0(ctx.seq().alts(vec![ctx.seq().nt("cchar"), ctx.seq().nt("nested-comment")]))
+            .ch('}'));
+
+        // -cchar: ~["{}"].
+        let ctx = RuleContext::new("cchar");
+        g.mark_define(Mark::Mute, "cchar", ctx.seq().lit(Lit::union().exclude().ch_in("{}")));
+    } else {
+        let ctx = RuleContext::new("comment");
+        g.mark_define(Mark::Mute, "comment", ctx.seq()
+            .mark_ch('{', TMark::Mute)
+            .repeat0(ctx.seq().alts(vec![ctx.seq().nt("cchar"), ctx.seq().nt("comment")]))
+            .mark_ch('}', TMark::Mute));
+
+        // -cchar: ~["{}"].
+        let ctx = RuleContext::new("cchar");
+        g.mark_define(Mark::Mute, "cchar", ctx.seq().mark_lit(Lit::union().exclude().ch_in("{}"), TMark::Mute));
+    }
 
     // prolog: version, s.
     // TODO
@@ -47,9 +83,11 @@ pub fn ixml_grammar() -> Grammar {
     // version: -"ixml", RS, -"version", RS, string, s, -'.' .
     // TODO
 
-    // rule: (mark, s)?, name, s, -["=:"], s, -alts, -".".
+    // rule: (pragma, s)*, (mark, s)?, name, s, -["=:"], s, -alts, -".".
+    // (the spec gives "=" and ":" exactly the same meaning, so nothing downstream can tell which was used)
     let ctx = RuleContext::new("rule");
     g.define("rule", ctx.seq()
+        .repeat0(ctx.seq().nt("pragma").nt("s"))
         .opt(ctx.seq().nt("mark").nt("s"))
         .nt("name")
         .nt("s")
@@ -58,6 +96,26 @@ pub fn ixml_grammar() -> Grammar {
         .mark_nt("alts", Mark::Mute)
         .mark_ch('.', TMark::Mute) );
 
+    // pragma: -"[", -"+"?, pname, (RS, pdata)?, -"]".
+    // (iXML 1.1 draft; pragmas are kept as metadata and don't affect parsing)
+    let ctx = RuleContext::new("pragma");
+    g.define("pragma", ctx.seq()
+        .mark_ch('[', TMark::Mute)
+        .opt(ctx.seq().mark_ch('+', TMark::Mute))
+        .nt("pname")
+        .opt(ctx.seq().nt("RS").nt("pdata"))
+        .mark_ch(']', TMark::Mute) );
+
+    // @pname: namestart, namefollower*.
+    let ctx = RuleContext::new("pname");
+    g.mark_define(Mark::Attr, "pname", ctx.seq().nt("namestart").repeat0(ctx.seq().nt("namefollower")));
+
+    // @pdata: ~["]"; " "; #9; #a; #d], ~["]"]*.
+    let ctx = RuleContext::new("pdata");
+    g.mark_define(Mark::Attr, "pdata", ctx.seq()
+        .lit(Lit::union().ch_in("] \u{0009}\u{000a}\u{000d}").exclude())
+        .repeat0( ctx.seq().lit(Lit::union().ch(']').exclude())));
+
     // @mark: ["@^-"].
     let ctx = RuleContext::new("mark");
     g.mark_define(Mark::Attr, "mark", ctx.seq().ch_in("@^-"));
@@ -82,10 +140,10 @@ pub fn ixml_grammar() -> Grammar {
     g.mark_define(Mark::Mute, "term", ctx.seq().nt("repeat1"));
 
     // -factor: terminal; nonterminal; insertion; -"(", s, alts, -")", s.
-    // TODO: insertion
     let ctx = RuleContext::new("factor");
     g.mark_define(Mark::Mute, "factor", ctx.seq().nt("terminal"));
     g.mark_define(Mark::Mute, "factor", ctx.seq().nt("nonterminal"));
+    g.mark_define(Mark::Mute, "factor", ctx.seq().nt("insertion"));
     g.mark_define(Mark::Mute, "factor", ctx.seq()
         .mark_ch('(', TMark::Mute).nt("s").nt("alts").mark_ch(')', TMark::Mute).nt("s"));
 
@@ -116,15 +174,17 @@ pub fn ixml_grammar() -> Grammar {
         .nt("name").nt("s") );
 
     // @name: namestart, namefollower*.
-    // TODO: fixme
     let ctx = RuleContext::new("name");
-    g.mark_define(Mark::Attr, "name", ctx.seq().repeat1( ctx.seq().ch_in("_abcdefghijklmnopqrstuvwxyzRS")));
-    
+    g.mark_define(Mark::Attr, "name", ctx.seq().nt("namestart").repeat0(ctx.seq().nt("namefollower")));
+
     // -namestart: ["_"; L].
-    // TODO
+    let ctx = RuleContext::new("namestart");
+    g.mark_define(Mark::Mute, "namestart", ctx.seq().lit(Lit::union().ch('_').ch_unicode("L")));
 
     // -namefollower: namestart; ["-.·‿⁀"; Nd; Mn].
-    // TODO
+    let ctx = RuleContext::new("namefollower");
+    g.mark_define(Mark::Mute, "namefollower", ctx.seq().nt("namestart"));
+    g.mark_define(Mark::Mute, "namefollower", ctx.seq().lit(Lit::union().ch_in("-.·‿⁀").ch_unicode("Nd").ch_unicode("Mn")));
 
     // -terminal: literal; charset.
     let ctx = RuleContext::new("terminal");
@@ -139,7 +199,7 @@ pub fn ixml_grammar() -> Grammar {
     // -quoted: (tmark, s)?, string, s.
     let ctx = RuleContext::new("quoted");
     g.mark_define(Mark::Mute, "quoted", ctx.seq()
-        .opt( ctx.seq().nt("mark").nt("s") )
+        .opt( ctx.seq().nt("tmark").nt("s") )
         .nt("string").nt("s"));
 
     // @tmark: ["^-"].
@@ -147,20 +207,25 @@ pub fn ixml_grammar() -> Grammar {
     g.mark_define(Mark::Attr, "tmark", ctx.seq().ch_in("^-"));
 
     // @string: -'"', dchar+, -'"'; -"'", schar+, -"'".
-    // TODO schar variant
     let ctx = RuleContext::new("string");
     g.mark_define(Mark::Attr, "string", ctx.seq()
         .mark_ch('"', TMark::Mute)
         .repeat1( ctx.seq().nt("dchar"))
         .mark_ch('"', TMark::Mute) );
+    g.mark_define(Mark::Attr, "string", ctx.seq()
+        .mark_ch('\'', TMark::Mute)
+        .repeat1( ctx.seq().nt("schar"))
+        .mark_ch('\'', TMark::Mute) );
 
     // dchar: ~['"'; #a; #d]; '"', -'"'. {all characters except line breaks; quotes must be doubled}
-    // TODO: fixme
     let ctx = RuleContext::new("dchar");
-    g.define("dchar", ctx.seq().ch_in("abcdefghijklmnopqrstuvwxyz ABCDEFGHIJKLMNOPQRSTUVWXYZ"));
+    g.define("dchar", ctx.seq().lit(Lit::union().exclude().ch_in("\"\n\r")));
+    g.define("dchar", ctx.seq().ch('"').mark_ch('"', TMark::Mute));
   
     // schar: ~["'"; #a; #d]; "'", -"'". {all characters except line breaks; quotes must be doubled}
-    // TODO
+    let ctx = RuleContext::new("schar");
+    g.define("schar", ctx.seq().lit(Lit::union().exclude().ch_in("'\n\r")));
+    g.define("schar", ctx.seq().ch('\'').mark_ch('\'', TMark::Mute));
 
     // -encoded: (tmark, s)?, -"#", hex, s.
     let ctx = RuleContext::new("encoded");
@@ -222,10 +287,11 @@ pub fn ixml_grammar() -> Grammar {
     g.mark_define(Mark::Attr, "to", ctx.seq().nt("character"));
 
     // -character: -'"', dchar, -'"'; -"'", schar, -"'"; "#", hex.
-    // TODO: schar variant
     let ctx = RuleContext::new("character");
     g.mark_define(Mark::Mute, "character", ctx.seq()
         .mark_ch('"', TMark::Mute).nt("dchar").mark_ch('"', TMark::Mute));
+    g.mark_define(Mark::Mute, "character", ctx.seq()
+        .mark_ch('\'', TMark::Mute).nt("schar").mark_ch('\'', TMark::Mute));
     g.mark_define(Mark::Mute, "character", ctx.seq().ch('#').nt("hex"));
 
     // -class: code.
@@ -245,12 +311,16 @@ pub fn ixml_grammar() -> Grammar {
     g.mark_define(Mark::Mute, "letter", ctx.seq().ch_range('a', 'z'));
 
     // insertion: -"+", s, (string; -"#", hex), s.
-    // TODO
+    let ctx = RuleContext::new("insertion");
+    g.define("insertion", ctx.seq().mark_ch('+', TMark::Mute).nt("s").nt("string").nt("s"));
+    g.define("insertion", ctx.seq().mark_ch('+', TMark::Mute).nt("s").mark_ch('#', TMark::Mute).nt("hex").nt("s"));
 
     g
 }
 
-/*
+/// The ixml grammar for ixml grammars, in compact syntax, as published in the spec.
+/// `ixml_grammar` is the hand-coded equivalent
+pub const IXML_SOURCE: &str = r##"
     ixml: s, prolog?, rule++RS, s.
 
     -s: (whitespace; comment)*. {Optional spacing}
@@ -323,24 +393,29 @@ member: string;
 -capital: ["A"-"Z"].
 -letter: ["a"-"z"].
 insertion: -"+", s, (string; -"#", hex), s.
-*/
+"##;
 
 /// one stop shopping for ixml String -> Result<Grammar, ParseError>
+/// As in the spec, a rule can be written with either `=` or `:` (e.g. `doc: "a".`); they mean the same thing
 pub fn ixml_str_to_grammar(ixml: &str) -> Result<Grammar, ParseError> {
     let mut ixml_parser = Parser::new(ixml_grammar());
-    let ixml_arena = ixml_parser.parse(ixml.trim())?;
-    let grammar = ixml_tree_to_grammar(&ixml_arena);
-    Ok(grammar)
+    // a grammar that doesn't even parse as ixml is a static error, whatever went wrong parsing it
+    let ixml_arena = ixml_parser.parse(ixml.trim()).map_err(|e| match e {
+        ParseError::DynamicError(msg) => ParseError::static_err(&format!("not an ixml grammar: {msg}")),
+        e => e,
+    })?;
+    ixml_tree_to_grammar(&ixml_arena)
 }
 
 /// Accepts the Arena<Content> resulting from the parse of a valid ixml grammar
 /// Produces a new Grammar as output
-/// TODO: Result<> return type.
-pub fn ixml_tree_to_grammar(arena: &Arena<Content>) -> Grammar {
+pub fn ixml_tree_to_grammar(arena: &Arena<Content>) -> Result<Grammar, ParseError> {
     let mut g = Grammar::new();
 
-    let root_node = arena.iter().next().unwrap(); // first item == root
-    let root_id = arena.get_node_id(root_node).unwrap();
+    let root_node = arena.iter().next() // first item == root
+        .ok_or_else(|| ParseError::static_err("can't convert ixml tree to grammar: empty tree"))?;
+    let root_id = arena.get_node_id(root_node)
+        .ok_or_else(|| ParseError::static_err("can't convert ixml tree to grammar: root node not in arena"))?;
 
     // first a pass over everything, making some indexes as we go
     let mut all_rules: Vec<NodeId> = Vec::new();
@@ -354,93 +429,197 @@ pub fn ixml_tree_to_grammar(arena: &Arena<Content>) -> Grammar {
             _ => {}
         }
     }
-    assert!(all_rules.len() > 0, "can't convert ixml tree to grammar: no rules present! {:?}", &arena);
+    if all_rules.is_empty() {
+        return Err(ParseError::static_err("can't convert ixml tree to grammar: no rules present"));
+    }
     for rule in all_rules {
         let rule_attrs = Parser::get_attributes(arena, rule);
-        let rule_name = &rule_attrs["name"];
-        let rule_mark = rule_attrs.get("mark");
-        let mark = match rule_mark.map(|s| s.as_str()) {
-            Some("@") => Mark::Attr,
-            Some("-") => Mark::Mute,
-            Some("^") => Mark::Unmute,
-            _ => Mark::Default,
-        };
-        ixml_construct_rule(rule, mark, arena, rule_name, &mut g);
+        let rule_name = rule_attrs.get("name")
+            .ok_or_else(|| ParseError::static_err("can't convert ixml tree to grammar: rule without a name"))?;
+        let mark = mark_from_attrs(&rule_attrs);
+        ixml_construct_rule(rule, mark, arena, rule_name, &mut g)?;
+        for pragma in Parser::get_child_elements_named(arena, rule, "pragma") {
+            let pragma_attrs = Parser::get_attributes(arena, pragma);
+            let pname = pragma_attrs.get("pname")
+                .ok_or_else(|| ParseError::static_err("can't convert ixml tree to grammar: pragma without a name"))?;
+            let pdata = pragma_attrs.get("pdata").map(String::as_str).unwrap_or_default();
+            g.add_pragma(rule_name, pname, pdata);
+        }
+    }
+    // catch undefined rules and the like now, rather than as a puzzling failure to parse later
+    g.validate()?;
+    Ok(g)
+}
+
+/// the character named by an encoded literal, e.g. #41 for 'A'
+/// With the (non-standard) `extensions` feature, a leading `d` means decimal instead, e.g. #d65.
+/// Astral-plane values like #1F600 are fine; surrogates (#D800-#DFFF) and anything above #10FFFF aren't characters.
+/// Like every other problem with the grammar itself, these are static errors
+fn encoded_char(code: &str) -> Result<char, ParseError> {
+    #[cfg(feature = "extensions")]
+    let (digits, radix) = match code.strip_prefix('d').filter(|dec| !dec.is_empty()) {
+        Some(dec) => (dec, 10),
+        None => (code, 16),
+    };
+    #[cfg(not(feature = "extensions"))]
+    let (digits, radix) = (code, 16);
+    let value = u32::from_str_radix(digits, radix)
+        .map_err(|_| ParseError::static_err(&format!("#{code} is not a valid character")))?;
+    match value {
+        0xD800..=0xDFFF => Err(ParseError::static_err(&format!("#{code} is a UTF-16 surrogate, not a character"))),
+        0x110000.. => Err(ParseError::static_err(&format!("#{code} is beyond the last Unicode character, #10FFFF"))),
+        _ => char::from_u32(value)
+            .ok_or_else(|| ParseError::static_err(&format!("#{code} is not a valid character"))),
+    }
+}
+
+/// one end of a range, which is either a single character or an encoded one, e.g. "a" or #61
+fn range_char(value: &str) -> Result<char, ParseError> {
+    let mut chars = value.chars();
+    match (chars.next(), chars.next()) {
+        (Some(ch), None) => Ok(ch),
+        (Some('#'), Some(_)) => encoded_char(&value[1..]),
+        _ => Err(ParseError::static_err(&format!("range bound {value} isn't a single character"))),
+    }
+}
+
+/// read the mark="..." attribute of a <rule> or <nonterminal>
+fn mark_from_attrs(attrs: &IndexMap<String, String>) -> Mark {
+    match attrs.get("mark").map(|s| s.as_str()) {
+        Some("@") => Mark::Attr,
+        Some("-") => Mark::Mute,
+        Some("^") => Mark::Unmute,
+        _ => Mark::Default,
+    }
+}
+
+/// read the tmark="..." attribute of a terminal
+fn tmark_from_attrs(attrs: &IndexMap<String, String>) -> TMark {
+    match attrs.get("tmark").map(|s| s.as_str()) {
+        Some("-") => TMark::Mute,
+        Some("^") => TMark::Unmute,
+        _ => TMark::Default,
     }
-    g
 }
 
 /// Fully construct one rule. (which may involve multiple calls to ixml_rulebuilder if there are multiple alts)
-pub fn ixml_construct_rule(rule: NodeId, mark: Mark, arena: &Arena<Content>, rule_name: &str, g: &mut Grammar) {
+pub fn ixml_construct_rule(rule: NodeId, mark: Mark, arena: &Arena<Content>, rule_name: &str, g: &mut Grammar) -> Result<(), ParseError> {
     //println!("Build rule ... {rule_name}");
     let ctx = RuleContext::new(rule_name);
     for (name, eid) in Parser::get_child_elements(arena, rule) {
         if name=="alt" {
-            let rb = ixml_rulebuilder_new(eid, arena, &ctx);
+            let rb = ixml_rulebuilder_new(eid, arena, &ctx)?;
             g.mark_define(mark, rule_name, rb);
         }
     }
+    Ok(())
 }
 
 /// Construct one of what ixml grammar calls an "alt", which is a sequence built from a single `SeqBuilder`
 /// @param `node` is the nodeID of current element, expected to be <alt>, <repeat0>, <repeat1>, <option>, or <sep>
 /// as it only looks at child elements downstream from the `NodeId` passed in
-pub fn ixml_rulebuilder_new<'a>(node: NodeId, arena: &'a Arena<Content>, ctx: &'a RuleContext) -> SeqBuilder<'a> {
+pub fn ixml_rulebuilder_new<'a>(node: NodeId, arena: &'a Arena<Content>, ctx: &'a RuleContext) -> Result<SeqBuilder<'a>, ParseError> {
     let mut seq = ctx.seq();
     for (name, nid) in Parser::get_child_elements(arena, node) {
-        seq = ixml_ruleappend(seq, &name, nid, arena, ctx);
+        seq = ixml_ruleappend(seq, &name, nid, arena, ctx)?;
     }
-    seq
+    Ok(seq)
 }
 
 /// Add additional factors onto the given `SeqBuilder`, possibly recursively
 /// @param `node` is the nodeID of current element, which is diectly processed
-pub fn ixml_ruleappend<'a>(mut seq: SeqBuilder<'a>, name: &str, nid: NodeId, arena: &'a Arena<Content>, ctx: &'a RuleContext) -> SeqBuilder<'a> {
+pub fn ixml_ruleappend<'a>(mut seq: SeqBuilder<'a>, name: &str, nid: NodeId, arena: &'a Arena<Content>, ctx: &'a RuleContext) -> Result<SeqBuilder<'a>, ParseError> {
 
     let attrs = Parser::get_attributes(arena, nid);
+    let attr = |key: &str| attrs.get(key)
+        .ok_or_else(|| ParseError::static_err(&format!("<{name}> is missing its {key} attribute")));
     match name {
+        "alt" => {
+            // a lone <alt> gets spliced directly into the current sequence
+            for (child_name, child) in Parser::get_child_elements(arena, nid) {
+                seq = ixml_ruleappend(seq, &child_name, child, arena, ctx)?;
+            }
+        }
         "alts" => {
             // an <alts> with only one <alt> child can be inlined, otherwise we give it the full treatment
             let alt_elements = Parser::get_child_elements_named(arena, nid, "alt");
             if alt_elements.len()==1 {
-                seq = ixml_ruleappend(seq, "alt", alt_elements[0], arena, ctx);
+                seq = ixml_ruleappend(seq, "alt", alt_elements[0], arena, ctx)?;
             } else {
-                let altrules: Vec<SeqBuilder> = alt_elements.iter()
-                    .map(|n| ixml_rulebuilder_new(*n, arena, &ctx))
-                    .collect();
+                let altrules = alt_elements.iter()
+                    .map(|n| ixml_rulebuilder_new(*n, arena, ctx))
+                    .collect::<Result<Vec<SeqBuilder>, ParseError>>()?;
                 seq = seq.alts(altrules);
             }
         }
         "literal" => {
-            seq = seq.ch(attrs["string"].chars().next().expect("no empty string literals"));
+            let tmark = tmark_from_attrs(&attrs);
+            seq = match attrs.get("hex") {
+                Some(code) => seq.mark_ch(encoded_char(code)?, tmark),
+                None => {
+                    let string = attr("string")?;
+                    if string.is_empty() {
+                        return Err(ParseError::static_err("empty string literals are not allowed"));
+                    }
+                    seq.mark_str(string, tmark)
+                }
+            };
         }
-        "inclusion" => {
-            // character classes
-            unimplemented!("need to handle character <inclusion>");
+        "inclusion" | "exclusion" => {
+            // character classes: every <member> contributes to one Lit
+            let mut lit = Lit::union();
+            for member in Parser::get_child_elements_named(arena, nid, "member") {
+                let member_attrs = Parser::get_attributes(arena, member);
+                lit = if let Some(string) = member_attrs.get("string") {
+                    lit.ch_in(string)
+                } else if let Some(code) = member_attrs.get("hex") {
+                    lit.ch(encoded_char(code)?)
+                } else if let (Some(from), Some(to)) = (member_attrs.get("from"), member_attrs.get("to")) {
+                    let (bot, top) = (range_char(from)?, range_char(to)?);
+                    if bot > top {
+                        return Err(ParseError::static_err(&format!("range {from}-{to} is reversed, so matches nothing")));
+                    }
+                    lit.ch_range(bot, top)
+                } else if let Some(code) = member_attrs.get("code") {
+                    UnicodeRange::new(code)?;
+                    lit.ch_unicode(code)
+                } else {
+                    return Err(ParseError::static_err("a <member> needs a string, hex, from and to, or code attribute"));
+                };
+            }
+            if name == "exclusion" {
+                lit = lit.exclude();
+            }
+            seq = seq.mark_lit(lit, tmark_from_attrs(&attrs));
         }
-        "exclusion" => {
-            // character classes
-            unimplemented!("need to handle character <exclusion>");
+        "insertion" => {
+            seq = match attrs.get("hex") {
+                Some(code) => seq.insert(&encoded_char(code)?.to_string()),
+                None => seq.insert(attr("string")?),
+            };
         }
         "nonterminal" => {
-            seq = seq.nt(&attrs["name"]);
+            seq = seq.mark_nt(attr("name")?, mark_from_attrs(&attrs));
         }
         "option" => {
-            let subexpr = ixml_rulebuilder_new(nid, arena, &ctx);
+            let subexpr = ixml_rulebuilder_new(nid, arena, ctx)?;
             seq = seq.opt(subexpr);
         }
         "repeat0" => {
             let children = Parser::get_child_elements(arena, nid);
             // assume first child is what-to-repeat (from `factor`)
-            let expr = children.get(0).expect("Should always be at least one child here");
+            let expr = children.first()
+                .ok_or_else(|| ParseError::static_err("<repeat0> has nothing to repeat"))?;
             let repeat_this_node = expr.1;
             let mut repeat_this = ctx.seq();
-            repeat_this = ixml_ruleappend(repeat_this, &expr.0, repeat_this_node, arena, ctx);
+            repeat_this = ixml_ruleappend(repeat_this, &expr.0, repeat_this_node, arena, ctx)?;
 
             // if a <sep> child exists, this is a ** rule, otherwise just *
             if let Some(sep) = children.get(1) {
-                assert_eq!(sep.0, "sep");
-                let separated_by = ixml_rulebuilder_new(sep.1, arena, &ctx);
+                if sep.0 != "sep" {
+                    return Err(ParseError::static_err(&format!("expected <sep> in <repeat0>, found <{}>", sep.0)));
+                }
+                let separated_by = ixml_rulebuilder_new(sep.1, arena, ctx)?;
                 seq = seq.repeat0_sep(repeat_this, separated_by)
             } else {
                 seq = seq.repeat0(repeat_this);
@@ -449,23 +628,26 @@ pub fn ixml_ruleappend<'a>(mut seq: SeqBuilder<'a>, name: &str, nid: NodeId, are
         "repeat1" => {
             let children = Parser::get_child_elements(arena, nid);
             // assume first child is what-to-repeat (from `factor`)
-            let expr = children.get(0).expect("Should always be at least one child here");
+            let expr = children.first()
+                .ok_or_else(|| ParseError::static_err("<repeat1> has nothing to repeat"))?;
             let repeat_this_node = expr.1;
             let mut repeat_this = ctx.seq();
-            repeat_this = ixml_ruleappend(repeat_this, &expr.0, repeat_this_node, arena, ctx);
+            repeat_this = ixml_ruleappend(repeat_this, &expr.0, repeat_this_node, arena, ctx)?;
 
             // if a <sep> child exists, this is a ++ rule, otherwise just +
             if let Some(sep) = children.get(1) {
-                assert_eq!(sep.0, "sep");
-                let separated_by = ixml_rulebuilder_new(sep.1, arena, &ctx);
+                if sep.0 != "sep" {
+                    return Err(ParseError::static_err(&format!("expected <sep> in <repeat1>, found <{}>", sep.0)));
+                }
+                let separated_by = ixml_rulebuilder_new(sep.1, arena, ctx)?;
                 seq = seq.repeat1_sep(repeat_this, separated_by)
             } else {
                 seq = seq.repeat1(repeat_this);
             }
         }
-        _ => unimplemented!("unknown element {name} child of <alt>"),
+        _ => return Err(ParseError::static_err(&format!("unknown element <{name}> child of <alt>"))),
     }
-    seq
+    Ok(seq)
 }
 
 
@@ -482,7 +664,7 @@ fn parse_ixml() -> Result<(), ParseError> {
     assert_eq!(result, expected);
 
     println!("=============");
-    let gen_grammar = ixml_tree_to_grammar(&arena);
+    let gen_grammar = ixml_tree_to_grammar(&arena)?;
     println!("{gen_grammar}");
     let mut gen_parser = Parser::new(gen_grammar);
     // now do a second pass, with the just-generated grammar
@@ -503,3 +685,360 @@ fn test_ixml_str_to_grammar() -> Result<(), ParseError> {
     assert_eq!(grammar.as_ref().unwrap().get_root_definition_name(), Some(String::from("doc")));
     Ok(())
 }
+
+#[test]
+fn test_nonterminal_separator() -> Result<(), ParseError> {
+    let ixml: &str = r#"doc = word++space. word = "a"+. space = " "."#;
+    let grammar = ixml_str_to_grammar(ixml)?;
+    let mut parser = Parser::new(grammar);
+    let arena = parser.parse("a aa a")?;
+    let result = Parser::tree_to_testfmt(&arena);
+    let expected = "<doc><word>a</word><space> </space><word>aa</word><space> </space><word>a</word></doc>";
+    assert_eq!(result, expected);
+
+    // same again, with **
+    let ixml: &str = r#"doc = word**space. word = "a"+. space = " "."#;
+    let grammar = ixml_str_to_grammar(ixml)?;
+    let mut parser = Parser::new(grammar.clone());
+    let arena = parser.parse("a aa")?;
+    assert_eq!(Parser::tree_to_testfmt(&arena), "<doc><word>a</word><space> </space><word>aa</word></doc>");
+    let mut parser = Parser::new(grammar);
+    let arena = parser.parse("")?;
+    assert_eq!(Parser::tree_to_testfmt(&arena), "<doc></doc>");
+    Ok(())
+}
+
+#[test]
+fn test_expand_sugar() -> Result<(), ParseError> {
+    let ixml: &str = r#"doc = ("a" | "b")*."#;
+    let grammar = ixml_str_to_grammar(ixml)?;
+    let synthesized = grammar.expand_sugar();
+    assert_eq!(synthesized, vec!["--doc.f-opt1", "--doc.f-option3", "--doc.f-star2"]);
+    assert_eq!(grammar.get_rule_count(), 4);
+    for name in &synthesized {
+        assert_eq!(grammar.get_definition_mark(name), Mark::Mute);
+    }
+    Ok(())
+}
+
+#[test]
+fn test_malformed_grammars_dont_panic() {
+    // a few inputs the fuzz target (fuzz/fuzz_targets/ixml_grammar.rs) would quickly come up with
+    for ixml in ["", "doc", "doc = (.", "= \"a\".", "doc = \"a\"", "\u{0}"] {
+        assert!(ixml_str_to_grammar(ixml).is_err(), "expected an error for >>>{ixml}<<<");
+    }
+}
+
+#[test]
+fn test_empty_literal_error() {
+    // the bootstrap grammar itself rejects "" (dchar+ needs at least one character),
+    // but a hand-assembled tree can still carry an empty string; see test_malformed_trees
+    assert!(matches!(ixml_str_to_grammar(r#"doc = ""."#), Err(ParseError::StaticError(_))));
+}
+
+#[test]
+fn test_malformed_trees() {
+    // build a minimal <ixml><rule name="doc"><alt>...</alt></rule></ixml> tree, letting the caller fill in the <alt>
+    fn tree_with_alt(fill: impl Fn(NodeId, &mut Arena<Content>)) -> Arena<Content> {
+        let mut arena = Arena::new();
+        let root = arena.new_node(Content::Root);
+        let ixml = arena.new_node(Content::Element("ixml".to_string()));
+        root.append(ixml, &mut arena);
+        let rule = arena.new_node(Content::Element("rule".to_string()));
+        ixml.append(rule, &mut arena);
+        let name = arena.new_node(Content::Attribute("name".to_string(), "doc".to_string()));
+        rule.append(name, &mut arena);
+        let alt = arena.new_node(Content::Element("alt".to_string()));
+        rule.append(alt, &mut arena);
+        fill(alt, &mut arena);
+        arena
+    }
+    fn elem(parent: NodeId, name: &str, arena: &mut Arena<Content>) -> NodeId {
+        let node = arena.new_node(Content::Element(name.to_string()));
+        parent.append(node, arena);
+        node
+    }
+
+    // empty arena
+    assert!(ixml_tree_to_grammar(&Arena::new()).is_err());
+
+    // an element the grammar builder doesn't know about
+    let arena = tree_with_alt(|alt, arena| { elem(alt, "bogus", arena); });
+    assert!(matches!(ixml_tree_to_grammar(&arena), Err(ParseError::StaticError(msg)) if msg.contains("bogus")));
+
+    // <literal> with an empty string
+    let arena = tree_with_alt(|alt, arena| {
+        let lit = elem(alt, "literal", arena);
+        let s = arena.new_node(Content::Attribute("string".to_string(), "".to_string()));
+        lit.append(s, arena);
+    });
+    assert!(matches!(ixml_tree_to_grammar(&arena), Err(ParseError::StaticError(msg)) if msg.contains("empty string")));
+
+    // <nonterminal> without a name
+    let arena = tree_with_alt(|alt, arena| { elem(alt, "nonterminal", arena); });
+    assert!(matches!(ixml_tree_to_grammar(&arena), Err(ParseError::StaticError(msg)) if msg.contains("name")));
+
+    // <repeat1> with nothing to repeat
+    let arena = tree_with_alt(|alt, arena| { elem(alt, "repeat1", arena); });
+    assert!(ixml_tree_to_grammar(&arena).is_err());
+
+    // <repeat1> whose second child isn't a <sep>
+    let arena = tree_with_alt(|alt, arena| {
+        let rep = elem(alt, "repeat1", arena);
+        let lit = elem(rep, "literal", arena);
+        let s = arena.new_node(Content::Attribute("string".to_string(), "a".to_string()));
+        lit.append(s, arena);
+        elem(rep, "option", arena);
+    });
+    assert!(matches!(ixml_tree_to_grammar(&arena), Err(ParseError::StaticError(msg)) if msg.contains("<sep>")));
+
+    // well-formed trees still work, including a single-alternative group
+    let g = ixml_str_to_grammar(r#"doc = ("a"), "b"."#).expect("grammar should build");
+    let mut parser = Parser::new(g);
+    assert!(parser.parse("ab").is_ok());
+}
+
+#[test]
+fn test_pragmas() {
+    let plain = ixml_str_to_grammar(r#"doc = "a", b. b = "b"."#).unwrap();
+    let with_pragmas = ixml_str_to_grammar(r#"doc = "a", b. [+pragma some data] [other] b = "b"."#).unwrap();
+
+    let pragmas = with_pragmas.get_definition("b").pragmas();
+    assert_eq!(pragmas, &[("pragma".to_string(), "some data".to_string()), ("other".to_string(), "".to_string())]);
+    assert!(with_pragmas.get_definition("doc").pragmas().is_empty());
+
+    // pragma names follow the same rules as rule names
+    let g = ixml_str_to_grammar(r#"[xmlns http://example.com/] [Foo] [_x-1.2] doc = "a"."#).unwrap();
+    let names = g.get_definition("doc").pragmas().iter().map(|(name, _)| name.as_str()).collect::<Vec<_>>();
+    assert_eq!(names, ["xmlns", "Foo", "_x-1.2"]);
+    assert!(ixml_str_to_grammar(r#"[1st] doc = "a"."#).is_err());
+
+    // pragmas are metadata only
+    assert_eq!(plain.to_string(), with_pragmas.to_string());
+    let mut parser = Parser::new(with_pragmas);
+    assert_eq!(Parser::tree_to_testfmt(&parser.parse("ab").unwrap()), "<doc>a<b>b</b></doc>");
+}
+
+#[test]
+fn test_unicode_whitespace() {
+    // U+2003 EM SPACE and U+00A0 NO-BREAK SPACE are both in Zs
+    let g = ixml_str_to_grammar("doc = a,\u{a0}b.\u{2003}a = \"a\".\u{2003}b = \"b\".").unwrap();
+    assert_eq!(g.get_rule_count(), 3);
+    let mut parser = Parser::new(g);
+    assert_eq!(Parser::tree_to_testfmt(&parser.parse("ab").unwrap()), "<doc><a>a</a><b>b</b></doc>");
+}
+
+#[test]
+fn test_encoded_literal() {
+    let g = ixml_str_to_grammar("doc = #41, #d65.").unwrap();
+    let mut parser = Parser::new(g);
+    #[cfg(feature = "extensions")]
+    assert_eq!(Parser::tree_to_testfmt(&parser.parse("AA").unwrap()), "<doc>AA</doc>");
+    // strict ixml: d65 is just more hex
+    #[cfg(not(feature = "extensions"))]
+    assert_eq!(Parser::tree_to_testfmt(&parser.parse("A\u{d65}").unwrap()), "<doc>A\u{d65}</doc>");
+
+    let err = ixml_str_to_grammar("doc = #110000.").unwrap_err().to_string();
+    assert!(err.contains("beyond the last Unicode character"), "{err}");
+}
+
+#[test]
+fn test_encoded_astral_and_surrogate() {
+    let g = ixml_str_to_grammar("doc = #1F600.").unwrap();
+    let mut parser = Parser::new(g);
+    assert_eq!(Parser::tree_to_testfmt(&parser.parse("\u{1F600}").unwrap()), "<doc>\u{1F600}</doc>");
+
+    for surrogate in ["#D800", "#dfff"] {
+        let err = ixml_str_to_grammar(&format!("doc = {surrogate}.")).unwrap_err();
+        assert!(matches!(err, ParseError::StaticError(_)), "{err}");
+        assert!(err.to_string().contains("surrogate"), "{err}");
+    }
+}
+
+#[test]
+fn test_rule_separators() {
+    let expected = ixml_str_to_grammar(r#"doc = "a", b. b = "b"."#).unwrap().to_string();
+    for ixml in [
+        r#"doc: "a", b. b: "b"."#,
+        r#"doc = "a", b. b: "b"."#,
+        r#"doc="a",b. b:"b"."#,
+        r#"doc :"a", b. b= "b"."#,
+        "doc\n  :\n  \"a\", b.\nb\n  =\n  \"b\".",
+        "doc\t=\t\"a\", b.\tb\t:\t\"b\".",
+    ] {
+        let g = ixml_str_to_grammar(ixml).unwrap_or_else(|e| panic!("{ixml}: {e}"));
+        assert_eq!(g.to_string(), expected, "{ixml}");
+    }
+}
+
+#[test]
+fn test_self_hosting() {
+    // the bootstrap grammar reads the ixml grammar...
+    let source = Grammar::ixml_source_string();
+    let bootstrap_tree = Parser::new(Grammar::ixml()).parse(source).unwrap();
+    let hosted = ixml_tree_to_grammar(&bootstrap_tree).unwrap();
+
+    // ...and what it builds reads the same source the same way, and rebuilds itself
+    let hosted_tree = Parser::new(hosted.clone()).parse(source).unwrap();
+    // except for comments: the spec's grammar makes them <comment> elements, while the bootstrap mutes them
+    let mut uncommented_tree = hosted_tree.clone();
+    let comments = uncommented_tree.iter()
+        .filter(|node| !node.is_removed() && node.get().get_name().as_deref() == Some("comment"))
+        .filter_map(|node| uncommented_tree.get_node_id(node))
+        .collect::<Vec<_>>();
+    assert!(!comments.is_empty());
+    for comment in comments {
+        comment.remove_subtree(&mut uncommented_tree);
+    }
+    assert!(Parser::trees_equal(&bootstrap_tree, &uncommented_tree));
+    let rehosted = ixml_tree_to_grammar(&hosted_tree).unwrap();
+    assert!(hosted.same_language_shape(&rehosted));
+    assert_eq!(hosted.to_string(), rehosted.to_string());
+}
+
+#[test]
+fn test_comments() {
+    let plain = ixml_str_to_grammar(r#"doc = "a", b. b = "b"."#).unwrap();
+    for ixml in [
+        r#"{leading} doc = "a", b. b = "b"."#,
+        r#"doc = "a", b. b = "b". {trailing}"#,
+        r#"doc = "a", {one {two {three}} levels} b. b = "b"."#,
+        r#"doc = "a", b.{no space needed}b = "b"."#,
+    ] {
+        let g = ixml_str_to_grammar(ixml).unwrap_or_else(|e| panic!("{ixml}: {e}"));
+        assert_eq!(g.to_string(), plain.to_string(), "{ixml}");
+    }
+    assert!(ixml_str_to_grammar(r#"doc = "a". {unclosed"#).is_err());
+}
+
+#[test]
+fn test_kept_comments() {
+    let source = r#"{leading} doc = "a", {one {two} -- three} b. b = "b"."#;
+    let tree = Parser::new(Grammar::ixml_with_comments()).parse(source).unwrap();
+    let comments = tree.iter()
+        .filter_map(|node| match node.get() {
+            Content::Comment(txt) => Some(txt.as_str()),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(comments, ["leading", "one {two} -- three"]);
+    assert!(Parser::tree_to_xml(&tree, false).contains("<!--one {two} - - three-->"));
+
+    // the comments don't change what grammar gets built
+    let plain = ixml_str_to_grammar(r#"doc = "a", b. b = "b"."#).unwrap();
+    assert_eq!(ixml_tree_to_grammar(&tree).unwrap().to_string(), plain.to_string());
+}
+
+#[test]
+fn test_multichar_string() {
+    let g = ixml_str_to_grammar(r#"doc = "AB", -"CD", "E"."#).unwrap();
+    let mut parser = Parser::new(g);
+    assert_eq!(Parser::tree_to_testfmt(&parser.parse("ABCDE").unwrap()), "<doc>ABE</doc>");
+    let mut parser = Parser::new(ixml_str_to_grammar(r#"doc = "AB"."#).unwrap());
+    assert!(matches!(parser.parse("A"), Err(ParseError::DynamicError(_))));
+}
+
+#[test]
+fn test_single_quoted_strings() {
+    let single = ixml_str_to_grammar("doc = 'a', 'b'.").unwrap();
+    let double = ixml_str_to_grammar(r#"doc = "a", "b"."#).unwrap();
+    assert_eq!(single.defn_order, double.defn_order);
+    assert_eq!(single.to_string(), double.to_string());
+
+    // also in character sets, and with the other kind of quote inside
+    let g = ixml_str_to_grammar(r#"doc = ['a'-'c'; "'"]+."#).unwrap();
+    let mut parser = Parser::new(g);
+    assert_eq!(Parser::tree_to_testfmt(&parser.parse("c'a").unwrap()), "<doc>c'a</doc>");
+}
+
+#[test]
+fn test_doubled_quotes() {
+    // a doubled quote inside a string stands for one quote character
+    for (ixml, input, output) in [
+        (r#"doc = "a""b"."#, r#"a"b"#, r#"<doc>a"b</doc>"#),
+        ("doc = 'it''s'.", "it's", "<doc>it's</doc>"),
+        (r#"doc = """"."#, r#"""#, r#"<doc>"</doc>"#),
+        (r#"doc = [''''; '"'; "<&"]+."#, r#"'"<&"#, r#"<doc>'"&lt;&amp;</doc>"#),
+    ] {
+        let g = ixml_str_to_grammar(ixml).unwrap_or_else(|e| panic!("{ixml}: {e}"));
+        let mut parser = Parser::new(g);
+        let arena = parser.parse(input).unwrap_or_else(|e| panic!("{ixml}: {e}"));
+        assert_eq!(Parser::tree_to_testfmt(&arena), output, "{ixml}");
+    }
+
+    // the attribute holds the collapsed text, and is only escaped on output
+    let tree = Parser::new(Grammar::ixml()).parse(r#"doc = "say ""hi""."."#).unwrap();
+    let string = tree.iter()
+        .filter(|node| !node.is_removed())
+        .find_map(|node| match node.get() {
+            Content::Attribute(name, value) if name == "string" => Some(value.as_str()),
+            _ => None,
+        });
+    assert_eq!(string, Some(r#"say "hi"."#));
+    assert!(Parser::tree_to_testfmt(&tree).contains(r#"string="say &quot;hi&quot;.""#));
+    let g = ixml_str_to_grammar(r#"doc = "say ""hi""."."#).unwrap();
+    assert_eq!(g.to_string(), "doc = \"say \"\"hi\"\".\".\n");
+}
+
+#[test]
+fn test_character_classes() {
+    let g = ixml_str_to_grammar(r#"doc = ["0"-"9"; "a"; #20]+."#).unwrap();
+    let mut parser = Parser::new(g);
+    assert_eq!(Parser::tree_to_testfmt(&parser.parse("4 a2").unwrap()), "<doc>4 a2</doc>");
+    let mut parser = Parser::new(ixml_str_to_grammar(r#"doc = ["0"-"9"; "a"; #20]+."#).unwrap());
+    assert!(matches!(parser.parse("b"), Err(ParseError::DynamicError(_))));
+
+    let g = ixml_str_to_grammar(r#"doc = ~["aeiou"]."#).unwrap();
+    let mut parser = Parser::new(g.clone());
+    assert_eq!(Parser::tree_to_testfmt(&parser.parse("x").unwrap()), "<doc>x</doc>");
+    let mut parser = Parser::new(g);
+    assert!(matches!(parser.parse("e"), Err(ParseError::DynamicError(_))));
+
+    let g = ixml_str_to_grammar(r#"doc = [L; Nd], -[#30-#39], ^["xy"]."#).unwrap();
+    let mut parser = Parser::new(g);
+    assert_eq!(Parser::tree_to_testfmt(&parser.parse("\u{e9}5y").unwrap()), "<doc>\u{e9}y</doc>");
+
+    assert!(ixml_str_to_grammar(r#"doc = ["9"-"0"]."#).is_err());
+    assert!(ixml_str_to_grammar(r#"doc = [Xx]."#).is_err());
+}
+
+#[test]
+fn test_encoded_whitespace_and_max() {
+    // an encoded literal shows up as a <literal> with a hex attribute
+    let g = ixml_str_to_grammar("doc = #a, -#20, #10FFFF.").unwrap();
+    let mut parser = Parser::new(g);
+    let arena = parser.parse("\n \u{10FFFF}").unwrap();
+    assert_eq!(Parser::tree_to_testfmt(&arena), "<doc>\n\u{10FFFF}</doc>");
+}
+
+#[test]
+fn test_insertion() {
+    let g = ixml_str_to_grammar(r#"doc = "a", +"X", "b"."#).unwrap();
+    let mut parser = Parser::new(g);
+    assert_eq!(Parser::tree_to_testfmt(&parser.parse("ab").unwrap()), "<doc>aXb</doc>");
+}
+
+#[test]
+fn test_attribute_insertion() {
+    // inside an attribute, inserted text becomes part of the value, and stays out of the element's own text
+    let g = ixml_str_to_grammar(r#"doc = id, +#a. @id = +"n", ["0"-"9"]+."#).unwrap();
+    let mut parser = Parser::new(g);
+    assert_eq!(Parser::tree_to_testfmt(&parser.parse("42").unwrap()), "<doc id=\"n42\">\n</doc>");
+
+    // even from a hidden rule further down, or with no input matched at all
+    let g = ixml_str_to_grammar(r#"doc = id, "x", type. @id = -prefix, ["0"-"9"]+. -prefix = +"n-". @type = +"plain"."#).unwrap();
+    let mut parser = Parser::new(g);
+    assert_eq!(Parser::tree_to_testfmt(&parser.parse("42x").unwrap()), "<doc id=\"n-42\" type=\"plain\">x</doc>");
+}
+
+#[test]
+fn test_undefined_nonterminals() {
+    let err = ixml_str_to_grammar("doc = foo.").unwrap_err();
+    assert!(matches!(err, ParseError::StaticError(_)));
+    assert_eq!(err.code(), Some(crate::parser::ErrorCode::D01));
+    assert!(err.to_string().contains("undefined rules: foo (referenced from doc)"), "{err}");
+
+    // a typo'd rule name leaves the intended rule unused, and the reference to it dangling
+    let err = ixml_str_to_grammar(r#"doc = item+. itme = "x"."#).unwrap_err();
+    assert!(err.to_string().contains("item (referenced from doc)"), "{err}");
+}
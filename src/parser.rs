@@ -1,12 +1,15 @@
-use crate::grammar::{Grammar, Rule, Factor, TMark, Mark};
-use std::{collections::{VecDeque, HashSet, HashMap}, fmt};
+use crate::grammar::{Grammar, Rule, Factor, TMark, Mark, Lit};
+use std::{borrow::Cow, collections::{VecDeque, HashSet, HashMap}, fmt, ops::Range, rc::Rc};
 use multimap::MultiMap;
 use smol_str::SmolStr;
 use string_builder::Builder;
-use indextree::{Arena, NodeId};
+use indextree::{Arena, Children, NodeId};
+use indexmap::IndexMap;
 use log::{info, debug, trace};
 
 const DOTSEP: &str = "•";
+/// Unpacking keeps its own stack, so this only bounds how much work a pathologically nested parse can make
+const DEFAULT_MAX_DEPTH: usize = 5000;
 
 #[derive(Debug, Clone, Eq, PartialEq)]
 /// A sort of iterator for a Rule.
@@ -57,6 +60,7 @@ impl fmt::Display for DotNotation {
                 match i {
                     MatchRec::Term(ch, pos, tmark) => format!("{tmark}'{ch}'@{pos}"),
                     MatchRec::NonTerm(name, pos, mark) => format!("{mark}{name}@{pos}"),
+                    MatchRec::Insertion(text, pos) => format!("+'{text}'@{pos}"),
             })
             .collect::<Vec<_>>()
             .join(", ");
@@ -77,6 +81,8 @@ impl fmt::Display for DotNotation {
 enum MatchRec {
     Term(char, usize, TMark),
     NonTerm(SmolStr, usize, Mark),
+    /// text that goes into the output without consuming input, so its position is where it was inserted
+    Insertion(SmolStr, usize),
 }
 
 impl MatchRec {
@@ -84,6 +90,7 @@ impl MatchRec {
         match self {
             Self::Term(_, pos, _) => *pos,
             Self::NonTerm(_, pos, _) => *pos,
+            Self::Insertion(_, pos) => *pos,
         }
     }
 }
@@ -96,22 +103,49 @@ pub struct Task {
     origin: usize,            // starting position in the input
     pos: usize,               // current position in the input
     dot: DotNotation,         // progress
+    alt: usize,               // which alternative of the rule the dot is in
+    prev: Option<TraceId>,    // the task this one advanced from, if any
 }
 
 impl Task {
     pub fn mark(&self) -> Mark {
         self.mark.clone()
     }
+
+    fn key(&self) -> TaskKey {
+        TaskKey {
+            name: self.name.clone(),
+            mark: self.mark,
+            origin: self.origin,
+            pos: self.pos,
+            cursor: self.dot.matched_so_far.len(),
+            alt: self.alt,
+            prev: self.prev,
+        }
+    }
+}
+
+/// What makes a Task distinct, for deduplication. Rather than every match so far,
+/// it names the (already deduplicated) task this one advanced from, which stands for all of them
+#[derive(Clone, Debug, Eq, PartialEq, Hash)]
+struct TaskKey {
+    name: SmolStr,
+    mark: Mark,
+    origin: usize,
+    pos: usize,
+    cursor: usize,
+    alt: usize,
+    prev: Option<TraceId>,
 }
 
-/// This is currently ONLY used as a hash of Task, and can probably be optimized
+/// for debug logging
 impl fmt::Display for Task {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "( {}{} {}:{} {}) ", self.mark, self.name, self.origin, self.pos, self.dot)
     }
 }
 
-#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
 pub struct TraceId(usize);
 
 #[derive(Debug)]
@@ -137,7 +171,7 @@ pub struct TraceArena {
     continuations: MultiMap<SmolStr, TraceId>,
 
     /// a simple yes/no test if we've seen this exact Task before
-    hashes: HashSet<String>,
+    hashes: HashSet<TaskKey>,
 }
 
 impl TraceArena {
@@ -176,11 +210,11 @@ impl TraceArena {
         result
     }
 
-    /// originate a completely new task
+    /// originate a completely new task, for alternative `alt` of the rule
     /// Returns Some(TraceId) (unless this is a duplicate Task, in which case None is returned)
-    fn task(&mut self, name: &str, mark: Mark, origin: usize, pos: usize, dot: DotNotation) -> Option<TraceId> {
+    fn task(&mut self, name: &str, mark: Mark, origin: usize, pos: usize, dot: DotNotation, alt: usize) -> Option<TraceId> {
         let id = TraceId(self.arena.len());
-        let task = Task{ id, name: SmolStr::new(name), mark, origin, pos, dot };
+        let task = Task{ id, name: SmolStr::new(name), mark, origin, pos, dot, alt, prev: None };
         if self.have_we_seen(&task) {
             None
         } else {
@@ -193,9 +227,9 @@ impl TraceArena {
     /// ... = x { <-- processing this rule }
     /// x = ... { <-- so queue up this one next, at same pos, etc. }
     /// Returns Some(TraceId) (unless this is a duplicate Task, in which case None is returned)
-    fn task_downstream(&mut self, name: &str, mark: Mark, origin: usize, pos: usize, dot: DotNotation) -> Option<TraceId> {
+    fn task_downstream(&mut self, name: &str, mark: Mark, origin: usize, pos: usize, dot: DotNotation, alt: usize) -> Option<TraceId> {
         let id = TraceId(self.arena.len());
-        let task = Task{ id, name: SmolStr::new(name), mark, origin, pos, dot };
+        let task = Task{ id, name: SmolStr::new(name), mark, origin, pos, dot, alt, prev: None };
         if self.have_we_seen(&task) {
             None
         } else {
@@ -213,7 +247,8 @@ impl TraceArena {
         let new_dot = from_task.dot.advance_dot(rec);
         let id = TraceId(self.arena.len());
         // use from_task.mark? Or take from MatchRec?
-        let task = Task { id, name: from_task.name.clone(), mark: from_task.mark.clone(), origin: from_task.origin, pos: new_pos, dot: new_dot };
+        let task = Task { id, name: from_task.name.clone(), mark: from_task.mark.clone(), origin: from_task.origin, pos: new_pos, dot: new_dot,
+            alt: from_task.alt, prev: Some(from) };
         if self.have_we_seen(&task) {
             None
         } else {
@@ -225,15 +260,40 @@ impl TraceArena {
     /// returns true if this trace had been previously seen
     /// also performs necessary bookkeeping
     fn have_we_seen(&mut self, task: &Task) -> bool {
-        let hash = task.to_string();
-        if self.hashes.contains(&hash) {
-            debug!("...Skipping this task -- previously seen {} @ {}:{} {}", task.name, task.origin, task.pos, hash);
-            true
-        } else {
-            debug!("...caching task {}", hash);
-            self.hashes.insert(hash);
+        if self.hashes.insert(task.key()) {
+            debug!("...caching task {task}");
             false
+        } else {
+            debug!("...Skipping this task -- previously seen {} @ {}:{} {task}", task.name, task.origin, task.pos);
+            true
+        }
+    }
+
+    /// drop every task past `pos`, renumbering the survivors (and their continuations)
+    /// Returns a map of old TraceId -> new TraceId
+    fn retain_up_to(&mut self, pos: usize) -> HashMap<usize, TraceId> {
+        let old_arena = std::mem::take(&mut self.arena);
+        let mut remap: HashMap<usize, TraceId> = HashMap::new();
+        self.queue.clear();
+        self.hashes.clear();
+        for mut task in old_arena.into_iter().filter(|t| t.pos <= pos) {
+            let new_id = TraceId(self.arena.len());
+            remap.insert(task.id.0, new_id);
+            task.id = new_id;
+            // a task never ends before the one it advanced from, so that one has already been renumbered
+            task.prev = task.prev.map(|prev| remap[&prev.0]);
+            self.hashes.insert(task.key());
+            self.arena.push(task);
         }
+        let old_continuations = std::mem::take(&mut self.continuations);
+        for (name, tids) in old_continuations {
+            for tid in tids {
+                if let Some(new_id) = remap.get(&tid.0) {
+                    self.continuations.insert(name.clone(), *new_id);
+                }
+            }
+        }
+        remap
     }
 
     fn format_task(&self, id: TraceId) -> String {
@@ -243,21 +303,63 @@ impl TraceArena {
     }
 }
 
-struct InputIter {
-    tokens: Vec<char>,
+/// A unit of input. Parsing text works a `char` at a time, but with `Parser::parse_tokens`
+/// a separate lexer can supply its own tokens instead
+pub trait Token {
+    /// the character grammar terminals see for this token, e.g. a code standing for the kind of token
+    fn as_char(&self) -> char;
+
+    /// whether a grammar terminal matches this token. By default, whether it accepts `as_char`
+    fn matches(&self, lit: &Lit) -> bool {
+        lit.accept(self.as_char())
+    }
+
+    /// the text this token contributes to the parse tree. By default, `as_char`
+    fn text(&self) -> String {
+        self.as_char().to_string()
+    }
+}
+
+impl Token for char {
+    fn as_char(&self) -> char {
+        *self
+    }
+}
+
+struct InputIter<T: Token = char> {
+    tokens: Vec<T>,
     // actual position is tracked externally, in Tasks
 }
 
-impl InputIter {
-    fn new(input: &str) -> Self {
-        Self { tokens: input.chars().collect::<Vec<_>>() }
+impl<T: Token> InputIter<T> {
+    fn from_tokens(tokens: Vec<T>) -> Self {
+        Self { tokens }
     }
 
     pub fn at_eof(&self, pos: usize) -> bool {
          pos >= self.tokens.len()
     }
 
-    pub fn get_at(&mut self, pos: usize) -> char {
+    /// the token at a position, or None past the end
+    pub fn get(&self, pos: usize) -> Option<&T> {
+        self.tokens.get(pos)
+    }
+
+    /// 1-based (line, column) of a position, counting tokens. Lines end with a token that reads as '\n'
+    pub fn line_col(&self, pos: usize) -> (usize, usize) {
+        let before = &self.tokens[..pos.min(self.tokens.len())];
+        let line = before.iter().filter(|tok| tok.as_char() == '\n').count() + 1;
+        let col = before.iter().rev().take_while(|tok| tok.as_char() != '\n').count() + 1;
+        (line, col)
+    }
+}
+
+impl InputIter {
+    fn new(input: &str) -> Self {
+        Self { tokens: input.chars().collect::<Vec<_>>() }
+    }
+
+    pub fn get_at(&self, pos: usize) -> char {
         if self.at_eof(pos) {
             debug!("📄🚫");
             '\x1f' // EOF char
@@ -265,16 +367,17 @@ impl InputIter {
             self.tokens[pos]
         }
     }
-    // TODO: row/col machinery for input tokens
 }
 
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 /// in the intermediate parse indextree, tree nodes are provided thusly
 pub enum Content {
     Root,
     Element(String),            // name
     Attribute(String, String),  // name, value
-    Text(String)                // value
+    Text(String),               // value
+    Comment(String),            // value
 }
 
 impl Content {
@@ -295,6 +398,7 @@ impl Content {
         match self {
             Self::Attribute(_, value) => Some(value.clone()),
             Self::Text(value) => Some(value.clone()),
+            Self::Comment(value) => Some(value.clone()),
             _ => None
         }
     }
@@ -302,11 +406,76 @@ impl Content {
         match self {
             Self::Attribute(name, _) => *self = Self::Attribute(name.clone(), value),
             Self::Text(_) => *self = Self::Text(value),
+            Self::Comment(_) => *self = Self::Comment(value),
             _ => panic!("Setting value on content that cannot hold a value"),
         }
     }
 }
 
+/// An owned, nested copy of a parse tree, for handing to serde-based serializers. See `Parser::tree_to_serde`
+#[cfg(feature = "serde")]
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct ContentTree {
+    pub content: Content,
+    pub children: Vec<ContentTree>,
+}
+
+/// Depth-first, pre-order iterator over a parse tree, yielding (depth, &Content). See `Parser::walk`
+/// Attribute nodes are yielded, but not the leftover text nodes beneath them
+pub struct TreeWalk<'a> {
+    arena: &'a Arena<Content>,
+    stack: Vec<(usize, NodeId)>,
+}
+
+impl<'a> TreeWalk<'a> {
+    fn push_children(&mut self, depth: usize, nid: NodeId) {
+        // reversed, so the first child is next off the stack
+        let children = nid.children(self.arena).collect::<Vec<_>>();
+        for child in children.into_iter().rev() {
+            self.stack.push((depth, child));
+        }
+    }
+}
+
+impl<'a> Iterator for TreeWalk<'a> {
+    type Item = (usize, &'a Content);
+    fn next(&mut self) -> Option<Self::Item> {
+        let (depth, nid) = self.stack.pop()?;
+        let content = self.arena.get(nid).unwrap().get();
+        if !content.is_attr() {
+            self.push_children(depth + 1, nid);
+        }
+        Some((depth, content))
+    }
+}
+
+/// An element (or attribute, or hidden rule) being unpacked, some of whose children are still to come
+struct OpenNode<'a> {
+    task: &'a Task,
+    /// where its children go: its own node, or for a hidden rule, its parent's
+    node: NodeId,
+    is_new: bool,
+    text_run: Option<(String, usize)>, // (text, starting pos)
+    /// how much of `in_flight` to keep once it's done
+    in_flight_len: usize,
+}
+
+/// A task's remaining matches while unpacking, where the next one starts,
+/// how much of `in_flight` to keep once it's done, and which `OpenNode` (by depth) its children go into
+struct UnpackFrame<'a> {
+    matches: std::slice::Iter<'a, MatchRec>,
+    next_origin: usize,
+    keep: usize,
+    node: usize,
+}
+
+/// a child node, as far as `Parser::trees_equal` is concerned
+enum ComparableChild {
+    Text(String),
+    Comment(String),
+    Element(NodeId),
+}
+
 #[derive(Debug)]
 pub enum ParseError {
     StaticError(String),
@@ -324,6 +493,43 @@ impl ParseError {
     pub fn uncategorized_err(msg: &str) -> Self {
         Self::UncategorizedError(msg.to_string())
     }
+
+    /// The ixml error code this error was raised with, if any.
+    /// Codes lead the message, as in "D02: duplicate attribute..."
+    pub fn code(&self) -> Option<ErrorCode> {
+        let (Self::StaticError(msg) | Self::DynamicError(msg) | Self::UncategorizedError(msg)) = self;
+        msg.split_once(':').and_then(|(code, _)| code.parse().ok())
+    }
+}
+
+/// The ixml error codes that test suites assert on, via `<assert-dynamic-error code="..."/>`
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum ErrorCode {
+    /// a nonterminal refers to a rule that isn't defined
+    D01,
+    /// something is defined more than once, like an attribute on the same element
+    D02,
+    /// the input is ambiguous and the parser was asked to reject ambiguity
+    D03,
+}
+
+impl std::str::FromStr for ErrorCode {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "D01" => Ok(Self::D01),
+            "D02" => Ok(Self::D02),
+            "D03" => Ok(Self::D03),
+            _ => Err(ParseError::static_err(&format!("unknown error code {s}"))),
+        }
+    }
+}
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        write!(f, "{self:?}")
+    }
 }
 
 impl fmt::Display for ParseError {
@@ -336,50 +542,473 @@ impl fmt::Display for ParseError {
     }
 }
 
+/// What to do when a single element ends up with more than one attribute of the same name.
+/// XML forbids duplicate attributes, so ixml treats this as a dynamic error by default
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum DuplicateAttrPolicy {
+    /// fail the parse with a dynamic error (D02)
+    Error,
+    /// keep only the first attribute of a given name, in document order
+    KeepFirst,
+    /// keep only the last attribute of a given name, in document order
+    KeepLast,
+}
+
+/// For ambiguous input, which derivation to build the tree from.
+/// Candidates are compared by where each of their parts ends, from left to right
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum RepetitionPolicy {
+    /// earlier parts (e.g. a repetition) take as much of the input as they can
+    Greedy,
+    /// earlier parts take as little of the input as they can
+    Lazy,
+}
+
+/// Where a `ChartEntry` is in matching its alternative
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum ChartState {
+    /// expected here, but nothing of it matched yet
+    Predicted,
+    /// partway through: some of the alternative has matched, up to this position
+    Scanned,
+    /// the whole alternative matched, ending at this position
+    Completed,
+}
+
+/// One entry (an Earley item) in the chart of the last parse, as returned by `Parser::chart_at`
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct ChartEntry {
+    /// the rule, which may be one synthesized from a repetition or group, like `--doc.f-star2`
+    pub rule: SmolStr,
+    /// where in the input the rule started matching
+    pub origin: usize,
+    /// how many factors of the alternative have matched so far
+    pub dot: usize,
+    pub state: ChartState,
+    /// the factor the entry is waiting on, in ixml syntax, unless it's completed
+    pub next: Option<String>,
+}
+
+impl fmt::Display for ChartEntry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} from {}, dot {}: {:?}", self.rule, self.origin, self.dot, self.state)?;
+        match &self.next {
+            Some(next) => write!(f, ", expecting {next}"),
+            None => Ok(()),
+        }
+    }
+}
+
+/// A user-supplied transformation applied to the input before parsing (e.g. Unicode normalization)
+pub struct Preprocessor(Box<dyn Fn(&str) -> String>);
+
+impl fmt::Debug for Preprocessor {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Preprocessor")
+    }
+}
+
+/// A user-supplied transformation from an element's text content to the node that replaces it (see `Parser::map_element`)
+pub struct ElementMapper(Box<dyn Fn(&str) -> Content>);
+
+impl fmt::Debug for ElementMapper {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "ElementMapper")
+    }
+}
+
 #[derive(Debug)]
 pub struct Parser {
     grammar: Grammar,
+    /// rule name -> for each alternative, the index of the first alternative identical to it (usually its own).
+    /// Worked out once, up front, so the predictor doesn't compare alternatives every time
+    alt_indexes: Rc<HashMap<SmolStr, Vec<usize>>>,
     /// the permanent owner of all tasks, referenced by TraceId
     traces: TraceArena,
     completed_trace: Vec<TraceId>,
+    /// the same completed tasks, by (rule name, origin, end), in the order they completed
+    completed_index: MultiMap<(SmolStr, usize, usize), TraceId>,
+    /// the same again, by (rule name, origin) only, for the predictor
+    completed_from: MultiMap<(SmolStr, usize), TraceId>,
     farthest_pos: usize,  // hint for later reading the trace
+    dup_attr_policy: DuplicateAttrPolicy,
+    preprocessor: Option<Preprocessor>,
+    /// guard against stack overflow when unpacking very tall parse trees
+    max_depth: usize,
+    /// reject inputs longer than this many characters, if set
+    max_input_len: Option<usize>,
+    /// rule name -> output element name
+    element_renames: HashMap<SmolStr, SmolStr>,
+    /// rule name -> transformation of that element's content
+    element_mappers: HashMap<SmolStr, ElementMapper>,
+    repetition_policy: RepetitionPolicy,
+    /// the rule the last parse started from, if not the grammar's root (see `parse_from`)
+    start_rule: Option<SmolStr>,
+    /// apply XML attribute-value normalization (collapse whitespace runs, trim ends)
+    normalize_attr_whitespace: bool,
+    reject_ambiguous: bool,
+    /// text of each token, when parsing tokens rather than chars (see `parse_tokens`)
+    token_texts: Vec<String>,
+    /// (char) input positions each node of the last returned tree covered, including muted parts
+    node_spans: HashMap<NodeId, Range<usize>>,
 }
 
 /// Earley parser
 impl Parser {
 
+    /// A parser for `grammar`, with the default settings: duplicate attributes are an error,
+    /// ambiguous repetitions split greedily (see `set_repetition_policy`), and input is parsed as-is
     pub fn new(grammar: Grammar) -> Self {
+        let alt_indexes = Rc::new(Self::alt_indexes(&grammar));
         Self {
             grammar,
+            alt_indexes,
             traces: TraceArena::new(),
             completed_trace: Vec::new(),
+            completed_index: MultiMap::new(),
+            completed_from: MultiMap::new(),
             farthest_pos: 0,
+            dup_attr_policy: DuplicateAttrPolicy::Error,
+            preprocessor: None,
+            max_depth: DEFAULT_MAX_DEPTH,
+            max_input_len: None,
+            element_renames: HashMap::new(),
+            element_mappers: HashMap::new(),
+            start_rule: None,
+            repetition_policy: RepetitionPolicy::Greedy,
+            normalize_attr_whitespace: false,
+            reject_ambiguous: false,
+            token_texts: Vec::new(),
+            node_spans: HashMap::new(),
+        }
+    }
+
+    /// Forget everything from the last parse (the chart, completed tasks, and position hints), keeping the grammar and settings.
+    /// Every parse starts with this, so one parser can work through many documents without re-cloning the grammar
+    pub fn reset(&mut self) {
+        self.traces = TraceArena::new();
+        self.completed_trace.clear();
+        self.completed_index.clear();
+        self.completed_from.clear();
+        self.farthest_pos = 0;
+        self.start_rule = None;
+        self.token_texts.clear();
+        self.node_spans.clear();
+    }
+
+    /// The grammar this parser was built from, e.g. for tooling that wants to inspect or serialize it
+    pub fn grammar(&self) -> &Grammar {
+        &self.grammar
+    }
+
+    /// Emit elements under a different name than their rule, e.g. rule `para` as `<p>`
+    /// Only affects elements, not attributes
+    pub fn set_element_renames(&mut self, renames: HashMap<SmolStr, SmolStr>) {
+        self.element_renames = renames;
+    }
+
+    /// Post-process every element from rule `rule_name` as the tree gets built: `mapper` gets the element's
+    /// text content, and the node it returns replaces the element's children (attributes are kept).
+    /// For example, to reformat or validate a `number`
+    pub fn map_element(&mut self, rule_name: &str, mapper: Box<dyn Fn(&str) -> Content>) {
+        self.element_mappers.insert(SmolStr::new(rule_name), ElementMapper(mapper));
+    }
+
+    /// Any parse nested deeper than this
+    /// (counting muted and synthesized rules, except where they're the last part of a rule, as in a repetition)
+    /// fails with a dynamic error when unpacking the parse tree. The default is 5000
+    pub fn set_max_depth(&mut self, max_depth: usize) {
+        self.max_depth = max_depth;
+    }
+
+    /// Refuse, with a static error, to parse any input longer than `max_input_len` characters.
+    /// The check happens before any other work. No limit by default
+    pub fn set_max_input_len(&mut self, max_input_len: usize) {
+        self.max_input_len = Some(max_input_len);
+    }
+
+    fn check_input_len(&self, input: &str) -> Result<(), ParseError> {
+        match self.max_input_len {
+            // the byte length is an upper bound on the char count, so most inputs skip counting
+            Some(max) if input.len() > max && input.chars().count() > max => Err(ParseError::static_err("input too long")),
+            _ => Ok(()),
         }
     }
 
-    /// Successful return value is an indextree over Content. Consider this temporary
+    /// Normalize input before parsing, for example NFC-normalizing so that combining characters
+    /// match [Mn]-based rules predictably. By default, input is parsed as-is
+    pub fn set_preprocessor(&mut self, preprocessor: Box<dyn Fn(&str) -> String>) {
+        self.preprocessor = Some(Preprocessor(preprocessor));
+    }
+
+    /// Collapse runs of XML whitespace (space, tab, CR, LF) in attribute values to a single space and trim the ends,
+    /// matching what an XML processor does for non-CDATA attributes. Off by default
+    pub fn set_attr_whitespace_normalization(&mut self, normalize: bool) {
+        self.normalize_attr_whitespace = normalize;
+    }
+
+    /// Choose which parse tree to build when the input can be split between parts of a rule in more than one way,
+    /// as in `x*, y*` where both accept the same characters. Doesn't change what input is accepted.
+    /// Default is `RepetitionPolicy::Greedy`, so a repetition takes as much as it can, as in most regex engines
+    pub fn set_repetition_policy(&mut self, policy: RepetitionPolicy) {
+        self.repetition_policy = policy;
+    }
+
+    /// Fail with a dynamic error if any part of the parse tree could have been built in more than one way,
+    /// instead of picking one derivation. Off by default
+    pub fn set_reject_ambiguous(&mut self, reject: bool) {
+        self.reject_ambiguous = reject;
+    }
+
+    /// Choose how duplicate attributes on the same element get handled. Default is `DuplicateAttrPolicy::Error`
+    pub fn set_duplicate_attr_policy(&mut self, policy: DuplicateAttrPolicy) {
+        self.dup_attr_policy = policy;
+    }
+
+    fn preprocess<'i>(&self, input: &'i str) -> Cow<'i, str> {
+        match &self.preprocessor {
+            Some(Preprocessor(f)) => Cow::Owned(f(input)),
+            None => Cow::Borrowed(input),
+        }
+    }
+
+    /// Successful return value is an indextree over Content. Consider this temporary.
+    /// Input the grammar doesn't match, all the way to the end, is a dynamic error saying where parsing got stuck
     pub fn parse(&mut self, input: &str) -> Result<Arena<Content>, ParseError> {
-        let mut input = InputIter::new(input);
+        let top_name = self.grammar.get_root_definition_name()
+            .ok_or(ParseError::static_err("No top grammar rule name"))?;
+        self.parse_from(input, &top_name)
+    }
 
-        // help avoid borrow-contention on *self
-        let g = self.grammar.clone();
-    
+    /// Like `parse`, but start from the rule named `start` instead of the grammar's root, so the whole input has to match that one rule.
+    /// The resulting tree is rooted at that rule's element; an `@`-marked rule becomes an element here, having nothing to be an attribute of.
+    /// Handy for checking a sub-component against a bigger grammar
+    pub fn parse_from(&mut self, input: &str, start: &str) -> Result<Arena<Content>, ParseError> {
+        if !self.grammar.has_definition(start) {
+            return Err(ParseError::static_err(&format!("No grammar rule named {start}")));
+        }
+        self.parse_rule(input, start)
+    }
+
+    /// Parse, then pull out values with a tiny path language: element names separated by `/`, starting from the document element,
+    /// optionally ending in `@name` to pick an attribute, e.g. `doc/name` or `doc/item/@id`.
+    /// Elements give their text content
+    pub fn parse_and_select(&mut self, input: &str, simple_path: &str) -> Result<Vec<String>, ParseError> {
+        let steps = simple_path.split('/').collect::<Vec<_>>();
+        let (attr, elem_steps) = match steps.split_last() {
+            Some((last, rest)) if last.starts_with('@') => (Some(&last[1..]), rest),
+            _ => (None, steps.as_slice()),
+        };
+        if elem_steps.is_empty() || elem_steps.iter().chain(attr.iter()).any(|step| step.is_empty() || step.contains('@')) {
+            return Err(ParseError::static_err(&format!("invalid path '{simple_path}'")));
+        }
+
+        let arena = self.parse(input)?;
+        let mut selected = arena.iter().next().map(|n| arena.get_node_id(n).unwrap()).into_iter().collect::<Vec<_>>(); // first item == root
+        for step in elem_steps {
+            selected = selected.into_iter()
+                .flat_map(|nid| Self::get_child_elements_named(&arena, nid, step))
+                .collect();
+        }
+        let values = match attr {
+            Some(attr) => selected.into_iter()
+                .filter_map(|nid| Self::get_attributes(&arena, nid).get(attr).cloned())
+                .collect(),
+            None => selected.into_iter()
+                .map(|nid| Self::text_content(&arena, nid))
+                .collect(),
+        };
+        Ok(values)
+    }
+
+    /// all the text inside an element, leaving out attribute values
+    fn text_content(arena: &Arena<Content>, nid: NodeId) -> String {
+        let mut text = String::new();
+        for child in nid.children(arena) {
+            match arena.get(child).unwrap().get() {
+                Content::Text(txt) => text.push_str(txt),
+                Content::Element(_) => text.push_str(&Self::text_content(arena, child)),
+                _ => {}
+            }
+        }
+        text
+    }
+
+    /// The same as `parse_from`
+    #[deprecated(note = "use parse_from")]
+    pub fn parse_fragment(&mut self, input: &str, rule_name: &str) -> Result<Arena<Content>, ParseError> {
+        self.parse_from(input, rule_name)
+    }
+
+    fn parse_rule(&mut self, input: &str, rule_name: &str) -> Result<Arena<Content>, ParseError> {
+        self.check_input_len(input)?;
+        let input = self.preprocess(input);
+        let input = InputIter::new(&input);
         debug!("Input now at position {} '{}'", 0, input.get_at(0));
+        self.reset();
+        self.run_from(&input, rule_name)
+    }
 
-        // Seed with top expr
-        let top_rule = g.get_root_definition()
-            .ok_or(ParseError::static_err("No top grammar rule"))?;
+    /// Parse a sequence of tokens from a separate lexer, instead of characters. See `Token`.
+    /// Each token's `text` becomes the text in the tree, wherever the token isn't muted
+    pub fn parse_tokens<T: Token + Clone>(&mut self, tokens: &[T]) -> Result<Arena<Content>, ParseError> {
+        let top_name = self.grammar.get_root_definition_name()
+            .ok_or(ParseError::static_err("No top grammar rule name"))?;
+        if self.max_input_len.is_some_and(|max| tokens.len() > max) {
+            return Err(ParseError::static_err("input too long"));
+        }
+        self.reset();
+        self.token_texts = tokens.iter().map(Token::text).collect();
+        let input = InputIter::from_tokens(tokens.to_vec());
+        self.run_from(&input, &top_name)
+    }
+
+    fn run_from<T: Token>(&mut self, input: &InputIter<T>, rule_name: &str) -> Result<Arena<Content>, ParseError> {
+        self.recognize(input, rule_name)?;
+        self.check_complete(input)?;
+        self.unpack_parse_tree()
+    }
 
-        for alt in top_rule.iter() {
-            let maybe_id = self.traces.task(&g.get_root_definition_name()
-                .ok_or(ParseError::static_err("No top grammar rule name"))?, top_rule.mark(), 0, 0, alt.dot_notator());
+    /// After filling in the chart, a dynamic error unless the start rule matched the whole input,
+    /// saying how far the parse got, e.g. "no parse: at line 2 col 5: unexpected 'x', expected digit or '+'"
+    fn check_complete<T: Token>(&self, input: &InputIter<T>) -> Result<(), ParseError> {
+        let end = input.tokens.len();
+        let root_name = self.start_rule_name().unwrap_or_default();
+        let completed = self.completed_trace.iter()
+            .map(|tid| self.traces.get(*tid))
+            .any(|t| t.name == root_name && t.origin == 0 && t.pos == end);
+        if completed {
+            Ok(())
+        } else {
+            Err(ParseError::dynamic_err(&format!("no parse: {}", self.describe_failure(input))))
+        }
+    }
+
+    /// Fill in the chart for `input`, starting from `rule_name`, without building any tree
+    fn recognize<T: Token>(&mut self, input: &InputIter<T>, rule_name: &str) -> Result<(), ParseError> {
+        // Seed with top expr
+        let top_rule = self.grammar.get_definition(rule_name);
+        let top_mark = match top_rule.mark() {
+            Mark::Attr if self.grammar.get_root_definition_name().as_deref() == Some(rule_name) => {
+                return Err(ParseError::static_err("root rule cannot be an attribute"));
+            }
+            // starting partway into the grammar, there's no enclosing element for it to be an attribute of
+            Mark::Attr => Mark::Default,
+            mark => mark,
+        };
+        let dots = top_rule.iter().zip(&self.alt_indexes[rule_name]).map(|(rule, alt)| (rule.dot_notator(), *alt)).collect::<Vec<_>>();
+
+        for (dot, alt) in dots {
+            let maybe_id = self.traces.task(rule_name, top_mark, 0, 0, dot, alt);
             self.queue_front(maybe_id);
         }
+        self.start_rule = Some(SmolStr::new(rule_name));
+        self.process_queue(input);
+        Ok(())
+    }
+
+    /// How many distinct derivations of the whole input there are: 0 if it doesn't parse, 1 if it's unambiguous, more if it's ambiguous.
+    /// Only walks the chart, so it's cheaper than building trees. Unboundedly ambiguous input (via a cycle of rules) counts as `usize::MAX`
+    pub fn count_parses(&mut self, input: &str) -> usize {
+        let Some(top_name) = self.grammar.get_root_definition_name() else {
+            return 0;
+        };
+        if self.check_input_len(input).is_err() {
+            return 0;
+        }
+        let input = self.preprocess(input).into_owned();
+        let input = InputIter::new(&input);
+        self.reset();
+        if self.recognize(&input, &top_name).is_err() {
+            return 0;
+        }
+        self.count_derivations(&SmolStr::new(top_name), 0, input.tokens.len(), &mut HashMap::new())
+    }
+
+    /// Number of distinct ways the named rule derives the span, memoized per `(name, origin, end)`.
+    /// Reaching a span that's still being counted means a cycle, so there's no upper bound
+    fn count_derivations(&self, name: &SmolStr, origin: usize, end: usize, memo: &mut HashMap<(SmolStr, usize, usize), Option<usize>>) -> usize {
+        let key = (name.clone(), origin, end);
+        match memo.get(&key) {
+            Some(Some(count)) => return *count,
+            Some(None) => return usize::MAX,
+            None => {}
+        }
+        memo.insert(key.clone(), None);
+        let tasks = self.completed_trace.iter()
+            .map(|tid| self.traces.get(*tid))
+            .filter(|t| t.name == *name && t.origin == origin && t.pos == end)
+            .collect::<Vec<_>>();
+        let mut seen = HashSet::new();
+        let mut total: usize = 0;
+        for t in tasks {
+            if !seen.insert(self.derivation_key(t)) {
+                continue;
+            }
+            let mut product: usize = 1;
+            let mut start = origin;
+            for rec in t.dot.matches_iter() {
+                if let MatchRec::NonTerm(child, child_end, _) = rec {
+                    product = product.saturating_mul(self.count_derivations(child, start, *child_end, memo));
+                }
+                start = rec.pos();
+            }
+            total = total.saturating_add(product);
+        }
+        memo.insert(key, Some(total));
+        total
+    }
+
+    /// Re-parse after a small edit, reusing every chart entry that ends at or before the edit.
+    /// `edit` is the range of (char) positions in the previous input that changed;
+    /// everything before `edit.start` must be unchanged in `new_input`.
+    /// Falls back to a full parse if nothing has been parsed yet
+    pub fn reparse(&mut self, new_input: &str, edit: Range<usize>) -> Result<Arena<Content>, ParseError> {
+        if self.traces.arena.is_empty() {
+            return self.parse(new_input);
+        }
+        self.check_input_len(new_input)?;
+        let new_input = self.preprocess(new_input);
+        let input = InputIter::new(&new_input);
+        self.token_texts.clear();
+
+        let remap = self.traces.retain_up_to(edit.start);
+        let completed = self.completed_trace.iter()
+            .filter_map(|tid| remap.get(&tid.0).copied())
+            .collect::<Vec<_>>();
+        self.completed_trace.clear();
+        self.completed_index.clear();
+        self.completed_from.clear();
+        for tid in completed {
+            self.record_completed(tid);
+        }
+        self.farthest_pos = self.traces.arena.iter().map(|t| t.pos).max().unwrap_or(0);
+
+        // anything waiting to scan a character at the edit point needs another look
+        let rescan = self.traces.arena.iter()
+            .filter(|t| t.pos == edit.start && !t.dot.is_completed())
+            .filter(|t| matches!(t.dot.next_unparsed(), Factor::Terminal(..)))
+            .map(|t| t.id)
+            .collect::<Vec<_>>();
+        for tid in rescan {
+            self.queue_back(Some(tid));
+        }
+        self.process_queue(&input);
+        self.check_complete(&input)?;
+        self.unpack_parse_tree()
+    }
+
+    /// The main Earley loop: keep processing until the queue is exhausted
+    fn process_queue<T: Token>(&mut self, input: &InputIter<T>) {
+        // help avoid borrow-contention on *self
+        let g = self.grammar.clone();
+        let alt_indexes = Rc::clone(&self.alt_indexes);
+
         // work through the queue
         while let Some(tid) = self.traces.queue.pop_front() {
             let current_pos = self.traces.get(tid).pos;
             if current_pos > self.farthest_pos {
-                debug!("⏭ Advanced input to position {} (='{}')", current_pos, input.get_at(current_pos));
+                debug!("⏭ Advanced input to position {} (={:?})", current_pos, input.get(current_pos).map(Token::as_char));
                 self.farthest_pos = current_pos;
             }
             debug!("Pulled from queue {} at {}", self.traces.format_task(tid), current_pos);
@@ -389,7 +1018,7 @@ impl Parser {
             // task in completed state?
             if is_completed {
                 debug!("COMPLETER pos={}", current_pos);
-                self.completed_trace.push(tid);
+                self.record_completed(tid);
 
                 // find “parent” states at same origin that can produce this expr;
                 let continuations_here = self.traces.get_continuations_for(self.traces.get(tid).name.clone());
@@ -407,6 +1036,7 @@ impl Parser {
                     match now_finished_via_child {
                         Factor::Nonterm(mark, name) => MatchRec::NonTerm(name, self.traces.get(tid).pos, mark),
                         Factor::Terminal(tmark, _ch ) => MatchRec::Term('?', self.traces.get(tid).pos, tmark),
+                        Factor::Insertion(text) => MatchRec::Insertion(text, self.traces.get(tid).pos),
                     };
                     trace!("MatchRec {:?}", &match_rec);
                     // child may have made progress; next item in parent seq needs to account for this
@@ -432,44 +1062,63 @@ impl Parser {
 
                     // We can have a Mark at the point of definiton,
                     // as well as at the point of reference...
-                    // Figure out what to do with all possible combinations
                     let defn_mark = g.get_definition_mark(&name);
-                    let effective_mark = match (defn_mark, mark) {
-                        (Mark::Default, Mark::Default) => Mark::Default,
-                        (Mark::Mute, Mark::Unmute) => Mark::Unmute,       // can 'undo' marking Mute
-                        (Mark::Attr, _) | (_, Mark::Attr) => Mark::Attr,  // attributes all the way down
-                        (Mark::Mute, _) | (_, Mark::Mute) => Mark::Mute,
-                        (Mark::Unmute, _) | (_, Mark::Unmute) => Mark::Unmute,
-                    };
+                    let effective_mark = Mark::combine(defn_mark, mark);
 
-                    for rule in g.get_definition(&name).iter() {
+                    let defn = g.get_definition(&name);
+                    for (rule, &alt) in defn.iter().zip(&alt_indexes[&name]) {
                         // TODO: propertly account for rule-level Mark
                         let dot = rule.dot_notator();
                         let new_pos = self.traces.get(tid).pos;
                         // "origin" for this downstream task now matches current pos
-                        let maybe_id = self.traces.task_downstream(&name, effective_mark.clone(), new_pos, new_pos, dot);
+                        let maybe_id = self.traces.task_downstream(&name, effective_mark.clone(), new_pos, new_pos, dot, alt);
                         self.queue_front(maybe_id);
                         //self.queue_back(maybe_id);
                     }
+
+                    // the rule may have already been completed from here (say, by matching nothing) before
+                    // this task asked for it, in which case the completer won't come around again
+                    let here = self.traces.get(tid).pos;
+                    let already_completed = self.completed_from.get_vec(&(name.clone(), here))
+                        .into_iter()
+                        .flatten()
+                        .map(|c| self.traces.get(*c).pos)
+                        .collect::<Vec<_>>();
+                    for end in already_completed {
+                        let rec = MatchRec::NonTerm(name.clone(), end, mark);
+                        let maybe_id = self.traces.task_advance_cursor(tid, rec);
+                        self.queue_back(maybe_id);
+                    }
                 }
                 Factor::Terminal(tmark, matcher) => {
                     // record terminal
                     debug!("SCANNER: Terminal {tmark}{matcher} at pos={current_pos}");
-                    if matcher.accept(input.get_at(current_pos)) {
+                    // nothing matches past the end of input
+                    let scanned = input.get(current_pos).filter(|tok| if g.is_case_insensitive() {
+                        matcher.accept_ignoring_case(tok.as_char())
+                    } else {
+                        tok.matches(&matcher)
+                    });
+                    if let Some(tok) = scanned {
                         // Match!
-                        let rec = MatchRec::Term(input.get_at(current_pos), current_pos + 1, tmark);
+                        let rec = MatchRec::Term(tok.as_char(), current_pos + 1, tmark);
                         debug!("advance cursor SCAN");
                         let maybe_id = self.traces.task_advance_cursor(tid, rec);
                         self.queue_back(maybe_id);
                     } else {
-                        debug!("non-matched char '{}' (expecting {matcher}); 🛑", input.get_at(current_pos));
+                        debug!("non-matched {:?} (expecting {matcher}); 🛑", input.get(current_pos).map(Token::as_char));
                     }
                 }
+                Factor::Insertion(text) => {
+                    // matches nothing, so move right along without consuming input
+                    debug!("INSERTION: +\"{text}\" at pos={current_pos}");
+                    let rec = MatchRec::Insertion(text, current_pos);
+                    let maybe_id = self.traces.task_advance_cursor(tid, rec);
+                    self.queue_back(maybe_id);
+                }
             }
         } // while
         info!("Finished parse with {} items in trace", self.traces.arena.len());
-        
-        self.unpack_parse_tree()
     }
 
     fn queue_front(&mut self, maybe_id: Option<TraceId>) {
@@ -484,20 +1133,258 @@ impl Parser {
         }
     }
 
+    fn start_rule_name(&self) -> Option<String> {
+        match &self.start_rule {
+            Some(name) => Some(name.to_string()),
+            None => self.grammar.get_root_definition_name(),
+        }
+    }
+
+    fn record_completed(&mut self, tid: TraceId) {
+        let t = self.traces.get(tid);
+        self.completed_index.insert((t.name.clone(), t.origin, t.pos), tid);
+        self.completed_from.insert((t.name.clone(), t.origin), tid);
+        self.completed_trace.push(tid);
+    }
+
+    /// the completed tasks for the named rule over exactly `origin..pos`, in the order they completed
+    fn completed_over(&self, name: &str, origin: usize, pos: usize) -> impl Iterator<Item = &Task> {
+        self.completed_index.get_vec(&(SmolStr::new(name), origin, pos))
+            .into_iter()
+            .flatten()
+            .map(|tid| self.traces.get(*tid))
+    }
+
     /// Sift through and find only completed Tasks
     /// this speeds up the unpacking process by omitting parse states irrelevant to the final result
+    /// When several derivations cover the same span, the choice is deterministic, regardless of the order the parser found them in:
+    /// 1. the earliest-defined alternative of the rule wins, then
+    /// 2. among derivations of that alternative, the `RepetitionPolicy` decides where the parts split
     fn filter_completed_trace(&self, name: &str, origin: usize, pos: usize) -> Option<&Task> {
-        // TODO: optimize
-        for tid in &self.completed_trace {
-            let t = self.traces.get(*tid);
-            if t.name == name && t.origin == origin && t.pos == pos {
-                return Some(t);
+        self.filter_completed_trace_avoiding(name, origin, pos, &[])
+    }
+
+    /// Like `filter_completed_trace`, but passing over any derivation that would lead back around,
+    /// over the very same span, to itself or to one of the `in_flight` (rule, origin, end) triples being unpacked.
+    /// With rules like `S = S; "a".` the earliest alternative would otherwise be chosen forever
+    fn filter_completed_trace_avoiding(&self, name: &str, origin: usize, pos: usize, in_flight: &[(SmolStr, usize, usize)]) -> Option<&Task> {
+        // a hash lookup rather than a scan of every completed task, which made unpacking quadratic in the input length
+        let mut best: Option<(&Task, usize, Vec<usize>)> = None;
+        for t in self.completed_over(name, origin, pos) {
+            if !self.is_cyclic(t, in_flight) {
+                let (alt_index, boundaries) = self.derivation_key(t);
+                let is_better = match &best {
+                    None => true,
+                    Some((_, best_alt_index, _)) if alt_index != *best_alt_index => alt_index < *best_alt_index,
+                    Some((_, _, best_boundaries)) => match self.repetition_policy {
+                        RepetitionPolicy::Greedy => boundaries > *best_boundaries,
+                        RepetitionPolicy::Lazy => boundaries < *best_boundaries,
+                    },
+                };
+                if is_better {
+                    best = Some((t, alt_index, boundaries));
+                }
+            }
+        }
+        best.map(|(t, _, _)| t)
+    }
+
+    /// Whether unpacking this derivation would come back around to a rule already in flight over the same span,
+    /// either directly or because a child covering the whole span has no derivation that doesn't
+    fn is_cyclic(&self, t: &Task, in_flight: &[(SmolStr, usize, usize)]) -> bool {
+        let mut chain: Option<Vec<(SmolStr, usize, usize)>> = None;
+        let mut start = t.origin;
+        for rec in t.dot.matches_iter() {
+            // only a child covering the very same span can lead back around
+            if let MatchRec::NonTerm(child, end, _) = rec {
+                if start == t.origin && *end == t.pos {
+                    let chain = chain.get_or_insert_with(|| {
+                        let mut chain = in_flight.to_vec();
+                        chain.push((t.name.clone(), t.origin, t.pos));
+                        chain
+                    });
+                    if chain.iter().any(|(name, o, p)| name == child && *o == start && p == end)
+                        || self.filter_completed_trace_avoiding(child, start, *end, chain).is_none() {
+                        return true;
+                    }
+                }
+            }
+            start = rec.pos();
+        }
+        false
+    }
+
+    /// What makes a completed task a distinct derivation: which alternative of the rule it matched,
+    /// and where each of that alternative's parts ended
+    fn derivation_key(&self, t: &Task) -> (usize, Vec<usize>) {
+        let boundaries = t.dot.matches_iter().map(MatchRec::pos).collect::<Vec<_>>();
+        (t.alt, boundaries)
+    }
+
+    /// Which alternative each alternative of each rule counts as. Identical alternatives share the first one's index,
+    /// so they can't give rise to distinct tasks
+    fn alt_indexes(grammar: &Grammar) -> HashMap<SmolStr, Vec<usize>> {
+        grammar.defn_order.iter()
+            .map(|name| {
+                let alts = grammar.get_definition(name).iter().collect::<Vec<_>>();
+                let indexes = alts.iter()
+                    .map(|rule| alts.iter().position(|alt| alt == rule).unwrap_or(usize::MAX))
+                    .collect();
+                (name.clone(), indexes)
+            })
+            .collect()
+    }
+
+    /// Whether the named rule matched the span in more than one distinct way
+    fn is_ambiguous_at(&self, name: &str, origin: usize, pos: usize) -> bool {
+        let mut keys = self.completed_over(name, origin, pos)
+            .map(|t| self.derivation_key(t));
+        match keys.next() {
+            Some(first) => keys.any(|key| key != first),
+            None => false,
+        }
+    }
+
+    /// After a parse, all `(origin, end)` input spans where the named rule completed, sorted and deduplicated.
+    /// This covers every completion the parser found, including ones that didn't end up in the final parse tree
+    pub fn matches_for(&self, rule_name: &str) -> Vec<(usize, usize)> {
+        let mut spans = self.completed_trace.iter()
+            .map(|tid| self.traces.get(*tid))
+            .filter(|t| t.name == rule_name)
+            .map(|t| (t.origin, t.pos))
+            .collect::<Vec<_>>();
+        spans.sort_unstable();
+        spans.dedup();
+        spans
+    }
+
+    /// After a parse, how many tasks (Earley items) each rule accounted for, including synthesized rules.
+    /// A rule with a disproportionate share is usually the one to look at when a parse is slow
+    pub fn rule_task_counts(&self) -> HashMap<SmolStr, usize> {
+        let mut counts: HashMap<SmolStr, usize> = HashMap::new();
+        for task in &self.traces.arena {
+            *counts.entry(task.name.clone()).or_default() += 1;
+        }
+        counts
+    }
+
+    /// After a successful parse, the names of the rules that took part in building the parse tree
+    /// (including muted and synthesized rules, which don't show up in the output).
+    /// Union this over a corpus of inputs to find rules that none of them exercise
+    pub fn coverage(&self) -> HashSet<SmolStr> {
+        let mut covered: HashSet<SmolStr> = HashSet::new();
+        let Some(root_name) = self.start_rule_name() else { return covered };
+        // same selection of completed tasks as unpack_parse_tree_internal
+        let mut pending: Vec<(SmolStr, usize, usize)> = vec![(SmolStr::new(root_name), 0, self.farthest_pos)];
+        let mut visited: HashSet<(SmolStr, usize, usize)> = HashSet::new();
+        while let Some((name, origin, end)) = pending.pop() {
+            if !visited.insert((name.clone(), origin, end)) {
+                continue;
+            }
+            let Some(task) = self.filter_completed_trace(&name, origin, end) else { continue };
+            covered.insert(task.name.clone());
+            let mut new_origin = origin;
+            for match_rec in task.dot.matches_iter() {
+                match match_rec {
+                    MatchRec::Term(_, pos, _) | MatchRec::Insertion(_, pos) => new_origin = *pos,
+                    MatchRec::NonTerm(nt_name, pos, _) => {
+                        pending.push((nt_name.clone(), new_origin, *pos));
+                        new_origin = *pos;
+                    }
+                }
             }
         }
-        None
+        covered
+    }
+
+    /// After a parse of `input` that didn't succeed, a human-readable account of where it got stuck, like
+    /// "at line 2 col 5: unexpected 'x', expected digit or '+'".
+    /// Expectations are named after the rule when a rule is waiting on its very first character, otherwise by the character(s)
+    pub fn explain_failure(&self, input: &str) -> String {
+        let input = self.preprocess(input);
+        self.describe_failure(&InputIter::new(&input))
+    }
+
+    fn describe_failure<T: Token>(&self, input: &InputIter<T>) -> String {
+        let pos = self.farthest_pos;
+        let (line, col) = input.line_col(pos);
+        let unexpected = match input.get(pos) {
+            Some(tok) => format!("unexpected '{}'", tok.as_char()),
+            None => "unexpected end of input".to_string(),
+        };
+
+        let mut expected: Vec<String> = Vec::new();
+        for task in self.traces.arena.iter().filter(|t| t.pos == pos && !t.dot.is_completed()) {
+            if let Factor::Terminal(_, lit) = task.dot.next_unparsed() {
+                let at_rule_start = task.dot.matches_iter().next().is_none();
+                let description = if at_rule_start && !Grammar::is_synthesized_name(&task.name) {
+                    task.name.to_string()
+                } else {
+                    match lit.single_char() {
+                        Some(ch) => format!("'{}'", ch.escape_debug()),
+                        None => lit.to_string(),
+                    }
+                };
+                if !expected.contains(&description) {
+                    expected.push(description);
+                }
+            }
+        }
+        if expected.is_empty() {
+            format!("at line {line} col {col}: {unexpected}")
+        } else {
+            format!("at line {line} col {col}: {unexpected}, expected {}", expected.join(" or "))
+        }
+    }
+
+    /// Render the Earley chart of the last parse as an HTML table, one row per input position,
+    /// listing every task that had reached that position. Completed tasks get `class="task done"`.
+    /// `input` should be the same text that was parsed. Meant for learning and debugging, not for machines
+    pub fn chart_to_html(&self, input: &str) -> String {
+        let input = self.preprocess(input);
+        let chars = input.chars().collect::<Vec<_>>();
+        let mut html = Builder::default();
+        html.append("<table class=\"earley-chart\">\n");
+        html.append("<tr><th>pos</th><th>char</th><th>tasks</th></tr>\n");
+        for pos in 0..=chars.len().max(self.farthest_pos) {
+            let ch = chars.get(pos).map_or(String::new(), |ch| Self::escape_text(&ch.escape_debug().to_string()));
+            html.append(format!("<tr id=\"pos-{pos}\"><td>{pos}</td><td class=\"char\">{ch}</td><td>"));
+            for task in self.traces.arena.iter().filter(|t| t.pos == pos) {
+                let class = if task.dot.is_completed() { "task done" } else { "task" };
+                html.append(format!("<div class=\"{class}\">{} {}..{}: {}</div>",
+                    Self::escape_text(&task.name), task.origin, task.pos, Self::escape_text(&task.dot.to_string())));
+            }
+            html.append("</td></tr>\n");
+        }
+        html.append("</table>\n");
+        html.string().unwrap()
+    }
+
+    /// After a parse, every chart entry that had reached input (char) position `pos`, in the order the parser made them.
+    /// When a parse fails, `chart_at(parser.farthest_pos())` shows what was still expected where it got stuck
+    pub fn chart_at(&self, pos: usize) -> Vec<ChartEntry> {
+        self.traces.arena.iter()
+            .filter(|t| t.pos == pos)
+            .map(|t| {
+                let dot = t.dot.matches_iter().len();
+                let (state, next) = if t.dot.is_completed() {
+                    (ChartState::Completed, None)
+                } else if dot == 0 {
+                    (ChartState::Predicted, Some(t.dot.next_unparsed().to_string()))
+                } else {
+                    (ChartState::Scanned, Some(t.dot.next_unparsed().to_string()))
+                };
+                ChartEntry { rule: t.name.clone(), origin: t.origin, dot, state, next }
+            })
+            .collect()
     }
 
-    /// Only for use in test sutes. Not guaranteed to be stable...
+    /// After a parse, the furthest input position the parser reached. For a failed parse, that's where it got stuck
+    pub fn farthest_pos(&self) -> usize {
+        self.farthest_pos
+    }
+
+    /// Only for use in test suites. Not guaranteed to be stable; see `chart_at` for that
     pub fn test_inspect_trace(&self, filter: Option<SmolStr>) -> Vec<Task> {
         match filter {
             Some(str) => self.traces.arena
@@ -517,14 +1404,16 @@ impl Parser {
         let mut arena = Arena::new();
         let root = arena.new_node(Content::Root);
         debug!("assuming ending pos of {}", self.farthest_pos);
-        let name = self.grammar.get_root_definition_name().unwrap();
-        self.unpack_parse_tree_internal(&mut arena, &name, Mark::Default, 0, self.farthest_pos, root);
+        let name = self.start_rule_name().unwrap();
+        let mut spans = HashMap::new();
+        spans.insert(root, 0..self.farthest_pos);
+        self.unpack_parse_tree_internal(&mut arena, &name, 0, self.farthest_pos, root, &mut spans)?;
 
         // the standard algorithm above leaves attribute nodes in an inconvenient state.
         // with a bare Content::Attribute node, for which one needs to plumb all descendants to find text nodes
         // below, we do that once-and-for-all for each Content::Attribute node
         let attr_node_ids = arena.iter()
-            .filter(|n| matches!(n.get(), Content::Attribute(..) ))
+            .filter(|n| !n.is_removed() && matches!(n.get(), Content::Attribute(..) ))
             .map(|n| arena.get_node_id(n).unwrap())
             .collect::<Vec<_>>();
         for attr_nid in attr_node_ids {
@@ -534,10 +1423,122 @@ impl Parser {
         // n.b. this doesn't actually delete these original descendent text nodes...
         // but you should never need to even look for them
 
+        self.resolve_duplicate_attrs(&mut arena)?;
+
+        let (arena, spans) = Self::merge_text_nodes(&arena, &spans);
+        self.node_spans = spans;
         Ok(arena)
     }
 
-    /// Recurse down through the tree to assemble all the text literals that comprise an attribute value
+    /// Characters get matched (and unpacked) one at a time, often from different rules.
+    /// Copy the tree into a fresh arena, merging each run of adjacent Text siblings into one node.
+    /// This also leaves behind any nodes removed along the way, so the result is safe to `iter()`
+    fn merge_text_nodes(arena: &Arena<Content>, spans: &HashMap<NodeId, Range<usize>>) -> (Arena<Content>, HashMap<NodeId, Range<usize>>) {
+        let mut merged = Arena::new();
+        let mut merged_spans = HashMap::new();
+        let old_root = arena.iter().next().map(|n| arena.get_node_id(n).unwrap()).unwrap(); // first item == root
+        let new_root = merged.new_node(Content::Root);
+        if let Some(span) = spans.get(&old_root) {
+            merged_spans.insert(new_root, span.clone());
+        }
+        Self::merge_text_children(arena, old_root, spans, &mut merged, new_root, &mut merged_spans);
+        (merged, merged_spans)
+    }
+
+    /// Works from an explicit stack of the children still to copy, rather than recursing, so a deeply nested tree can't overflow
+    fn merge_text_children(arena: &Arena<Content>, from: NodeId, spans: &HashMap<NodeId, Range<usize>>,
+                           merged: &mut Arena<Content>, to: NodeId, merged_spans: &mut HashMap<NodeId, Range<usize>>) {
+        // (children left to copy, where they go, the text node still open for more text)
+        let mut stack: Vec<(Children<Content>, NodeId, Option<NodeId>)> = vec![(from.children(arena), to, None)];
+        while let Some((children, to, text_run)) = stack.last_mut() {
+            let Some(child) = children.next() else {
+                stack.pop();
+                continue;
+            };
+            let to = *to;
+            let content = arena.get(child).unwrap().get();
+            if let (Content::Text(txt), Some(run)) = (content, *text_run) {
+                if let Content::Text(run_txt) = merged.get_mut(run).unwrap().get_mut() {
+                    run_txt.push_str(txt);
+                }
+                if let (Some(run_span), Some(span)) = (merged_spans.get_mut(&run), spans.get(&child)) {
+                    run_span.end = span.end;
+                }
+                continue;
+            }
+            let new_child = merged.new_node(content.clone());
+            to.append(new_child, merged);
+            if let Some(span) = spans.get(&child) {
+                merged_spans.insert(new_child, span.clone());
+            }
+            if matches!(content, Content::Text(_)) {
+                *text_run = Some(new_child);
+            } else {
+                *text_run = None;
+                stack.push((child.children(arena), new_child, None));
+            }
+        }
+    }
+
+    /// An element can pick up the same attribute more than once (e.g. `doc = x, x. @x = ...`)
+    /// Either report that, or drop the extras, depending on `dup_attr_policy`
+    fn resolve_duplicate_attrs(&self, arena: &mut Arena<Content>) -> Result<(), ParseError> {
+        let elem_ids = arena.iter()
+            .filter(|n| !n.is_removed() && n.get().is_elem())
+            .map(|n| arena.get_node_id(n).unwrap())
+            .collect::<Vec<_>>();
+        for elem in elem_ids {
+            let mut attrs: Vec<(String, NodeId)> = elem.children(arena)
+                .filter(|n| arena.get(*n).unwrap().get().is_attr())
+                .map(|n| (arena.get(n).unwrap().get().get_name().unwrap(), n))
+                .collect();
+            if self.dup_attr_policy == DuplicateAttrPolicy::KeepLast {
+                attrs.reverse();
+            }
+            let mut seen: HashSet<String> = HashSet::new();
+            for (name, nid) in attrs {
+                if seen.insert(name.clone()) {
+                    continue;
+                }
+                match self.dup_attr_policy {
+                    DuplicateAttrPolicy::Error => {
+                        return Err(ParseError::dynamic_err(&format!("{}: duplicate attribute '{name}' on the same element", ErrorCode::D02)));
+                    }
+                    DuplicateAttrPolicy::KeepFirst | DuplicateAttrPolicy::KeepLast => {
+                        nid.remove_subtree(arena);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Replace the (non-attribute) children of `elem` with whatever `mapper` makes of their text
+    fn apply_mapper(arena: &mut Arena<Content>, elem: NodeId, mapper: &dyn Fn(&str) -> Content) {
+        let text = Self::text_content(arena, elem);
+        let children = elem.children(arena)
+            .filter(|n| !arena.get(*n).unwrap().get().is_attr())
+            .collect::<Vec<_>>();
+        for child in children {
+            child.remove_subtree(arena);
+        }
+        let replacement = arena.new_node(mapper(&text));
+        elem.append(replacement, arena);
+    }
+
+    /// Turn `elem` into a comment holding its text, dropping everything under it (see `Grammar::set_comment_rule`)
+    fn make_comment(arena: &mut Arena<Content>, elem: NodeId) {
+        let text = Self::text_content(arena, elem);
+        let children = elem.children(arena).collect::<Vec<_>>();
+        for child in children {
+            child.remove_subtree(arena);
+        }
+        *arena.get_mut(elem).unwrap().get_mut() = Content::Comment(text);
+    }
+
+    /// Recurse down through the tree to assemble all the text literals that comprise an attribute value.
+    /// Insertions were unpacked into text like any matched characters, so any beneath the attribute land in its value here.
+    /// The value is kept as plain text, like `Content::Text`; it only gets escaped on the way out to XML
     fn unpack_attr_value(&self, attr_nid: NodeId, arena: &mut Arena<Content>) -> String {
         let mut attr_value = Builder::default();
         for descendant in attr_nid.descendants(arena) {
@@ -545,61 +1546,153 @@ impl Parser {
             if let Content::Text(txt) = arena.get(descendant).unwrap().get() {
                 attr_builder.append(txt.as_str());
             }
-            attr_value.append(attr_builder.string().unwrap().replace('\"', "&quot;"));
+            attr_value.append(attr_builder.string().unwrap());
+        }
+        let attr_value = attr_value.string().unwrap();
+        if self.normalize_attr_whitespace {
+            attr_value.split([' ', '\t', '\n', '\r'])
+                .filter(|s| !s.is_empty())
+                .collect::<Vec<_>>()
+                .join(" ")
+        } else {
+            attr_value
         }
-        attr_value.string().unwrap()
     }
 
-    fn unpack_parse_tree_internal(&self, arena: &mut Arena<Content>, name: &str, mark: Mark, origin: usize, end: usize, root: NodeId) {
-        let matching_trace = self.filter_completed_trace(name, origin, end);
-        let mut new_root = root;
-            match matching_trace {
-                Some(task) => {
-                    let match_name = &task.name;
+    /// Whether XML 1.0 allows `ch` at all. The rest (most C0 controls, U+FFFE and U+FFFF)
+    /// can't even be written as character references, so the escaping functions put U+FFFD in their place
+    fn is_xml_char(ch: char) -> bool {
+        matches!(ch, '\t' | '\n' | '\r' | '\u{20}'..='\u{D7FF}' | '\u{E000}'..='\u{FFFD}' | '\u{10000}'..)
+    }
 
-                    if task.mark==Mark::Mute || match_name.starts_with('-') {
-                        // Skip
-                        debug!("trace found {mark} {task} -- SKIPPING");
-                    } else {
-                        // Element or Attribute
-                        debug!("trace found {} {task}", task.mark);
-                        let name_str = match_name.to_string();
-                        let data = if task.mark==Mark::Attr {
-                            Content::Attribute(name_str, "".to_string()) // 2nd pass will fill in the atttribute value
-                        } else {
-                            Content::Element(name_str)
-                        };
-                        let temp_root = arena.new_node(data);
-                        root.append(temp_root, arena);
-                        new_root = temp_root;
+    /// Escape text for use as XML character data
+    fn escape_text(text: &str) -> String {
+        let mut escaped = String::with_capacity(text.len());
+        for ch in text.chars() {
+            match ch {
+                '&' => escaped.push_str("&amp;"),
+                '<' => escaped.push_str("&lt;"),
+                '>' => escaped.push_str("&gt;"),
+                ch if !Self::is_xml_char(ch) => escaped.push(char::REPLACEMENT_CHARACTER),
+                ch => escaped.push(ch),
+            }
+        }
+        escaped
+    }
+
+    /// Escape a value for use inside a double-quoted XML attribute.
+    /// Besides markup characters, control characters become character references,
+    /// including tab/CR/LF, which an XML parser would otherwise normalize to spaces
+    fn escape_attr(value: &str) -> String {
+        let mut escaped = String::with_capacity(value.len());
+        for ch in value.chars() {
+            match ch {
+                '&' => escaped.push_str("&amp;"),
+                '<' => escaped.push_str("&lt;"),
+                '>' => escaped.push_str("&gt;"),
+                '"' => escaped.push_str("&quot;"),
+                ch if !Self::is_xml_char(ch) => escaped.push(char::REPLACEMENT_CHARACTER),
+                ch if ch.is_control() => escaped.push_str(&format!("&#x{:X};", ch as u32)),
+                ch => escaped.push(ch),
+            }
+        }
+        escaped
+    }
+
+    /// Make text safe to put inside an XML comment, which can't contain "--" or end with "-"
+    fn escape_comment(text: &str) -> String {
+        let mut escaped = String::with_capacity(text.len());
+        for ch in text.chars() {
+            if ch == '-' && escaped.ends_with('-') {
+                escaped.push(' ');
+            }
+            escaped.push(if Self::is_xml_char(ch) { ch } else { char::REPLACEMENT_CHARACTER });
+        }
+        if escaped.ends_with('-') {
+            escaped.push(' ');
+        }
+        escaped
+    }
+
+    /// Build the tree for `name` over `origin..end` under `root`.
+    /// This works from an explicit stack rather than recursing, so how deeply the tree nests is limited by `max_depth`, not the call stack.
+    /// Hidden (muted or synthesized) rules get unpacked inline, into the element they appear in,
+    /// so e.g. the right-leaning chain of rules behind a repetition stays flat, and its characters stay one run
+    fn unpack_parse_tree_internal(&self, arena: &mut Arena<Content>, name: &str, origin: usize, end: usize, root: NodeId,
+            spans: &mut HashMap<NodeId, Range<usize>>) -> Result<(), ParseError> {
+        // the (rule, origin, end) of every rule being unpacked, so no derivation gets chosen that would lead back around to one of them
+        let mut in_flight: Vec<(SmolStr, usize, usize)> = Vec::new();
+        let mut open: Vec<OpenNode> = Vec::new();
+        let mut frames: Vec<UnpackFrame> = Vec::new();
+        self.open_node(arena, spans, name, origin, end, root, &mut in_flight, &mut open, &mut frames)?;
+
+        let mut steps_in_place = 0; // inlined rules matching nothing, in a row; guards against cycles
+        while let Some(UnpackFrame { matches, next_origin, .. }) = frames.last_mut() {
+            let Some(match_rec) = matches.next() else {
+                if let Some(done) = frames.pop() {
+                    in_flight.truncate(done.keep);
+                    // the last of a node's frames is done, and so is the node
+                    if frames.last().map(|frame| frame.node) != Some(done.node) {
+                        if let Some(node) = open.pop() {
+                            self.close_node(arena, spans, node, &mut in_flight);
+                        }
                     }
-            
-                    // CHILDREN
-                    let mut new_origin = origin;
-                    let dot = &task.dot;
-                    for match_rec in dot.matches_iter() {
-                        match match_rec {
-                            MatchRec::Term(ch, pos, tmark) => {
-                                if *tmark != TMark::Mute {
-                                    let new_child = arena.new_node(Content::Text(ch.to_string()) );
-                                   new_root.append(new_child, arena);
+                }
+                continue;
+            };
+            let is_last = matches.as_slice().is_empty();
+            let new_origin = std::mem::replace(next_origin, match_rec.pos());
+            let Some(current) = open.last_mut() else { break };
+            match match_rec {
+                MatchRec::Term(ch, _pos, tmark) => {
+                    // a muted character contributes nothing, so the run carries on past it
+                    if *tmark != TMark::Mute {
+                        let run = &mut current.text_run.get_or_insert_with(|| (String::new(), new_origin)).0;
+                        match self.token_texts.get(new_origin) {
+                            Some(token_text) => run.push_str(token_text),
+                            None => run.push(*ch),
+                        }
+                    }
+                    steps_in_place = 0;
+                }
+                MatchRec::Insertion(text, _pos) => {
+                    current.text_run.get_or_insert_with(|| (String::new(), new_origin)).0.push_str(text);
+                }
+                MatchRec::NonTerm(nt_name, pos, _mark) => {
+                    // `is_cyclic` already steers clear of any derivation that leads straight back to itself
+                    debug_assert!(*nt_name != current.task.name || new_origin != current.task.origin || *pos != current.task.pos);
+                    let hidden = self.filter_completed_trace_avoiding(nt_name, new_origin, *pos, &in_flight)
+                        .filter(|t| t.mark == Mark::Mute || Grammar::is_synthesized_name(&t.name));
+                    match hidden {
+                        Some(hidden) => {
+                            if self.reject_ambiguous && self.is_ambiguous_at(nt_name, new_origin, *pos) {
+                                return Err(ParseError::dynamic_err(&format!("{}: ambiguous parse: {nt_name} matches {new_origin}..{pos} in more than one way", ErrorCode::D03)));
+                            }
+                            steps_in_place = if new_origin == *pos { steps_in_place + 1 } else { 0 };
+                            let node = open.len() - 1;
+                            let mut keep = in_flight.len();
+                            if is_last {
+                                // nothing left to come back for; the hidden rule takes over this frame's place
+                                if let Some(done) = frames.pop() {
+                                    keep = done.keep;
                                 }
-                                new_origin = *pos;
                             }
-                            MatchRec::NonTerm(nt_name, pos, mark) => {
-                                // guard against infinite recursion
-                                assert!( (nt_name!=name || new_origin!=origin || *pos!=end));
-                                self.unpack_parse_tree_internal(arena, nt_name, mark.clone(), new_origin, *pos, new_root);
-                                new_origin = *pos;
+                            if frames.len() > self.max_depth || steps_in_place > self.max_depth {
+                                return Err(ParseError::dynamic_err("max nesting exceeded"));
                             }
+                            in_flight.push((hidden.name.clone(), new_origin, *pos));
+                            frames.push(UnpackFrame { matches: hidden.dot.matches_iter(), next_origin: new_origin, keep, node });
+                        }
+                        None => {
+                            let parent = current.node;
+                            Self::flush_text(arena, spans, parent, &mut current.text_run, new_origin);
+                            self.open_node(arena, spans, nt_name, new_origin, *pos, parent, &mut in_flight, &mut open, &mut frames)?;
+                            steps_in_place = 0;
                         }
                     }
-            
-                }
-                None => {
-                    info!("  No matching traces for {}@{}:{}", name, origin, end);
                 }
             }
+        }
 
         //HOW TO SERIALISE name FROM start TO end: 
         //    IF SOME task IN trace[end] HAS (symbol task = name AND finished task AND start.position task = start): 
@@ -615,6 +1708,74 @@ impl Parser {
         //                SERIALISE sym FROM newstart TO pos
         //        PUT pos IN newstart
 
+        Ok(())
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    /// Start unpacking `name` over `origin..end`: add its element or attribute under `parent` (unless it's hidden),
+    /// and stack up a frame to work through its matches
+    fn open_node<'a>(&'a self, arena: &mut Arena<Content>, spans: &mut HashMap<NodeId, Range<usize>>, name: &str, origin: usize, end: usize, parent: NodeId,
+            in_flight: &mut Vec<(SmolStr, usize, usize)>, open: &mut Vec<OpenNode<'a>>, frames: &mut Vec<UnpackFrame<'a>>) -> Result<(), ParseError> {
+        if frames.len() > self.max_depth {
+            return Err(ParseError::dynamic_err("max nesting exceeded"));
+        }
+        if self.reject_ambiguous && self.is_ambiguous_at(name, origin, end) {
+            return Err(ParseError::dynamic_err(&format!("{}: ambiguous parse: {name} matches {origin}..{end} in more than one way", ErrorCode::D03)));
+        }
+        let Some(task) = self.filter_completed_trace_avoiding(name, origin, end, in_flight) else {
+            info!("  No matching traces for {}@{}:{}", name, origin, end);
+            return Ok(());
+        };
+        let mut node = parent;
+        // synthesized rules are always muted, but check the name too, since they'd be invalid XML names
+        if task.mark==Mark::Mute || Grammar::is_synthesized_name(&task.name) {
+            // Skip
+            debug!("trace found {task} -- SKIPPING");
+        } else {
+            // Element or Attribute
+            debug!("trace found {} {task}", task.mark);
+            let name_str = task.name.to_string();
+            let data = if task.mark==Mark::Attr {
+                Content::Attribute(name_str, "".to_string()) // 2nd pass will fill in the atttribute value
+            } else {
+                match self.element_renames.get(&task.name) {
+                    Some(renamed) => Content::Element(renamed.to_string()),
+                    None => Content::Element(name_str),
+                }
+            };
+            node = arena.new_node(data);
+            parent.append(node, arena);
+            spans.insert(node, origin..end);
+        }
+        let in_flight_len = in_flight.len();
+        in_flight.push((task.name.clone(), origin, end));
+        frames.push(UnpackFrame { matches: task.dot.matches_iter(), next_origin: origin, keep: in_flight_len, node: open.len() });
+        open.push(OpenNode { task, node, is_new: node != parent, text_run: None, in_flight_len });
+        Ok(())
+    }
+
+    /// All of a node's matches are unpacked: finish off its text, and apply any mapping or comment rule
+    fn close_node(&self, arena: &mut Arena<Content>, spans: &mut HashMap<NodeId, Range<usize>>, mut node: OpenNode, in_flight: &mut Vec<(SmolStr, usize, usize)>) {
+        Self::flush_text(arena, spans, node.node, &mut node.text_run, node.task.pos);
+        in_flight.truncate(node.in_flight_len);
+        if !node.is_new || !arena.get(node.node).unwrap().get().is_elem() {
+            return;
+        }
+        if let Some(ElementMapper(mapper)) = self.element_mappers.get(&node.task.name) {
+            Self::apply_mapper(arena, node.node, mapper);
+        }
+        if self.grammar.is_comment_rule(&node.task.name) {
+            Self::make_comment(arena, node.node);
+        }
+    }
+
+    /// consecutive visible characters accumulate into a single text node, added once the run ends
+    fn flush_text(arena: &mut Arena<Content>, spans: &mut HashMap<NodeId, Range<usize>>, node: NodeId, text_run: &mut Option<(String, usize)>, end: usize) {
+        if let Some((txt, start)) = text_run.take() {
+            let new_child = arena.new_node(Content::Text(txt));
+            node.append(new_child, arena);
+            spans.insert(new_child, start..end);
+        }
     }
 
 
@@ -650,14 +1811,13 @@ impl Parser {
                     };
                     builder.append(attr_name.to_string());
                     builder.append("=\"");
-                    builder.append(attr_value.replace('"', "&quot;"));
+                    builder.append(Self::escape_attr(attr_value));
                     builder.append("\"");
                 }
 
                 builder.append(">");
     
                 for child in nid.children(arena) {
-                    println!("testfmt found {child} in ::Element");
                     Self::tree_to_testfmt_recurse(arena, builder, child);
                 }
     
@@ -666,20 +1826,179 @@ impl Parser {
                 builder.append(">");
             },
             Content::Attribute(..) => {}, // handled above
-            Content::Text(utf8) => builder.append(utf8.clone()),
+            Content::Text(utf8) => builder.append(Self::escape_text(utf8)),
+            Content::Comment(utf8) => {
+                builder.append("<!--");
+                builder.append(Self::escape_comment(utf8));
+                builder.append("-->");
+            }
+        }
+    }
+
+    /// Well-formed XML for real output, starting with an XML declaration.
+    /// With `pretty`, child elements go on lines of their own, indented two spaces per level,
+    /// except inside an element with text of its own, where added whitespace would change the content
+    pub fn tree_to_xml(arena: &Arena<Content>, pretty: bool) -> String {
+        let mut builder = Builder::default();
+        builder.append(r#"<?xml version="1.0" encoding="UTF-8"?>"#);
+        if let Some(root) = arena.iter().next() { // first item == root
+            let root_id = arena.get_node_id(root).unwrap();
+            for child in root_id.children(arena) {
+                builder.append("\n");
+                Self::tree_to_xml_recurse(arena, &mut builder, child, pretty.then_some(0));
+            }
+        }
+        builder.append("\n");
+        builder.string().unwrap()
+    }
+
+    /// `indent` is how deep this node sits, when its children get indented
+    fn tree_to_xml_recurse(arena: &Arena<Content>, builder: &mut Builder, nid: NodeId, indent: Option<usize>) {
+        let Some(node) = arena.get(nid) else { return };
+        match node.get() {
+            Content::Root | Content::Attribute(..) => {}, // attributes are handled with their element
+            Content::Element(name) => {
+                builder.append("<");
+                builder.append(name.to_string());
+                for attr_child in nid.children(arena) {
+                    if let Content::Attribute(attr_name, attr_value) = arena.get(attr_child).unwrap().get() {
+                        builder.append(format!(" {attr_name}=\"{}\"", Self::escape_attr(attr_value)));
+                    }
+                }
+                let children = nid.children(arena)
+                    .filter(|child| !arena.get(*child).unwrap().get().is_attr())
+                    .collect::<Vec<_>>();
+                if children.is_empty() {
+                    builder.append("/>");
+                    return;
+                }
+                builder.append(">");
+                let has_text = children.iter().any(|child| matches!(arena.get(*child).unwrap().get(), Content::Text(_)));
+                let child_indent = indent.filter(|_| !has_text).map(|depth| depth + 1);
+                for child in children {
+                    if let Some(depth) = child_indent {
+                        builder.append("\n");
+                        builder.append("  ".repeat(depth));
+                    }
+                    Self::tree_to_xml_recurse(arena, builder, child, child_indent);
+                }
+                if let Some(depth) = child_indent {
+                    builder.append("\n");
+                    builder.append("  ".repeat(depth - 1));
+                }
+                builder.append("</");
+                builder.append(name.to_string());
+                builder.append(">");
+            }
+            Content::Text(utf8) => builder.append(Self::escape_text(utf8)),
+            Content::Comment(utf8) => {
+                builder.append("<!--");
+                builder.append(Self::escape_comment(utf8));
+                builder.append("-->");
+            }
+        }
+    }
+
+    /// Compare two trees structurally: element names, attributes (in any order), text, comments, and child order.
+    /// How text happens to be split across adjacent Text nodes doesn't matter, and neither does `NodeId` numbering
+    pub fn trees_equal(a: &Arena<Content>, b: &Arena<Content>) -> bool {
+        let root_of = |arena: &Arena<Content>| arena.iter().next().map(|n| arena.get_node_id(n).unwrap()); // first item == root
+        match (root_of(a), root_of(b)) {
+            (Some(a_root), Some(b_root)) => Self::nodes_equal(a, a_root, b, b_root),
+            (None, None) => true,
+            _ => false,
         }
     }
 
+    fn nodes_equal(a: &Arena<Content>, a_nid: NodeId, b: &Arena<Content>, b_nid: NodeId) -> bool {
+        let same_node = match (a.get(a_nid).unwrap().get(), b.get(b_nid).unwrap().get()) {
+            (Content::Root, Content::Root) => true,
+            (Content::Element(a_name), Content::Element(b_name)) => a_name == b_name,
+            _ => false,
+        };
+        if !same_node {
+            return false;
+        }
+        let a_attrs = Self::get_attributes(a, a_nid).into_iter().collect::<HashMap<_, _>>();
+        let b_attrs = Self::get_attributes(b, b_nid).into_iter().collect::<HashMap<_, _>>();
+        if a_attrs != b_attrs {
+            return false;
+        }
+        let a_children = Self::comparable_children(a, a_nid);
+        let b_children = Self::comparable_children(b, b_nid);
+        a_children.len() == b_children.len() && a_children.iter().zip(b_children.iter()).all(|pair| match pair {
+            (ComparableChild::Text(a_txt), ComparableChild::Text(b_txt)) => a_txt == b_txt,
+            (ComparableChild::Comment(a_txt), ComparableChild::Comment(b_txt)) => a_txt == b_txt,
+            (ComparableChild::Element(a_child), ComparableChild::Element(b_child)) => Self::nodes_equal(a, *a_child, b, *b_child),
+            _ => false,
+        })
+    }
+
+    /// non-attribute children, with adjacent text joined up
+    fn comparable_children(arena: &Arena<Content>, nid: NodeId) -> Vec<ComparableChild> {
+        let mut children: Vec<ComparableChild> = Vec::new();
+        for child in nid.children(arena) {
+            match arena.get(child).unwrap().get() {
+                Content::Text(txt) => match children.last_mut() {
+                    Some(ComparableChild::Text(run)) => run.push_str(txt),
+                    _ => children.push(ComparableChild::Text(txt.clone())),
+                },
+                Content::Comment(txt) => children.push(ComparableChild::Comment(txt.clone())),
+                Content::Attribute(..) => {},
+                Content::Root | Content::Element(_) => children.push(ComparableChild::Element(child)),
+            }
+        }
+        children
+    }
+
+    /// Walk the whole tree depth-first, in document order, without needing to know indextree
+    /// The synthetic `Content::Root` is skipped; the document element is at depth 0
+    pub fn walk(arena: &Arena<Content>) -> TreeWalk<'_> {
+        let mut walk = TreeWalk { arena, stack: Vec::new() };
+        if let Some(root) = arena.iter().next() { // first item == root
+            walk.push_children(0, arena.get_node_id(root).unwrap());
+        }
+        walk
+    }
+
+    /// Copy the tree into nested `ContentTree`s, starting from the `Content::Root`, so it can go through any serde format
+    /// As with `walk`, the leftover text nodes beneath attributes are left out
+    #[cfg(feature = "serde")]
+    pub fn tree_to_serde(arena: &Arena<Content>) -> ContentTree {
+        fn recurse(arena: &Arena<Content>, nid: NodeId) -> ContentTree {
+            let content = arena.get(nid).unwrap().get().clone();
+            let children = if content.is_attr() {
+                Vec::new()
+            } else {
+                nid.children(arena).map(|child| recurse(arena, child)).collect()
+            };
+            ContentTree { content, children }
+        }
+        match arena.iter().next() { // first item == root
+            Some(root) => recurse(arena, arena.get_node_id(root).unwrap()),
+            None => ContentTree { content: Content::Root, children: Vec::new() },
+        }
+    }
+
+    /// The exact slice of `original_input` that a node of the last returned tree covered, including muted characters.
+    /// Unlike the node's text descendants, nothing is left out. `original_input` must be the text that was parsed
+    /// (after any preprocessing). None if the node isn't from the last parse
+    pub fn matched_text(&self, arena: &Arena<Content>, nid: NodeId, original_input: &str) -> Option<String> {
+        arena.get(nid)?;
+        let span = self.node_spans.get(&nid)?;
+        Some(original_input.chars().skip(span.start).take(span.len()).collect())
+    }
+
     /// Helper function for working with indextree
     /// Given a `NodeId` (that should be an element) get all the Attribute nodes
-    /// Returns an easily-digestiable `HashMap` of Name -> Value
-    pub fn get_attributes(arena: &Arena<Content>, elem: NodeId) -> HashMap<String, String> {
+    /// Returns an easily-digestiable map of Name -> Value, which iterates in document order
+    pub fn get_attributes(arena: &Arena<Content>, elem: NodeId) -> IndexMap<String, String> {
         elem.children(arena)
             // from NodeId to Content...
             .map(|n| arena.get(n).unwrap().get())
             // and only Content::Attribute...
             .filter(|c| matches!(*c, Content::Attribute(..)))
-            // and pair it up to put in a HashMap...
+            // and pair it up to put in a map...
             .map(|node| (node.get_name().unwrap(), node.get_value().unwrap()))
             .collect()
     }
@@ -715,3 +2034,1069 @@ impl Parser {
             .collect()
     }
 }
+
+
+#[test]
+fn test_duplicate_attributes() {
+    use crate::grammar::RuleContext;
+
+    // doc = x, x.
+    // @x = ["a"-"z"].
+    let mut g = Grammar::new();
+    let ctx = RuleContext::new("doc");
+    g.define("doc", ctx.seq().nt("x").nt("x"));
+    let ctx = RuleContext::new("x");
+    g.mark_define(Mark::Attr, "x", ctx.seq().ch_range('a', 'z'));
+
+    // error by default
+    let mut parser = Parser::new(g.clone());
+    let result = parser.parse("ab");
+    assert!(matches!(result, Err(ParseError::DynamicError(_))));
+
+    let mut parser = Parser::new(g.clone());
+    parser.set_duplicate_attr_policy(DuplicateAttrPolicy::KeepFirst);
+    let arena = parser.parse("ab").unwrap();
+    assert_eq!(Parser::tree_to_testfmt(&arena), r#"<doc x="a"></doc>"#);
+
+    let mut parser = Parser::new(g);
+    parser.set_duplicate_attr_policy(DuplicateAttrPolicy::KeepLast);
+    let arena = parser.parse("ab").unwrap();
+    assert_eq!(Parser::tree_to_testfmt(&arena), r#"<doc x="b"></doc>"#);
+}
+
+#[test]
+fn test_preprocessor() {
+    use crate::grammar::RuleContext;
+
+    let mut g = Grammar::new();
+    let ctx = RuleContext::new("doc");
+    g.define("doc", ctx.seq().ch('A').ch('B'));
+
+    let mut parser = Parser::new(g.clone());
+    let unprocessed = parser.parse("ab").map(|arena| Parser::tree_to_testfmt(&arena)).unwrap_or_default();
+    assert_eq!(unprocessed, "");
+
+    let mut parser = Parser::new(g);
+    parser.set_preprocessor(Box::new(|s: &str| s.to_uppercase()));
+    let arena = parser.parse("ab").unwrap();
+    assert_eq!(Parser::tree_to_testfmt(&arena), "<doc>AB</doc>");
+}
+
+#[test]
+fn test_walk() {
+    use crate::builtin_grammars::{ParserTestSet, SmokeNT};
+
+    let mut parser = Parser::new(SmokeNT{}.get_grammar());
+    let arena = parser.parse("ab").unwrap();
+    let walked = Parser::walk(&arena)
+        .map(|(depth, content)| (depth, content.get_name().or_else(|| content.get_value()).unwrap()))
+        .collect::<Vec<_>>();
+    let expected = vec![(0, "doc"), (1, "a"), (2, "a"), (1, "b"), (2, "b")];
+    assert_eq!(walked, expected.into_iter().map(|(d, s)| (d, s.to_string())).collect::<Vec<_>>());
+}
+
+#[test]
+fn test_attr_within_mute() {
+    use crate::grammar::RuleContext;
+
+    // doc = x. -x = y. @y = "a".
+    // the muted x drops its own tag, but y still surfaces as an attribute on doc
+    let mut g = Grammar::new();
+    let ctx = RuleContext::new("doc");
+    g.define("doc", ctx.seq().nt("x"));
+    let ctx = RuleContext::new("x");
+    g.mark_define(Mark::Mute, "x", ctx.seq().nt("y"));
+    let ctx = RuleContext::new("y");
+    g.mark_define(Mark::Attr, "y", ctx.seq().ch('a'));
+    let mut parser = Parser::new(g);
+    let arena = parser.parse("a").unwrap();
+    assert_eq!(Parser::tree_to_testfmt(&arena), r#"<doc y="a"></doc>"#);
+
+    // doc = -y. @y = "a".
+    // a mark on the reference overrides the @ on the definition
+    let mut g = Grammar::new();
+    let ctx = RuleContext::new("doc");
+    g.define("doc", ctx.seq().mark_nt("y", Mark::Mute));
+    let ctx = RuleContext::new("y");
+    g.mark_define(Mark::Attr, "y", ctx.seq().ch('a'));
+    let mut parser = Parser::new(g);
+    let arena = parser.parse("a").unwrap();
+    assert_eq!(Parser::tree_to_testfmt(&arena), "<doc>a</doc>");
+}
+
+#[test]
+fn test_deep_attr_hoisting() {
+    use crate::grammar::RuleContext;
+
+    // doc = "t", a. -a = "p", b, "q". -b = "x", c, "y". @c = "v".
+    // c comes after text, two muted levels down, and still lands in doc's start tag
+    let mut g = Grammar::new();
+    let ctx = RuleContext::new("doc");
+    g.define("doc", ctx.seq().ch('t').nt("a"));
+    let ctx = RuleContext::new("a");
+    g.mark_define(Mark::Mute, "a", ctx.seq().ch('p').nt("b").ch('q'));
+    let ctx = RuleContext::new("b");
+    g.mark_define(Mark::Mute, "b", ctx.seq().ch('x').nt("c").ch('y'));
+    let ctx = RuleContext::new("c");
+    g.mark_define(Mark::Attr, "c", ctx.seq().ch('v'));
+    let mut parser = Parser::new(g);
+    let arena = parser.parse("tpxvyq").unwrap();
+    assert_eq!(Parser::tree_to_testfmt(&arena), r#"<doc c="v">tpxyq</doc>"#);
+
+    // doc = "t", e. e = "s", -g. g = "x", c. @c = d. d = "v", f. @f = "w".
+    // c stops at e, the nearest element; within an attribute, everything below it is just text
+    let mut g = Grammar::new();
+    let ctx = RuleContext::new("doc");
+    g.define("doc", ctx.seq().ch('t').nt("e"));
+    let ctx = RuleContext::new("e");
+    g.define("e", ctx.seq().ch('s').mark_nt("g", Mark::Mute));
+    let ctx = RuleContext::new("g");
+    g.define("g", ctx.seq().ch('x').nt("c"));
+    let ctx = RuleContext::new("c");
+    g.mark_define(Mark::Attr, "c", ctx.seq().nt("d"));
+    let ctx = RuleContext::new("d");
+    g.define("d", ctx.seq().ch('v').nt("f"));
+    let ctx = RuleContext::new("f");
+    g.mark_define(Mark::Attr, "f", ctx.seq().ch('w'));
+    let mut parser = Parser::new(g);
+    let arena = parser.parse("tsxvw").unwrap();
+    assert_eq!(Parser::tree_to_testfmt(&arena), r#"<doc>t<e c="vw">sx</e></doc>"#);
+}
+
+#[test]
+fn test_attr_and_element_refs() {
+    use crate::grammar::RuleContext;
+
+    // doc = @x, x. x = "a".
+    // the mark is resolved per reference, so the same rule can surface as both an attribute and an element
+    let mut g = Grammar::new();
+    let ctx = RuleContext::new("doc");
+    g.define("doc", ctx.seq().mark_nt("x", Mark::Attr).nt("x"));
+    let ctx = RuleContext::new("x");
+    g.define("x", ctx.seq().ch('a'));
+    let mut parser = Parser::new(g);
+    let arena = parser.parse("aa").unwrap();
+    assert_eq!(Parser::tree_to_testfmt(&arena), r#"<doc x="a"><x>a</x></doc>"#);
+
+    // doc = x, ^x. @x = "a".
+    // with @ on the definition, only an explicit ^ on the reference gets an element back
+    let mut g = Grammar::new();
+    let ctx = RuleContext::new("doc");
+    g.define("doc", ctx.seq().nt("x").mark_nt("x", Mark::Unmute));
+    let ctx = RuleContext::new("x");
+    g.mark_define(Mark::Attr, "x", ctx.seq().ch('a'));
+    let mut parser = Parser::new(g);
+    let arena = parser.parse("aa").unwrap();
+    assert_eq!(Parser::tree_to_testfmt(&arena), r#"<doc x="a"><x>a</x></doc>"#);
+}
+
+#[test]
+fn test_max_depth() {
+    use crate::grammar::RuleContext;
+
+    // doc = "a", doc; "a".
+    let mut g = Grammar::new();
+    let ctx = RuleContext::new("doc");
+    g.define("doc", ctx.seq().ch('a').nt("doc"));
+    g.define("doc", ctx.seq().ch('a'));
+
+    let tall = "a".repeat(100);
+    let mut parser = Parser::new(g.clone());
+    parser.set_max_depth(50);
+    assert!(matches!(parser.parse(&tall), Err(ParseError::DynamicError(_))));
+
+    let mut parser = Parser::new(g);
+    parser.set_max_depth(50);
+    let arena = parser.parse("aaa").unwrap();
+    assert_eq!(Parser::tree_to_testfmt(&arena), "<doc>a<doc>a<doc>a</doc></doc></doc>");
+}
+
+#[test]
+fn test_default_max_depth() {
+    use crate::grammar::RuleContext;
+
+    // as deep as the default allows still fits on a test thread's default stack; one level more is an error, not an overflow
+    // doc = item. item = "(", item, ")"; "x".
+    let mut g = Grammar::new();
+    let ctx = RuleContext::new("doc");
+    g.define("doc", ctx.seq().nt("item"));
+    let ctx = RuleContext::new("item");
+    g.define("item", ctx.seq().ch('(').nt("item").ch(')'));
+    g.define("item", ctx.seq().ch('x'));
+
+    let nested = |depth: usize| format!("{}x{}", "(".repeat(depth), ")".repeat(depth));
+    let mut parser = Parser::new(g);
+    let arena = parser.parse(&nested(DEFAULT_MAX_DEPTH - 1)).unwrap();
+    assert_eq!(arena.iter().filter(|n| n.get().is_elem()).count(), DEFAULT_MAX_DEPTH + 1);
+    assert!(matches!(parser.parse(&nested(DEFAULT_MAX_DEPTH)), Err(ParseError::DynamicError(msg)) if msg.contains("max nesting")));
+}
+
+#[test]
+fn test_cyclic_derivations() {
+    use crate::grammar::RuleContext;
+
+    // doc = doc; "a".
+    let mut g = Grammar::new();
+    let ctx = RuleContext::new("doc");
+    g.define("doc", ctx.seq().nt("doc"));
+    g.define("doc", ctx.seq().ch('a'));
+    let mut parser = Parser::new(g);
+    let arena = parser.parse("a").unwrap();
+    assert_eq!(Parser::tree_to_testfmt(&arena), "<doc>a</doc>");
+
+    // doc = a; b. a = doc; "a". b = "a".
+    // the first choice for a, doc, is fine on its own, but not underneath doc
+    let mut g = Grammar::new();
+    let ctx = RuleContext::new("doc");
+    g.define("doc", ctx.seq().nt("a"));
+    g.define("doc", ctx.seq().nt("b"));
+    let ctx = RuleContext::new("a");
+    g.define("a", ctx.seq().nt("doc"));
+    g.define("a", ctx.seq().ch('a'));
+    let ctx = RuleContext::new("b");
+    g.define("b", ctx.seq().ch('a'));
+    let mut parser = Parser::new(g);
+    let arena = parser.parse("a").unwrap();
+    assert_eq!(Parser::tree_to_testfmt(&arena), "<doc><a>a</a></doc>");
+
+    // doc = (doc; "a")*.   (through hidden, synthesized rules)
+    let mut g = Grammar::new();
+    let ctx = RuleContext::new("doc");
+    g.define("doc", ctx.seq().repeat0(ctx.seq().alts(vec![ctx.seq().nt("doc"), ctx.seq().ch('a')])));
+    let mut parser = Parser::new(g);
+    let arena = parser.parse("aa").unwrap();
+    assert_eq!(Parser::tree_to_testfmt(&arena), "<doc>aa</doc>");
+}
+
+#[test]
+fn test_rule_task_counts() {
+    use crate::builtin_grammars::{ParserTestSet, SmokeWiki};
+
+    let mut parser = Parser::new(SmokeWiki{}.get_grammar());
+    let input = vec!["1"; 30].join("+");
+    parser.parse(&input).unwrap();
+    let counts = parser.rule_task_counts();
+    let total = counts.values().sum::<usize>();
+    assert_eq!(total, parser.traces.arena.len());
+    // the left-recursive S and M, which get resumed after every "+" and every completed term, dominate
+    let mut busiest = counts.iter().collect::<Vec<_>>();
+    busiest.sort_by_key(|(_, count)| std::cmp::Reverse(**count));
+    let mut top_two = busiest.iter().take(2).map(|(name, _)| name.as_str()).collect::<Vec<_>>();
+    top_two.sort_unstable();
+    assert_eq!(top_two, vec!["M", "S"], "{counts:?}");
+    assert!((counts["S"] + counts["M"]) * 2 > total, "{counts:?}");
+}
+
+#[test]
+fn test_flat_repetition() {
+    use crate::builtin_grammars::{ParserTestSet, SmokeStar};
+
+    // doc = "a"*.
+    let mut parser = Parser::new(SmokeStar{}.get_grammar());
+    let arena = parser.parse("aaaa").unwrap();
+    assert_eq!(Parser::tree_to_testfmt(&arena), "<doc>aaaa</doc>");
+    assert_eq!(arena.len(), 3); // root, doc, and one text node
+
+    // the chain of synthesized rules behind the repetition doesn't count towards the nesting limit
+    let long = "a".repeat(200);
+    let mut parser = Parser::new(SmokeStar{}.get_grammar());
+    parser.set_max_depth(10);
+    let arena = parser.parse(&long).unwrap();
+    assert_eq!(Parser::tree_to_testfmt(&arena), format!("<doc>{long}</doc>"));
+}
+
+#[test]
+fn test_reparse() {
+    use crate::builtin_grammars::{ParserTestSet, SmokeStar, SmokeWiki};
+
+    // appending a character
+    let mut parser = Parser::new(SmokeStar{}.get_grammar());
+    parser.parse("aaa").unwrap();
+    let incremental = parser.reparse("aaaa", 3..3).unwrap();
+    let full = Parser::new(SmokeStar{}.get_grammar()).parse("aaaa").unwrap();
+    assert_eq!(Parser::tree_to_testfmt(&incremental), Parser::tree_to_testfmt(&full));
+    assert_eq!(Parser::tree_to_testfmt(&incremental), "<doc>aaaa</doc>");
+
+    // replacing a character
+    let mut parser = Parser::new(SmokeWiki{}.get_grammar());
+    parser.parse("1+2*3").unwrap();
+    let incremental = parser.reparse("1+2+3", 3..4).unwrap();
+    let full = Parser::new(SmokeWiki{}.get_grammar()).parse("1+2+3").unwrap();
+    assert_eq!(Parser::tree_to_testfmt(&incremental), Parser::tree_to_testfmt(&full));
+}
+
+#[test]
+fn test_comment_content() {
+    use crate::builtin_grammars::{ParserTestSet, SmokeSeq};
+
+    let mut parser = Parser::new(SmokeSeq{}.get_grammar());
+    let mut arena = parser.parse("ab").unwrap();
+    let (_, doc) = Parser::get_child_elements(&arena, arena.get_node_id(arena.iter().next().unwrap()).unwrap())[0].clone();
+    let comment = arena.new_node(Content::Comment(" muted: -- ".to_string()));
+    doc.append(comment, &mut arena);
+    assert_eq!(Parser::tree_to_testfmt(&arena), "<doc>ab<!-- muted: - - --></doc>");
+}
+
+#[test]
+fn test_element_renames() {
+    use crate::builtin_grammars::{ParserTestSet, SmokeSeq};
+
+    let mut parser = Parser::new(SmokeSeq{}.get_grammar());
+    parser.set_element_renames(HashMap::from([(SmolStr::new("doc"), SmolStr::new("document"))]));
+    let arena = parser.parse("ab").unwrap();
+    assert_eq!(Parser::tree_to_testfmt(&arena), "<document>ab</document>");
+}
+
+#[test]
+fn test_matches_for() {
+    use crate::grammar::RuleContext;
+
+    // doc = number++",". number = ["0"-"9"]+.
+    let mut g = Grammar::new();
+    let ctx = RuleContext::new("doc");
+    g.define("doc", ctx.seq().repeat1_sep(ctx.seq().nt("number"), ctx.seq().ch(',')));
+    let ctx = RuleContext::new("number");
+    g.define("number", ctx.seq().repeat1(ctx.seq().ch_range('0', '9')));
+    let mut parser = Parser::new(g);
+    parser.parse("12,3").unwrap();
+
+    // "1" is a complete number too, even though the final parse uses "12"
+    assert_eq!(parser.matches_for("number"), vec![(0, 1), (0, 2), (3, 4)]);
+    assert_eq!(parser.matches_for("doc"), vec![(0, 1), (0, 2), (0, 4)]);
+    assert!(parser.matches_for("nosuchrule").is_empty());
+}
+
+#[test]
+fn test_attr_whitespace_normalization() {
+    use crate::grammar::RuleContext;
+
+    // doc = value. @value = [" "; "a"-"z"]+.
+    let mut g = Grammar::new();
+    let ctx = RuleContext::new("doc");
+    g.define("doc", ctx.seq().nt("value"));
+    let ctx = RuleContext::new("value");
+    g.mark_define(Mark::Attr, "value", ctx.seq().repeat1(ctx.seq().lit(crate::grammar::Lit::union().ch(' ').ch_range('a', 'z'))));
+
+    let mut parser = Parser::new(g.clone());
+    let arena = parser.parse("  a   b c ").unwrap();
+    assert_eq!(Parser::tree_to_testfmt(&arena), r#"<doc value="  a   b c "></doc>"#);
+
+    let mut parser = Parser::new(g);
+    parser.set_attr_whitespace_normalization(true);
+    let arena = parser.parse("  a   b c ").unwrap();
+    assert_eq!(Parser::tree_to_testfmt(&arena), r#"<doc value="a b c"></doc>"#);
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn test_tree_to_serde() {
+    use crate::grammar::RuleContext;
+
+    // doc = id, "-", x. @id = "a". x = "b".
+    let mut g = Grammar::new();
+    let ctx = RuleContext::new("doc");
+    g.define("doc", ctx.seq().nt("id").ch('-').nt("x"));
+    let ctx = RuleContext::new("id");
+    g.mark_define(Mark::Attr, "id", ctx.seq().ch('a'));
+    let ctx = RuleContext::new("x");
+    g.define("x", ctx.seq().ch('b'));
+    let mut parser = Parser::new(g);
+    let arena = parser.parse("a-b").unwrap();
+    assert_eq!(Parser::tree_to_testfmt(&arena), r#"<doc id="a">-<x>b</x></doc>"#);
+
+    let json = serde_json::to_value(Parser::tree_to_serde(&arena)).unwrap();
+    let expected = serde_json::json!({
+        "content": "Root",
+        "children": [{
+            "content": {"Element": "doc"},
+            "children": [
+                {"content": {"Attribute": ["id", "a"]}, "children": []},
+                {"content": {"Text": "-"}, "children": []},
+                {"content": {"Element": "x"}, "children": [{"content": {"Text": "b"}, "children": []}]},
+            ]
+        }]
+    });
+    assert_eq!(json, expected);
+}
+
+#[test]
+fn test_coverage() {
+    use crate::builtin_grammars::{ParserTestSet, SmokeNT};
+    use crate::grammar::RuleContext;
+
+    let mut parser = Parser::new(SmokeNT{}.get_grammar());
+    parser.parse("ab").unwrap();
+    let expected: HashSet<SmolStr> = ["doc", "a", "b"].into_iter().map(SmolStr::new).collect();
+    assert_eq!(parser.coverage(), expected);
+
+    // doc = a | c. a = "a". c = "c".
+    let mut g = Grammar::new();
+    let ctx = RuleContext::new("doc");
+    g.define("doc", ctx.seq().nt("a"));
+    g.define("doc", ctx.seq().nt("c"));
+    let ctx = RuleContext::new("a");
+    g.define("a", ctx.seq().ch('a'));
+    let ctx = RuleContext::new("c");
+    g.define("c", ctx.seq().ch('c'));
+    let mut parser = Parser::new(g);
+    parser.parse("a").unwrap();
+    assert!(parser.coverage().contains("a"));
+    assert!(!parser.coverage().contains("c"));
+}
+
+#[test]
+fn test_parse_from_subrule() {
+    use crate::grammar::RuleContext;
+
+    // expr = number, "+", number. number = digit+. digit = ["0"-"9"].
+    let mut g = Grammar::new();
+    let ctx = RuleContext::new("expr");
+    g.define("expr", ctx.seq().nt("number").ch('+').nt("number"));
+    let ctx = RuleContext::new("number");
+    g.define("number", ctx.seq().repeat1(ctx.seq().nt("digit")));
+    let ctx = RuleContext::new("digit");
+    g.define("digit", ctx.seq().ch_range('0', '9'));
+
+    let mut parser = Parser::new(g.clone());
+    let arena = parser.parse_from("42", "number").unwrap();
+    assert_eq!(Parser::tree_to_testfmt(&arena), "<number><digit>4</digit><digit>2</digit></number>");
+
+    let mut parser = Parser::new(g.clone());
+    assert!(matches!(parser.parse_from("42", "nosuchrule"), Err(ParseError::StaticError(_))));
+
+    // the grammar root still works as before
+    let mut parser = Parser::new(g);
+    let arena = parser.parse("1+2").unwrap();
+    assert_eq!(Parser::tree_to_testfmt(&arena), "<expr><number><digit>1</digit></number>+<number><digit>2</digit></number></expr>");
+}
+
+#[test]
+fn test_reused_parser() {
+    use crate::ixml_grammar::ixml_str_to_grammar;
+
+    let g = ixml_str_to_grammar(r#"expr = term++"+". term = digit+; "(", expr, ")". -digit = ["0"-"9"]."#).unwrap();
+    let inputs = ["12+(3+4)", "5", "(6)+78+9"];
+
+    let mut reused = Parser::new(g.clone());
+    for input in inputs {
+        let fresh = Parser::tree_to_testfmt(&Parser::new(g.clone()).parse(input).unwrap());
+        assert_eq!(Parser::tree_to_testfmt(&reused.parse(input).unwrap()), fresh);
+    }
+
+    // a failed parse leaves nothing behind either
+    assert!(reused.parse("1+").is_err());
+    let arena = reused.parse("1").unwrap();
+    assert_eq!(Parser::tree_to_testfmt(&arena), "<expr><term>1</term></expr>");
+
+    // an explicit reset clears the chart
+    reused.reset();
+    assert_eq!(reused.farthest_pos(), 0);
+    assert!(reused.chart_at(0).is_empty());
+}
+
+#[test]
+fn test_parse_from() {
+    use crate::ixml_grammar::ixml_grammar;
+
+    // the bootstrap grammar's hex rule is an attribute, but started from directly it makes an element
+    let mut parser = Parser::new(ixml_grammar());
+    let arena = parser.parse_from("1a2f", "hex").unwrap();
+    assert_eq!(Parser::tree_to_testfmt(&arena), "<hex>1a2f</hex>");
+
+    let mut parser = Parser::new(ixml_grammar());
+    assert!(matches!(parser.parse_from("1a2g", "hex"), Err(ParseError::DynamicError(_))));
+
+    let mut parser = Parser::new(ixml_grammar());
+    assert!(matches!(parser.parse_from("1a2f", "nosuchrule"), Err(ParseError::StaticError(_))));
+}
+
+#[test]
+fn test_repetition_policy() {
+    use crate::grammar::RuleContext;
+
+    // doc = x*, y*. x = "a". y = "a".
+    let mut g = Grammar::new();
+    let ctx = RuleContext::new("doc");
+    g.define("doc", ctx.seq().repeat0(ctx.seq().nt("x")).repeat0(ctx.seq().nt("y")));
+    let ctx = RuleContext::new("x");
+    g.define("x", ctx.seq().ch('a'));
+    let ctx = RuleContext::new("y");
+    g.define("y", ctx.seq().ch('a'));
+
+    let mut parser = Parser::new(g.clone());
+    let arena = parser.parse("aa").unwrap();
+    assert_eq!(Parser::tree_to_testfmt(&arena), "<doc><x>a</x><x>a</x></doc>");
+
+    let mut parser = Parser::new(g);
+    parser.set_repetition_policy(RepetitionPolicy::Lazy);
+    let arena = parser.parse("aa").unwrap();
+    assert_eq!(Parser::tree_to_testfmt(&arena), "<doc><y>a</y><y>a</y></doc>");
+}
+
+#[test]
+fn test_tree_to_xml() {
+    use crate::builtin_grammars::{ParserTestSet, SmokeNT};
+    use crate::grammar::RuleContext;
+
+    let mut parser = Parser::new(SmokeNT{}.get_grammar());
+    let arena = parser.parse("ab").unwrap();
+    let expected = indoc::indoc! {r#"
+        <?xml version="1.0" encoding="UTF-8"?>
+        <doc>
+          <a>a</a>
+          <b>b</b>
+        </doc>
+    "#};
+    assert_eq!(Parser::tree_to_xml(&arena, true), expected);
+    assert_eq!(Parser::tree_to_xml(&arena, false), "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<doc><a>a</a><b>b</b></doc>\n");
+
+    // doc = q, e, ["<&>"]+. @q = '"'. e = .
+    let mut g = Grammar::new();
+    let ctx = RuleContext::new("doc");
+    g.define("doc", ctx.seq().nt("q").nt("e").repeat1(ctx.seq().ch_in("<&>")));
+    let ctx = RuleContext::new("q");
+    g.mark_define(Mark::Attr, "q", ctx.seq().ch('"'));
+    let ctx = RuleContext::new("e");
+    g.define("e", ctx.seq());
+    let mut parser = Parser::new(g);
+    let arena = parser.parse("\"<&>").unwrap();
+    // with text of its own, doc's children stay put, even when pretty
+    let expected = "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<doc q=\"&quot;\"><e/>&lt;&amp;&gt;</doc>\n";
+    assert_eq!(Parser::tree_to_xml(&arena, true), expected);
+}
+
+#[test]
+fn test_escaping() {
+    use crate::grammar::RuleContext;
+    use crate::testsuite_utils::xml_canonicalize;
+
+    // doc = amp, ["a"-"c"; "&<>"]+. @amp = "&".
+    let mut g = Grammar::new();
+    let ctx = RuleContext::new("doc");
+    g.define("doc", ctx.seq().nt("amp").repeat1(ctx.seq().lit(Lit::union().ch_range('a', 'c').ch_in("&<>"))));
+    let ctx = RuleContext::new("amp");
+    g.mark_define(Mark::Attr, "amp", ctx.seq().ch('&'));
+    let mut parser = Parser::new(g);
+    let arena = parser.parse("&a&b<c>").unwrap();
+    let testfmt = Parser::tree_to_testfmt(&arena);
+    assert_eq!(testfmt, r#"<doc amp="&amp;">a&amp;b&lt;c&gt;</doc>"#);
+    // well-formed enough for an XML parser, which gets back the original characters
+    assert_eq!(xml_canonicalize(&testfmt), "<doc amp=\"&amp;\"\n>a&amp;b&lt;c></doc\n>");
+
+    // characters XML doesn't allow at all get replaced, and comments never hold "--" or end with "-"
+    assert_eq!(Parser::escape_text("a\u{0}\t\u{85}"), "a\u{FFFD}\t\u{85}");
+    assert_eq!(Parser::escape_attr("\u{1}\r\u{85}'"), "\u{FFFD}&#xD;&#x85;'");
+    assert_eq!(Parser::escape_comment("a--b"), "a- -b");
+    assert_eq!(Parser::escape_comment("a---"), "a- - - ");
+    assert_eq!(Parser::escape_comment("\u{B}"), "\u{FFFD}");
+}
+
+#[test]
+fn test_explain_failure() {
+    use crate::grammar::RuleContext;
+
+    // lines = line++newline. line = digit++"+". digit = ["0"-"9"].
+    let mut g = Grammar::new();
+    let ctx = RuleContext::new("lines");
+    g.define("lines", ctx.seq().repeat1_sep(ctx.seq().nt("line"), ctx.seq().newline()));
+    let ctx = RuleContext::new("line");
+    g.define("line", ctx.seq().repeat1_sep(ctx.seq().nt("digit"), ctx.seq().ch('+')));
+    let ctx = RuleContext::new("digit");
+    g.define("digit", ctx.seq().ch_range('0', '9'));
+
+    let mut parser = Parser::new(g.clone());
+    let _ = parser.parse("1+2\n+3");
+    assert_eq!(parser.explain_failure("1+2\n+3"), "at line 2 col 1: unexpected '+', expected digit");
+
+    let mut parser = Parser::new(g.clone());
+    let _ = parser.parse("1+2\n3x");
+    assert_eq!(parser.explain_failure("1+2\n3x"), r"at line 2 col 2: unexpected 'x', expected '+' or '\r' or '\n'");
+
+    let mut parser = Parser::new(g.clone());
+    let _ = parser.parse("1+");
+    assert_eq!(parser.explain_failure("1+"), "at line 1 col 3: unexpected end of input, expected digit");
+
+    // the same account comes back as the error
+    let mut parser = Parser::new(g);
+    let err = parser.parse("1+2\n3x").unwrap_err();
+    assert_eq!(err.to_string(), r"DynamicError: no parse: at line 2 col 2: unexpected 'x', expected '+' or '\r' or '\n'");
+}
+
+#[test]
+fn test_trailing_input() {
+    // doc = "a".
+    let mut g = Grammar::new();
+    let ctx = crate::grammar::RuleContext::new("doc");
+    g.define("doc", ctx.seq().ch('a'));
+    let mut parser = Parser::new(g);
+    let err = parser.parse("ab").unwrap_err();
+    assert_eq!(err.to_string(), "DynamicError: no parse: at line 1 col 2: unexpected 'b'");
+}
+
+#[test]
+fn test_attribute_order() {
+    use crate::grammar::RuleContext;
+
+    // doc = zed, alpha, mid. @zed = "z". @alpha = "a". @mid = "m".
+    let mut g = Grammar::new();
+    let ctx = RuleContext::new("doc");
+    g.define("doc", ctx.seq().nt("zed").nt("alpha").nt("mid"));
+    for (name, ch) in [("zed", 'z'), ("alpha", 'a'), ("mid", 'm')] {
+        let ctx = RuleContext::new(name);
+        g.mark_define(Mark::Attr, name, ctx.seq().ch(ch));
+    }
+    let mut parser = Parser::new(g);
+    let arena = parser.parse("zam").unwrap();
+    let doc = arena.get_node_id(arena.iter().nth(1).unwrap()).unwrap();
+    let attrs = Parser::get_attributes(&arena, doc);
+    assert_eq!(attrs.iter().map(|(k, v)| (k.as_str(), v.as_str())).collect::<Vec<_>>(), vec![("zed", "z"), ("alpha", "a"), ("mid", "m")]);
+    assert_eq!(attrs["alpha"], "a");
+}
+
+#[test]
+fn test_deterministic_ambiguity() {
+    use crate::grammar::RuleContext;
+
+    // doc = a | b. a = c. c = "x". b = "x".
+    // b completes first (it's a shorter path), but a is defined first, so a wins
+    let mut g = Grammar::new();
+    let ctx = RuleContext::new("doc");
+    g.define("doc", ctx.seq().nt("a"));
+    g.define("doc", ctx.seq().nt("b"));
+    let ctx = RuleContext::new("a");
+    g.define("a", ctx.seq().nt("c"));
+    let ctx = RuleContext::new("c");
+    g.define("c", ctx.seq().ch('x'));
+    let ctx = RuleContext::new("b");
+    g.define("b", ctx.seq().ch('x'));
+
+    for _ in 0..10 {
+        let mut parser = Parser::new(g.clone());
+        let arena = parser.parse("x").unwrap();
+        assert_eq!(Parser::tree_to_testfmt(&arena), "<doc><a><c>x</c></a></doc>");
+    }
+}
+
+#[test]
+fn test_matched_text() {
+    use crate::grammar::RuleContext;
+
+    // doc = item, -",", item. item = ["a"-"z"]+.
+    let mut g = Grammar::new();
+    let ctx = RuleContext::new("doc");
+    g.define("doc", ctx.seq().nt("item").mark_ch(',', TMark::Mute).nt("item"));
+    let ctx = RuleContext::new("item");
+    g.define("item", ctx.seq().repeat1(ctx.seq().ch_range('a', 'z')));
+
+    let input = "ab,cd";
+    let mut parser = Parser::new(g);
+    let arena = parser.parse(input).unwrap();
+    let root = arena.iter().next().map(|n| arena.get_node_id(n).unwrap()).unwrap();
+    let doc = Parser::get_child_elements_named(&arena, root, "doc")[0];
+
+    let text_content = doc.descendants(&arena)
+        .filter_map(|n| match arena.get(n).unwrap().get() {
+            Content::Text(t) => Some(t.as_str()),
+            _ => None,
+        })
+        .collect::<String>();
+    assert_eq!(text_content, "abcd");
+    assert_eq!(parser.matched_text(&arena, doc, input).unwrap(), "ab,cd");
+
+    let items = Parser::get_child_elements_named(&arena, doc, "item");
+    assert_eq!(parser.matched_text(&arena, items[1], input).unwrap(), "cd");
+}
+
+#[test]
+fn test_attribute_root_rule() {
+    use crate::grammar::RuleContext;
+
+    // @doc = "a".
+    let mut g = Grammar::new();
+    let ctx = RuleContext::new("doc");
+    g.mark_define(Mark::Attr, "doc", ctx.seq().ch('a'));
+    let mut parser = Parser::new(g);
+    match parser.parse("a") {
+        Err(ParseError::StaticError(msg)) => assert_eq!(msg, "root rule cannot be an attribute"),
+        other => panic!("expected a static error, got {other:?}"),
+    }
+}
+
+#[test]
+fn test_reject_ambiguous() {
+    use crate::grammar::RuleContext;
+
+    // doc = a | b. a = "x". b = "x".
+    let mut g = Grammar::new();
+    let ctx = RuleContext::new("doc");
+    g.define("doc", ctx.seq().nt("a"));
+    g.define("doc", ctx.seq().nt("b"));
+    let ctx = RuleContext::new("a");
+    g.define("a", ctx.seq().ch('x'));
+    let ctx = RuleContext::new("b");
+    g.define("b", ctx.seq().ch('x'));
+    let mut parser = Parser::new(g);
+    parser.set_reject_ambiguous(true);
+    let err = parser.parse("x").unwrap_err();
+    assert!(matches!(err, ParseError::DynamicError(_)));
+    assert_eq!(err.code(), Some(ErrorCode::D03));
+    assert_eq!(ParseError::dynamic_err("no parse").code(), None);
+
+    // doc = "x"*, "y".
+    let mut g = Grammar::new();
+    let ctx = RuleContext::new("doc");
+    g.define("doc", ctx.seq().repeat0(ctx.seq().ch('x')).ch('y'));
+    let mut parser = Parser::new(g);
+    parser.set_reject_ambiguous(true);
+    let arena = parser.parse("xxxy").unwrap();
+    assert_eq!(Parser::tree_to_testfmt(&arena), "<doc>xxxy</doc>");
+}
+
+#[test]
+fn test_empty_input() {
+    use crate::grammar::RuleContext;
+
+    let ctx = RuleContext::new("doc");
+    let cases = [
+        ("doc = \"a\"?.", ctx.seq().opt(ctx.seq().ch('a')), "<doc></doc>"),
+        ("doc = \"a\"*.", ctx.seq().repeat0(ctx.seq().ch('a')), "<doc></doc>"),
+        ("doc = \"a\"**\",\".", ctx.seq().repeat0_sep(ctx.seq().ch('a'), ctx.seq().ch(',')), "<doc></doc>"),
+        ("doc = \"a\"+.", ctx.seq().repeat1(ctx.seq().ch('a')), ""),
+        ("doc = \"a\"++\",\".", ctx.seq().repeat1_sep(ctx.seq().ch('a'), ctx.seq().ch(',')), ""),
+        ("doc = a?, a*. a = \"a\".", ctx.seq().opt(ctx.seq().nt("a")).repeat0(ctx.seq().nt("a")), "<doc></doc>"),
+    ];
+    for (ixml, sb, expected) in cases {
+        let mut g = Grammar::new();
+        g.define("doc", sb);
+        let a_ctx = RuleContext::new("a");
+        g.define("a", a_ctx.seq().ch('a'));
+        let mut parser = Parser::new(g.clone());
+        let output = parser.parse("").map(|arena| Parser::tree_to_testfmt(&arena)).unwrap_or_default();
+        assert_eq!(output, expected, "{ixml}");
+        let parse_count = if expected.is_empty() { 0 } else { 1 };
+        assert_eq!(Parser::new(g).count_parses(""), parse_count, "{ixml}");
+    }
+}
+
+#[test]
+fn test_grammar_accessor() {
+    use crate::grammar::RuleContext;
+
+    // doc = a. a = "a".
+    let mut g = Grammar::new();
+    let ctx = RuleContext::new("doc");
+    g.define("doc", ctx.seq().nt("a"));
+    let ctx = RuleContext::new("a");
+    g.define("a", ctx.seq().ch('a'));
+    let parser = Parser::new(g);
+    assert_eq!(parser.grammar().get_rule_count(), 2);
+    assert_eq!(parser.grammar().get_root_definition_name(), Some(String::from("doc")));
+}
+
+#[test]
+fn test_count_parses() {
+    use crate::grammar::RuleContext;
+
+    // doc = a | b. a = "x". b = "x", "y"?.
+    let mut g = Grammar::new();
+    let ctx = RuleContext::new("doc");
+    g.define("doc", ctx.seq().nt("a"));
+    g.define("doc", ctx.seq().nt("b"));
+    let ctx = RuleContext::new("a");
+    g.define("a", ctx.seq().ch('x'));
+    let ctx = RuleContext::new("b");
+    g.define("b", ctx.seq().ch('x').opt(ctx.seq().ch('y')));
+    assert_eq!(Parser::new(g.clone()).count_parses("z"), 0);
+    assert_eq!(Parser::new(g.clone()).count_parses("xyy"), 0);
+    assert_eq!(Parser::new(g.clone()).count_parses("xy"), 1);
+    assert_eq!(Parser::new(g).count_parses("x"), 2);
+
+    // doc = "x"*, "x"*.
+    let mut g = Grammar::new();
+    let ctx = RuleContext::new("doc");
+    g.define("doc", ctx.seq().repeat0(ctx.seq().ch('x')).repeat0(ctx.seq().ch('x')));
+    assert_eq!(Parser::new(g).count_parses("xx"), 3);
+}
+
+#[test]
+fn test_map_element() {
+    use crate::grammar::RuleContext;
+
+    // doc = number, -",", number. number = ["0"-"9"]+.
+    let mut g = Grammar::new();
+    let ctx = RuleContext::new("doc");
+    g.define("doc", ctx.seq().nt("number").mark_ch(',', TMark::Mute).nt("number"));
+    let ctx = RuleContext::new("number");
+    g.define("number", ctx.seq().repeat1(ctx.seq().ch_range('0', '9')));
+
+    let mut parser = Parser::new(g);
+    parser.map_element("number", Box::new(|txt| {
+        let n: u32 = txt.parse().unwrap();
+        Content::Text(n.to_string())
+    }));
+    let arena = parser.parse("007,42").unwrap();
+    assert_eq!(Parser::tree_to_testfmt(&arena), "<doc><number>7</number><number>42</number></doc>");
+}
+
+#[test]
+fn test_merge_text_nodes() {
+    use crate::grammar::RuleContext;
+
+    // doc = "a"*.
+    let mut g = Grammar::new();
+    let ctx = RuleContext::new("doc");
+    g.define("doc", ctx.seq().repeat0(ctx.seq().ch('a')));
+
+    let input = "a".repeat(100);
+    let mut parser = Parser::new(g);
+    let arena = parser.parse(&input).unwrap();
+    assert_eq!(Parser::tree_to_testfmt(&arena), format!("<doc>{input}</doc>"));
+    let text_nodes = Parser::walk(&arena)
+        .filter(|(_, content)| matches!(content, Content::Text(_)))
+        .count();
+    assert_eq!(text_nodes, 1);
+}
+
+#[test]
+fn test_text_run_single_node() {
+    use crate::grammar::RuleContext;
+
+    // doc = "abc", -"-", "def".
+    let mut g = Grammar::new();
+    let ctx = RuleContext::new("doc");
+    g.define("doc", ctx.seq().ch('a').ch('b').ch('c').mark_ch('-', TMark::Mute).ch('d').ch('e').ch('f'));
+
+    let mut parser = Parser::new(g);
+    let arena = parser.parse("abc-def").unwrap();
+    assert_eq!(Parser::tree_to_testfmt(&arena), "<doc>abcdef</doc>");
+    // Root, doc, and one text node
+    assert_eq!(arena.len(), 3);
+
+    // the same straight out of unpacking, before any merging of text nodes
+    let mut arena = Arena::new();
+    let root = arena.new_node(Content::Root);
+    parser.unpack_parse_tree_internal(&mut arena, "doc", 0, 7, root, &mut HashMap::new()).unwrap();
+    assert_eq!(arena.len(), 3);
+    assert_eq!(Parser::tree_to_testfmt(&arena), "<doc>abcdef</doc>");
+}
+
+#[test]
+fn test_attribute_control_chars() {
+    use crate::grammar::RuleContext;
+
+    // doc = note. @note = ["a&<"#9#C"]+.
+    let mut g = Grammar::new();
+    let ctx = RuleContext::new("doc");
+    g.define("doc", ctx.seq().nt("note"));
+    let ctx = RuleContext::new("note");
+    g.mark_define(Mark::Attr, "note", ctx.seq().repeat1(ctx.seq().ch_in("a&<\"\t\u{C}")));
+
+    let mut parser = Parser::new(g);
+    let arena = parser.parse("a\u{C}&<\"\t").unwrap();
+    // a form feed isn't allowed anywhere in XML 1.0, not even as a character reference
+    assert_eq!(Parser::tree_to_testfmt(&arena), "<doc note=\"a\u{FFFD}&amp;&lt;&quot;&#x9;\"></doc>");
+}
+
+#[test]
+fn test_trees_equal() {
+    use crate::builtin_grammars::{ParserTestSet, SmokeWiki};
+
+    let parsed = Parser::new(SmokeWiki{}.get_grammar()).parse("1+2").unwrap();
+
+    // <doc><S><S><M><T>1</T></M></S>+<M><T>2</T></M></S></doc>, built by hand with the text split differently
+    let mut built = Arena::new();
+    let root = built.new_node(Content::Root);
+    let mut add = |parent: NodeId, content: Content| {
+        let nid = built.new_node(content);
+        parent.append(nid, &mut built);
+        nid
+    };
+    let doc = add(root, Content::Element("doc".to_string()));
+    let s = add(doc, Content::Element("S".to_string()));
+    let inner_s = add(s, Content::Element("S".to_string()));
+    let m = add(inner_s, Content::Element("M".to_string()));
+    let t = add(m, Content::Element("T".to_string()));
+    add(t, Content::Text("1".to_string()));
+    add(s, Content::Text("+".to_string()));
+    let m = add(s, Content::Element("M".to_string()));
+    let t = add(m, Content::Element("T".to_string()));
+    add(t, Content::Text("".to_string()));
+    add(t, Content::Text("2".to_string()));
+    assert!(Parser::trees_equal(&parsed, &built));
+
+    let other = Parser::new(SmokeWiki{}.get_grammar()).parse("1+3").unwrap();
+    assert!(!Parser::trees_equal(&parsed, &other));
+}
+
+#[test]
+fn test_chart_to_html() {
+    use crate::builtin_grammars::{ParserTestSet, SmokeWiki};
+
+    let mut parser = Parser::new(SmokeWiki{}.get_grammar());
+    parser.parse("1+2").unwrap();
+    let html = parser.chart_to_html("1+2");
+    let row0 = html.lines().find(|line| line.starts_with("<tr id=\"pos-0\">")).unwrap();
+    assert!(row0.contains("<td>0</td><td class=\"char\">1</td>"), "{row0}");
+    assert!(row0.contains("<div class=\"task\">doc 0..0: "), "{row0}");
+    let row3 = html.lines().find(|line| line.starts_with("<tr id=\"pos-3\">")).unwrap();
+    assert!(row3.contains("<div class=\"task done\">doc 0..3: "), "{row3}");
+}
+
+#[test]
+fn test_max_input_len() {
+    use crate::grammar::RuleContext;
+
+    // doc = "a"*.
+    let mut g = Grammar::new();
+    let ctx = RuleContext::new("doc");
+    g.define("doc", ctx.seq().repeat0(ctx.seq().ch('a')));
+    let mut parser = Parser::new(g.clone());
+    parser.set_max_input_len(5);
+    assert!(parser.parse("aaaaa").is_ok());
+
+    let mut parser = Parser::new(g);
+    parser.set_max_input_len(5);
+    match parser.parse("aaaaaa") {
+        Err(ParseError::StaticError(msg)) => assert_eq!(msg, "input too long"),
+        other => panic!("expected a static error, got {other:?}"),
+    }
+    // rejected before any parsing happened
+    assert!(parser.test_inspect_trace(None).is_empty());
+}
+
+#[test]
+fn test_parse_tokens() {
+    use crate::grammar::RuleContext;
+
+    #[derive(Clone)]
+    enum Tok {
+        Num(u32),
+        Plus,
+    }
+    impl Token for Tok {
+        fn as_char(&self) -> char {
+            match self {
+                Tok::Num(_) => 'n',
+                Tok::Plus => '+',
+            }
+        }
+        fn text(&self) -> String {
+            match self {
+                Tok::Num(n) => n.to_string(),
+                Tok::Plus => "+".to_string(),
+            }
+        }
+    }
+
+    // expr = num, (-"+", num)*. num = "n".
+    let mut g = Grammar::new();
+    let ctx = RuleContext::new("expr");
+    g.define("expr", ctx.seq().nt("num").repeat0(ctx.seq().mark_ch('+', TMark::Mute).nt("num")));
+    let ctx = RuleContext::new("num");
+    g.define("num", ctx.seq().ch('n'));
+
+    let mut parser = Parser::new(g.clone());
+    let arena = parser.parse_tokens(&[Tok::Num(12), Tok::Plus, Tok::Num(3)]).unwrap();
+    assert_eq!(Parser::tree_to_testfmt(&arena), "<expr><num>12</num><num>3</num></expr>");
+
+    let mut parser = Parser::new(g);
+    let err = parser.parse_tokens(&[Tok::Num(12), Tok::Plus]).unwrap_err();
+    assert_eq!(err.to_string(), "DynamicError: no parse: at line 1 col 3: unexpected end of input, expected num");
+}
+
+#[test]
+fn test_parse_and_select() {
+    use crate::builtin_grammars::{ParserTestSet, SmokeElem, SmokeAttr};
+
+    let mut parser = Parser::new(SmokeElem{}.get_grammar());
+    assert_eq!(parser.parse_and_select("abc:def", "doc/name").unwrap(), vec!["abc"]);
+    let mut parser = Parser::new(SmokeElem{}.get_grammar());
+    assert_eq!(parser.parse_and_select("abc:def", "doc").unwrap(), vec!["abc:def"]);
+    let mut parser = Parser::new(SmokeElem{}.get_grammar());
+    assert!(parser.parse_and_select("abc:def", "doc/nosuch").unwrap().is_empty());
+
+    let mut parser = Parser::new(SmokeAttr{}.get_grammar());
+    assert_eq!(parser.parse_and_select("abc:def", "doc/@name").unwrap(), vec!["abc"]);
+
+    let mut parser = Parser::new(SmokeElem{}.get_grammar());
+    assert!(matches!(parser.parse_and_select("abc:def", "doc//name"), Err(ParseError::StaticError(_))));
+}
+
+#[test]
+fn test_task_dedup() {
+    use crate::builtin_grammars::{ParserTestSet, SmokeWiki};
+
+    // structurally identical tasks dedupe, whether fresh or advanced
+    let g = SmokeWiki{}.get_grammar();
+    let rule = g.get_definition("T").iter().next().unwrap().clone();
+    let mut traces = TraceArena::new();
+    let fresh = traces.task("T", Mark::Default, 0, 0, rule.dot_notator(), 0).unwrap();
+    assert!(traces.task("T", Mark::Default, 0, 0, rule.dot_notator(), 0).is_none());
+    assert!(traces.task("T", Mark::Default, 1, 1, rule.dot_notator(), 0).is_some());
+    assert!(traces.task_advance_cursor(fresh, MatchRec::Term('1', 1, TMark::Default)).is_some());
+    assert!(traces.task_advance_cursor(fresh, MatchRec::Term('1', 1, TMark::Default)).is_none());
+
+    // over a whole parse, the keys tell tasks apart exactly as well as their full rendering does
+    let mut parser = Parser::new(g);
+    parser.parse("1+2*3+4*1").unwrap();
+    let tasks = &parser.traces.arena;
+    let keys = tasks.iter().map(Task::key).collect::<HashSet<_>>();
+    let rendered = tasks.iter().map(ToString::to_string).collect::<HashSet<_>>();
+    assert_eq!(keys.len(), tasks.len());
+    assert_eq!(rendered.len(), tasks.len());
+
+    // a repeated alternative stays in the grammar as written; the parser works out which one it repeats
+    let g = crate::ixml_grammar::ixml_str_to_grammar(r#"doc = "x"; "x"; "y"."#).unwrap();
+    let parser = Parser::new(g.clone());
+    assert_eq!(parser.alt_indexes["doc"], [0, 0, 2]);
+    assert_eq!(parser.grammar().to_string(), g.to_string());
+    assert_eq!(g.get_definition("doc").iter().count(), 3);
+}
+
+#[test]
+fn test_deep_nesting_unpacks() {
+    use crate::grammar::RuleContext;
+
+    // Each element unpacked looks up the completed tasks for its span. That lookup is a hash,
+    // not a scan of every completed task, so building this tree is linear in its size rather than quadratic.
+    // doc = item. item = "(", item, ")"; "x".
+    let mut g = Grammar::new();
+    let ctx = RuleContext::new("doc");
+    g.define("doc", ctx.seq().nt("item"));
+    let ctx = RuleContext::new("item");
+    g.define("item", ctx.seq().ch('(').nt("item").ch(')'));
+    g.define("item", ctx.seq().ch('x'));
+
+    let depth = 200;
+    let input = format!("{}x{}", "(".repeat(depth), ")".repeat(depth));
+    let arena = Parser::new(g).parse(&input).unwrap();
+    let expected = format!("<doc>{}<item>x</item>{}</doc>", "<item>(".repeat(depth), ")</item>".repeat(depth));
+    assert_eq!(Parser::tree_to_testfmt(&arena), expected);
+}
+
+#[test]
+fn test_chart_at() {
+    use crate::grammar::RuleContext;
+
+    // sum = num, "+", num. num = ["0"-"9"]+.
+    let mut g = Grammar::new();
+    let ctx = RuleContext::new("sum");
+    g.define("sum", ctx.seq().nt("num").ch('+').nt("num"));
+    let ctx = RuleContext::new("num");
+    g.define("num", ctx.seq().repeat1(ctx.seq().ch_range('0', '9')));
+    let mut parser = Parser::new(g);
+    assert!(parser.parse("12-3").is_err());
+
+    // where it got stuck, "sum" has its first num, and wants a "+"; the chart says so
+    let stuck = parser.farthest_pos();
+    assert_eq!(stuck, 2);
+    let chart = parser.chart_at(stuck);
+    let sum = chart.iter().find(|entry| entry.rule == "sum").unwrap();
+    assert_eq!((sum.origin, sum.dot, sum.state), (0, 1, ChartState::Scanned));
+    assert_eq!(sum.next.as_deref(), Some(r#""+""#));
+    assert_eq!(sum.to_string(), r#"sum from 0, dot 1: Scanned, expecting "+""#);
+    assert!(chart.iter().any(|entry| entry.rule == "num" && entry.origin == 0 && entry.state == ChartState::Completed));
+
+    // and at the start, everything is predicted
+    assert!(parser.chart_at(0).iter().all(|entry| entry.state == ChartState::Predicted && entry.origin == 0));
+}
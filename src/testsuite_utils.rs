@@ -1,5 +1,6 @@
 use std::collections::HashMap;
 use std::{fs, fmt};
+use std::io::BufRead;
 use std::path::PathBuf;
 use std::str::from_utf8;
 
@@ -8,7 +9,9 @@ use quick_xml::events::Event;
 use quick_xml::events::attributes::{Attributes};
 use quick_xml::name::QName;
 use quick_xml::reader::Reader;
+use quick_xml::Writer;
 use string_builder::Builder;
+use log::{debug, info, error};
 
 use crate::grammar::Grammar;
 
@@ -95,7 +98,7 @@ impl TestCaseBuilder {
         self.input = None;
         let expected = self.expected.drain(..).collect();
         self.expected.clear();
-        println!("built test case ===={}====", name.clone().unwrap());
+        debug!("built test case ===={}====", name.clone().unwrap());
 
         TestCase { name: name.unwrap(), grammars: grammar, input: input.unwrap(), expected }
     }
@@ -127,159 +130,210 @@ r##"<test-catalog xmlns='https://github.com/invisibleXML/ixml/test-catalog'>
 pub fn read_test_catalog(path: String) -> Vec<TestCase> {
     let pathbuf = PathBuf::from(&path);
     let basepath = pathbuf.parent().unwrap();
-    println!("{}", basepath.to_string_lossy());
+    info!("{}", basepath.to_string_lossy());
     let file = fs::read_to_string(&path).expect("The file could not be read");
 
     //let file = TEST_CATALOG_EG;
     //println!("{file}");
-    
-    let mut reader = Reader::from_str(&file);
-    reader.trim_text(true);
-    reader.expand_empty_elements(true);
 
-    let mut buf = Vec::new();
-    let mut test_set_nesting: Vec<String> = Vec::new();
-    let mut current_grammar = String::new();
-    let mut builder = TestCaseBuilder::new();
-    let mut test_cases: Vec<TestCase> = Vec::new();
-    
+    let test_cases: Vec<TestCase> = TestCatalogIter::new(Reader::from_str(&file), basepath.to_path_buf()).collect();
+    info!("read {} cases", test_cases.len());
+    test_cases
+}
+
+/// Like `read_test_catalog`, but streams the catalog file, yielding each `TestCase` as soon as its
+/// `</test-case>` is read. Only the current test-set's grammar is held in memory
+pub fn read_test_catalog_iter(path: String) -> impl Iterator<Item=TestCase> {
+    let pathbuf = PathBuf::from(&path);
+    let basepath = pathbuf.parent().unwrap().to_path_buf();
+    info!("{}", basepath.to_string_lossy());
+    let reader = Reader::from_file(&path).expect("The file could not be read");
+    TestCatalogIter::new(reader, basepath)
+}
+
+/// Incremental catalog reader, over any quick-xml source
+struct TestCatalogIter<R> {
+    reader: Reader<R>,
+    basepath: PathBuf,
+    buf: Vec<u8>,
+    test_set_nesting: Vec<String>,
+    current_grammar: String,
+    builder: TestCaseBuilder,
     // to capture <assert-xml> arbitrary content, we just store a buch of u8 in a Vec
     // (and later turn it into a String)
-    let mut raw_xml_accum: Vec<u8> = Vec::new();
-    let mut enable_accum = false;
+    raw_xml_accum: Vec<u8>,
+    enable_accum: bool,
+    /// raw text of an inline <ixml-grammar> or <test-string> being read
+    text_accum: Option<Vec<u8>>,
+}
 
-    // The `Reader` does not implement `Iterator` because it outputs borrowed data (`Cow`s)
-    loop {
-        // NOTE: this is the generic case when we don't know about the input BufRead.
-        // when the input is a &str or a &[u8], we don't actually need to use another
-        // buffer, we could directly call `reader.read_event()`
-        match reader.read_event_into(&mut buf) {
-            Err(e) => panic!("Error at position {}: {:?}", reader.buffer_position(), e),
-            // exits the loop when reaching end of file
-            Ok(Event::Eof) => break,
+impl<R: BufRead> TestCatalogIter<R> {
+    fn new(mut reader: Reader<R>, basepath: PathBuf) -> Self {
+        reader.trim_text(true);
+        reader.expand_empty_elements(true);
+        Self {
+            reader,
+            basepath,
+            buf: Vec::new(),
+            test_set_nesting: Vec::new(),
+            current_grammar: String::new(),
+            builder: TestCaseBuilder::new(),
+            raw_xml_accum: Vec::new(),
+            enable_accum: false,
+            text_accum: None,
+        }
+    }
 
-            Ok(Event::Start(e)) => {
-                match e.name().as_ref() {
-                    b"test-set" => {
-                        let name = attr_by_name(&e.attributes(), "name");
-                        test_set_nesting.push(name);
-                    },
-                    b"ixml-grammar" => {
-                        let grammar = reader.read_text(e.to_end().name());
-                        current_grammar = grammar.expect("parse error reading inline grammar").to_string();
-                    },
-                    b"ixml-grammar-ref" => {
-                        let href = attr_by_name(&e.attributes(), "href");
-                        let mut fullpath = basepath.to_path_buf();
-                        fullpath.push(href);
-                        //println!("ixml-grammar-ref {}", fullpath.to_string_lossy());
-                        current_grammar = fs::read_to_string(fullpath).expect("Error reading grammar file");
-                    },
-                    b"test-case" => {
-                        let name = attr_by_name(&e.attributes(), "name");
-                        builder = TestCaseBuilder::new();
-                        let mut fullname = test_set_nesting.join("/");
-                        fullname.push('/');
-                        fullname.push_str(&name);
-                        builder.name = Some(fullname);
-                        builder.grammar.push(TestGrammar::Unparsed(current_grammar.clone()));
-                    },
-                    b"test-case-ref" => {
-                        // TODO: maybe just note these somewhere...
-                    },
-                    b"test-string" => {
-                        let input = reader.read_text(e.to_end().name());
-                        builder.input = Some(input.expect("parse error reading inline test-string").to_string());
-                    },
-                    b"test-string-ref" => {
-                        let href = attr_by_name(&e.attributes(), "href");
-                        let mut fullpath = basepath.to_path_buf();
-                        fullpath.push(href);
-                        //println!("test-string-ref {}", fullpath.to_string_lossy());
-                        builder.input = Some(fs::read_to_string(fullpath).expect("Error reading grammar file"));
-                    },
-                    b"assert-not-a-sentence" => {
-                        builder.expected.push(TestResult::AssertNotASentence);
-                    },
-                    b"assert-dynamic-error" => {
-                        let codes = attr_by_name(&e.attributes(), "code");
-                        for code in codes.split(' ') {
-                            builder.expected.push(TestResult::AssertDynamicError(String::from(code)));
+    /// inline grammars and test strings are taken verbatim, so turn off whitespace trimming while reading them
+    fn start_text_accum(&mut self) {
+        self.text_accum = Some(Vec::new());
+        self.reader.trim_text(false);
+    }
+
+    fn finish_text_accum(&mut self) -> String {
+        self.reader.trim_text(true);
+        let raw = self.text_accum.take().unwrap_or_default();
+        from_utf8(&raw).expect("UTF-8 error in inline text").to_string()
+    }
+}
+
+impl<R: BufRead> Iterator for TestCatalogIter<R> {
+    type Item = TestCase;
+
+    fn next(&mut self) -> Option<TestCase> {
+        // The `Reader` does not implement `Iterator` because it outputs borrowed data (`Cow`s)
+        loop {
+            // if we don't keep a borrow elsewhere, we can clear the buffer to keep memory usage low
+            self.buf.clear();
+            match self.reader.read_event_into(&mut self.buf) {
+                Err(e) => panic!("Error at position {}: {:?}", self.reader.buffer_position(), e),
+                // exits the loop when reaching end of file
+                Ok(Event::Eof) => return None,
+
+                Ok(Event::Start(e)) => {
+                    match e.name().as_ref() {
+                        b"test-set" => {
+                            let name = attr_by_name(&e.attributes(), "name");
+                            self.test_set_nesting.push(name);
+                        },
+                        b"ixml-grammar" | b"test-string" => {
+                            self.start_text_accum();
+                        },
+                        b"ixml-grammar-ref" => {
+                            let href = attr_by_name(&e.attributes(), "href");
+                            let mut fullpath = self.basepath.clone();
+                            fullpath.push(href);
+                            //println!("ixml-grammar-ref {}", fullpath.to_string_lossy());
+                            self.current_grammar = fs::read_to_string(fullpath).expect("Error reading grammar file");
+                        },
+                        b"test-case" => {
+                            let name = attr_by_name(&e.attributes(), "name");
+                            self.builder = TestCaseBuilder::new();
+                            let mut fullname = self.test_set_nesting.join("/");
+                            fullname.push('/');
+                            fullname.push_str(&name);
+                            self.builder.name = Some(fullname);
+                            self.builder.grammar.push(TestGrammar::Unparsed(self.current_grammar.clone()));
+                        },
+                        b"test-case-ref" => {
+                            // TODO: maybe just note these somewhere...
+                        },
+                        b"test-string-ref" => {
+                            let href = attr_by_name(&e.attributes(), "href");
+                            let mut fullpath = self.basepath.clone();
+                            fullpath.push(href);
+                            //println!("test-string-ref {}", fullpath.to_string_lossy());
+                            self.builder.input = Some(fs::read_to_string(fullpath).expect("Error reading grammar file"));
+                        },
+                        b"assert-not-a-sentence" => {
+                            self.builder.expected.push(TestResult::AssertNotASentence);
+                        },
+                        b"assert-dynamic-error" => {
+                            let codes = attr_by_name(&e.attributes(), "code");
+                            for code in codes.split(' ') {
+                                self.builder.expected.push(TestResult::AssertDynamicError(String::from(code)));
+                            }
+                        },
+                        b"assert-xml" => {
+                            self.enable_accum = true;
+                        },
+                        b"assert-xml-ref" => {
+                            let href = attr_by_name(&e.attributes(), "href");
+                            let mut fullpath = self.basepath.clone();
+                            fullpath.push(href);
+                            //println!("assert-xml-ref {}", fullpath.to_string_lossy());
+                            self.builder.expected.push(TestResult::AssertXml(fs::read_to_string(fullpath).expect("Error reading assert-xml file")));
                         }
-                    },
-                    b"assert-xml" => {
-                        //let inner_content = reader.read_to_end(e.to_end().name());
-                        //builder.expected.push(TestResult::AssertXml(from_utf8(inner_content.expect("Error reading inline assert-xml")).unwrap()));
-                        enable_accum = true;
-                    },
-                    b"assert-xml-ref" => {
-                        let href = attr_by_name(&e.attributes(), "href");
-                        let mut fullpath = basepath.to_path_buf();
-                        fullpath.push(href);
-                        //println!("assert-xml-ref {}", fullpath.to_string_lossy());
-                        builder.expected.push(TestResult::AssertXml(fs::read_to_string(fullpath).expect("Error reading assert-xml file")));
+                        _ => {
+                            if self.enable_accum {
+                                // let quick-xml serialize the complete start tag, attributes and all
+                                Writer::new(&mut self.raw_xml_accum).write_event(Event::Start(e))
+                                    .expect("Error re-serializing assert-xml content");
+                            }
+                        },
                     }
-                    _ => {
-                        if enable_accum {
-                            raw_xml_accum.push(b'<');
-                            raw_xml_accum.extend(e.iter());
-                            raw_xml_accum.push(b'>');
-                        }
-                    },
-                }
-            }
-            Ok(Event::Text(t)) => {
-                if enable_accum {
-                    raw_xml_accum.extend(t.iter());
                 }
-                
-            },
-            Ok(Event::End(e)) => {
-                match e.name().as_ref() {
-                    b"test-set" => {
-                       test_set_nesting.pop();
-                    },
-                    b"test-case" => {
-                        test_cases.push(builder.build());
-                    },
-                    b"assert-xml" => {
-                        enable_accum = false;
-                        let xml_string = from_utf8(&raw_xml_accum).expect("UTF-8 error in assert-xml").to_string();
-                        //println!("assert-xml literal {xml_string}");
-                        builder.expected.push(TestResult::AssertXml(xml_string));
+                Ok(Event::Text(t)) => {
+                    if let Some(text) = self.text_accum.as_mut() {
+                        text.extend(t.iter());
+                    } else if self.enable_accum {
+                        Writer::new(&mut self.raw_xml_accum).write_event(Event::Text(t))
+                            .expect("Error re-serializing assert-xml content");
                     }
-                    _ => {
-                        if enable_accum {
-                            raw_xml_accum.push(b'<');
-                            raw_xml_accum.push(b'/');
-                            raw_xml_accum.extend(e.iter());
-                            raw_xml_accum.push(b'>');
+                },
+                Ok(Event::CData(t)) => {
+                    if let Some(text) = self.text_accum.as_mut() {
+                        text.extend(t.iter());
+                    }
+                },
+                Ok(Event::End(e)) => {
+                    match e.name().as_ref() {
+                        b"test-set" => {
+                           self.test_set_nesting.pop();
+                        },
+                        b"ixml-grammar" => {
+                            self.current_grammar = self.finish_text_accum();
+                        },
+                        b"test-string" => {
+                            self.builder.input = Some(self.finish_text_accum());
+                        },
+                        b"test-case" => {
+                            return Some(self.builder.build());
+                        },
+                        b"assert-xml" => {
+                            self.enable_accum = false;
+                            let xml_string = from_utf8(&self.raw_xml_accum).expect("UTF-8 error in assert-xml").to_string();
+                            self.raw_xml_accum.clear();
+                            //println!("assert-xml literal {xml_string}");
+                            self.builder.expected.push(TestResult::AssertXml(xml_string));
+                        }
+                        _ => {
+                            if self.enable_accum {
+                                Writer::new(&mut self.raw_xml_accum).write_event(Event::End(e))
+                                    .expect("Error re-serializing assert-xml content");
+                            }
                         }
                     }
-                }
-            },
-            Ok(Event::Empty(_b)) => (),
-            Ok(Event::CData(_b)) => (),
-            Ok(Event::Comment(_b)) => (),
-            Ok(Event::PI(_b)) => (),
-            Ok(Event::Decl(_b)) => (),
-            Ok(Event::DocType(_b)) => (),
+                },
+                Ok(Event::Empty(_b)) => (),
+                Ok(Event::Comment(_b)) => (),
+                Ok(Event::PI(_b)) => (),
+                Ok(Event::Decl(_b)) => (),
+                Ok(Event::DocType(_b)) => (),
+            }
         }
-        // if we don't keep a borrow elsewhere, we can clear the buffer to keep memory usage low
-        buf.clear();
     }
-    println!("read {} cases", test_cases.len());
-    test_cases
 }
 
 /// Not "Canonical XML" but close enough for our purposes here
 /// Formats an XML document in a conveniently-diffable format
 /// Not namespace-aware, and does its own thing with newlines
+/// Whitespace-only text (indentation between tags) is dropped, but any other text is kept exactly, whitespace included
 pub fn xml_canonicalize(input_xml: &str) -> String {
     let mut builder = Builder::default();
     
     let mut reader = Reader::from_str(input_xml);
-    reader.trim_text(true);
+    reader.trim_text(false);
     reader.expand_empty_elements(true);
 
     let mut buf = Vec::new();
@@ -299,14 +353,15 @@ pub fn xml_canonicalize(input_xml: &str) -> String {
                         builder.append(" ");
                         builder.append(k);
                         builder.append("=\"");
-                        builder.append(v.replace('\"', "&quot;"));
+                        builder.append(v.replace('&', "&amp;").replace('\"', "&quot;"));
                         builder.append("\"")
                     }
                 }
                 builder.append("\n>");
             }
+            Ok(Event::Text(t)) if t.iter().all(u8::is_ascii_whitespace) => (),
             Ok(Event::Text(t)) => {
-                builder.append(t.unescape().expect("UTF-8 parse error on text").to_string().replace('<', "&lt;"));
+                builder.append(t.unescape().expect("UTF-8 parse error on text").replace('&', "&amp;").replace('<', "&lt;"));
             },
             Ok(Event::End(e)) => {
                 builder.append("</");
@@ -346,7 +401,7 @@ fn all_attrs(attrs: Attributes) -> HashMap<String, String> {
                 };
             }
             Err(e) => {
-                println!("{e}");
+                error!("{e}");
                 panic!("Error iterating through attributes");
             }
         };
@@ -367,4 +422,107 @@ fn test_canonize_xml() {
     assert_eq!(xml_canonicalize(xml1), xml2);
     println!("2: {}", xml_canonicalize(xml2));
     assert_eq!(xml_canonicalize(xml1), xml_canonicalize(xml2));
-}
\ No newline at end of file
+}
+#[test]
+fn test_significant_whitespace() {
+    use crate::grammar::RuleContext;
+    use crate::parser::Parser;
+
+    // doc = "a", " ", "b", x, " c". x = "x".
+    let mut g = Grammar::new();
+    let ctx = RuleContext::new("doc");
+    g.define("doc", ctx.seq().ch('a').ch(' ').ch('b').nt("x").ch(' ').ch('c'));
+    let ctx = RuleContext::new("x");
+    g.define("x", ctx.seq().ch('x'));
+
+    let mut parser = Parser::new(g);
+    let arena = parser.parse("a bx c").unwrap();
+    let result = Parser::tree_to_testfmt(&arena);
+    assert_eq!(result, "<doc>a b<x>x</x> c</doc>");
+    assert_eq!(xml_canonicalize(&result), "<doc\n>a b<x\n>x</x\n> c</doc\n>");
+    assert_ne!(xml_canonicalize(&result), xml_canonicalize("<doc>a b<x>x</x>c</doc>"));
+    // indentation between elements still doesn't matter
+    assert_eq!(xml_canonicalize("<doc>\n  <x>x</x>\n</doc>"), xml_canonicalize("<doc><x>x</x></doc>"));
+}
+
+#[test]
+fn test_unicode_element_names() {
+    use crate::grammar::{RuleContext, Mark};
+    use crate::parser::Parser;
+
+    // données = clé, "=", 値.
+    // @clé = ["a"-"z"].
+    // 値 = ["0"-"9"].
+    let mut g = Grammar::new();
+    let ctx = RuleContext::new("données");
+    g.define("données", ctx.seq().nt("clé").ch('=').nt("値"));
+    let ctx = RuleContext::new("clé");
+    g.mark_define(Mark::Attr, "clé", ctx.seq().ch_range('a', 'z'));
+    let ctx = RuleContext::new("値");
+    g.define("値", ctx.seq().ch_range('0', '9'));
+
+    let mut parser = Parser::new(g);
+    let arena = parser.parse("x=1").unwrap();
+    let result = Parser::tree_to_testfmt(&arena);
+    assert_eq!(result, r#"<données clé="x">=<値>1</値></données>"#);
+    assert_eq!(xml_canonicalize(&result), "<données clé=\"x\"\n>=<値\n>1</値\n></données\n>");
+}
+
+#[test]
+fn test_catalog_iter_matches_eager() {
+    let catalog = r##"<test-catalog xmlns='https://github.com/invisibleXML/ixml/test-catalog'>
+  <test-set name='first'>
+    <ixml-grammar>doc = "a".</ixml-grammar>
+    <test-case name='one'>
+      <test-string>a</test-string>
+      <assert-xml><doc>a</doc></assert-xml>
+    </test-case>
+    <test-case name='two'>
+      <test-string> a </test-string>
+      <assert-not-a-sentence/>
+    </test-case>
+  </test-set>
+  <test-set name='second'>
+    <ixml-grammar>doc = "b".</ixml-grammar>
+    <test-case name='three'>
+      <test-string>b</test-string>
+      <assert-dynamic-error code='D01 D02'/>
+    </test-case>
+  </test-set>
+</test-catalog>"##;
+    let path = std::env::temp_dir().join(format!("eb-catalog-iter-{}.xml", std::process::id()));
+    fs::write(&path, catalog).unwrap();
+    let path = path.to_string_lossy().to_string();
+
+    let eager = read_test_catalog(path.clone());
+    let streamed = read_test_catalog_iter(path.clone()).collect::<Vec<_>>();
+    fs::remove_file(&path).unwrap();
+
+    assert_eq!(eager.len(), 3);
+    // the derived Debug covers every field, including grammars and expected results
+    assert_eq!(format!("{eager:?}"), format!("{streamed:?}"));
+    assert_eq!(streamed[1].name, "first/two");
+    assert_eq!(streamed[1].input, " a ");
+    assert!(matches!(&streamed[2].grammars[0], TestGrammar::Unparsed(g) if g == r#"doc = "b"."#));
+}
+
+#[test]
+fn test_inline_assert_xml_attributes() {
+    let catalog = r##"<test-catalog xmlns='https://github.com/invisibleXML/ixml/test-catalog'>
+  <test-set name='attrs'>
+    <ixml-grammar>doc = "a".</ixml-grammar>
+    <test-case name='inline'>
+      <test-string>a</test-string>
+      <assert-xml><doc id='1' note="a &amp; b"><x y="z"/>text</doc></assert-xml>
+    </test-case>
+  </test-set>
+</test-catalog>"##;
+    let path = std::env::temp_dir().join(format!("eb-catalog-attrs-{}.xml", std::process::id()));
+    fs::write(&path, catalog).unwrap();
+    let cases = read_test_catalog(path.to_string_lossy().to_string());
+    fs::remove_file(&path).unwrap();
+
+    let TestResult::AssertXml(xml) = &cases[0].expected[0] else { panic!("expected inline assert-xml") };
+    assert_eq!(xml, r#"<doc id='1' note="a &amp; b"><x y="z"></x>text</doc>"#);
+    assert_eq!(xml_canonicalize(xml), xml_canonicalize(r#"<doc note="a &amp; b" id="1"><x y="z"/>text</doc>"#));
+}
@@ -1,38 +1,181 @@
-use unicode_character_database::general_category::{LETTER, NONSPACING_MARK, DECIMAL_NUMBER, SPACE_SEPARATOR};
+use unicode_character_database::general_category::{
+    UPPERCASE_LETTER, LOWERCASE_LETTER, TITLECASE_LETTER, MODIFIER_LETTER, OTHER_LETTER, LETTER,
+    NONSPACING_MARK, SPACING_MARK, ENCLOSING_MARK,
+    DECIMAL_NUMBER, LETTER_NUMBER, OTHER_NUMBER,
+    CONNECTOR_PUNCTUATION, DASH_PUNCTUATION, OPEN_PUNCTUATION, CLOSE_PUNCTUATION, INITIAL_PUNCTUATION, FINAL_PUNCTUATION, OTHER_PUNCTUATION,
+    MATH_SYMBOL, CURRENCY_SYMBOL, MODIFIER_SYMBOL, OTHER_SYMBOL,
+    SPACE_SEPARATOR, LINE_SEPARATOR, PARAGRAPH_SEPARATOR,
+    CONTROL, FORMAT, SURROGATE, PRIVATE_USE, UNASSIGNED,
+};
 
-#[derive(Clone, Copy, Debug)]
-/// WIP. Immediate focus on things needed for `ixml_grammar`
+use std::{cmp::Ordering, fmt};
+
+use crate::parser::ParseError;
+
+type Table = &'static [(u32, u32)];
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Ord, PartialOrd, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+/// The Unicode general categories, as used in ixml character classes like `[Lu; Nd]`.
+/// The single-letter categories are the union of their two-letter sub-categories
 /// See: https://en.wikipedia.org/wiki/Unicode_character_property#General_Category
-/// TODO: would https://docs.rs/unicode_categories/ be better?
 pub enum UnicodeRange {
-    L,
-    Mn,
-    Nd,
-    Zs,
+    L, Lu, Ll, Lt, Lm, Lo,
+    M, Mn, Mc, Me,
+    N, Nd, Nl, No,
+    P, Pc, Pd, Ps, Pe, Pi, Pf, Po,
+    S, Sm, Sc, Sk, So,
+    Z, Zs, Zl, Zp,
+    C, Cc, Cf, Cs, Co, Cn,
 }
 
 impl UnicodeRange {
-    pub fn new(name: &str) -> Self {
-        match name {
+    /// look up a category by its (one or two letter) name
+    pub fn new(name: &str) -> Result<Self, ParseError> {
+        let range = match name {
             "L" => Self::L,
+            "Lu" => Self::Lu,
+            "Ll" => Self::Ll,
+            "Lt" => Self::Lt,
+            "Lm" => Self::Lm,
+            "Lo" => Self::Lo,
+            "M" => Self::M,
             "Mn" => Self::Mn,
+            "Mc" => Self::Mc,
+            "Me" => Self::Me,
+            "N" => Self::N,
             "Nd" => Self::Nd,
+            "Nl" => Self::Nl,
+            "No" => Self::No,
+            "P" => Self::P,
+            "Pc" => Self::Pc,
+            "Pd" => Self::Pd,
+            "Ps" => Self::Ps,
+            "Pe" => Self::Pe,
+            "Pi" => Self::Pi,
+            "Pf" => Self::Pf,
+            "Po" => Self::Po,
+            "S" => Self::S,
+            "Sm" => Self::Sm,
+            "Sc" => Self::Sc,
+            "Sk" => Self::Sk,
+            "So" => Self::So,
+            "Z" => Self::Z,
             "Zs" => Self::Zs,
-            _ => panic!("Referenced unknown Unicode Category {name}")
-        }
+            "Zl" => Self::Zl,
+            "Zp" => Self::Zp,
+            "C" => Self::C,
+            "Cc" => Self::Cc,
+            "Cf" => Self::Cf,
+            "Cs" => Self::Cs,
+            "Co" => Self::Co,
+            "Cn" => Self::Cn,
+            _ => return Err(ParseError::static_err(&format!("unknown Unicode category {name}"))),
+        };
+        Ok(range)
     }
 
     pub fn accept(&self, ch: char) -> bool {
+        let codepoint = ch as u32;
+        // each table is sorted and non-overlapping, so a binary search finds the one range that could hold it
+        self.tables().iter().any(|table| table.binary_search_by(|(bot, top)| {
+            if *top < codepoint {
+                Ordering::Less
+            } else if *bot > codepoint {
+                Ordering::Greater
+            } else {
+                Ordering::Equal
+            }
+        }).is_ok())
+    }
+
+    /// the inclusive codepoint ranges making up this category
+    pub fn ranges(&self) -> impl Iterator<Item = (u32, u32)> {
+        self.tables().iter().flat_map(|table| table.iter().copied())
+    }
+
+    fn tables(&self) -> &'static [Table] {
         match self {
-            Self::L => member_of_category(ch, LETTER),
-            Self::Mn => member_of_category(ch, NONSPACING_MARK),
-            Self::Nd => member_of_category(ch, DECIMAL_NUMBER),
-            Self::Zs => member_of_category(ch, SPACE_SEPARATOR),
+            Self::L => &[LETTER],
+            Self::Lu => &[UPPERCASE_LETTER],
+            Self::Ll => &[LOWERCASE_LETTER],
+            Self::Lt => &[TITLECASE_LETTER],
+            Self::Lm => &[MODIFIER_LETTER],
+            Self::Lo => &[OTHER_LETTER],
+            Self::M => &[NONSPACING_MARK, SPACING_MARK, ENCLOSING_MARK],
+            Self::Mn => &[NONSPACING_MARK],
+            Self::Mc => &[SPACING_MARK],
+            Self::Me => &[ENCLOSING_MARK],
+            Self::N => &[DECIMAL_NUMBER, LETTER_NUMBER, OTHER_NUMBER],
+            Self::Nd => &[DECIMAL_NUMBER],
+            Self::Nl => &[LETTER_NUMBER],
+            Self::No => &[OTHER_NUMBER],
+            Self::P => &[CONNECTOR_PUNCTUATION, DASH_PUNCTUATION, OPEN_PUNCTUATION, CLOSE_PUNCTUATION,
+                INITIAL_PUNCTUATION, FINAL_PUNCTUATION, OTHER_PUNCTUATION],
+            Self::Pc => &[CONNECTOR_PUNCTUATION],
+            Self::Pd => &[DASH_PUNCTUATION],
+            Self::Ps => &[OPEN_PUNCTUATION],
+            Self::Pe => &[CLOSE_PUNCTUATION],
+            Self::Pi => &[INITIAL_PUNCTUATION],
+            Self::Pf => &[FINAL_PUNCTUATION],
+            Self::Po => &[OTHER_PUNCTUATION],
+            Self::S => &[MATH_SYMBOL, CURRENCY_SYMBOL, MODIFIER_SYMBOL, OTHER_SYMBOL],
+            Self::Sm => &[MATH_SYMBOL],
+            Self::Sc => &[CURRENCY_SYMBOL],
+            Self::Sk => &[MODIFIER_SYMBOL],
+            Self::So => &[OTHER_SYMBOL],
+            Self::Z => &[SPACE_SEPARATOR, LINE_SEPARATOR, PARAGRAPH_SEPARATOR],
+            Self::Zs => &[SPACE_SEPARATOR],
+            Self::Zl => &[LINE_SEPARATOR],
+            Self::Zp => &[PARAGRAPH_SEPARATOR],
+            Self::C => &[CONTROL, FORMAT, SURROGATE, PRIVATE_USE, UNASSIGNED],
+            Self::Cc => &[CONTROL],
+            Self::Cf => &[FORMAT],
+            Self::Cs => &[SURROGATE],
+            Self::Co => &[PRIVATE_USE],
+            Self::Cn => &[UNASSIGNED],
         }
     }
 }
 
-fn member_of_category(ch: char, spec: &'static [(u32,u32)]) -> bool {
-    let codepoint = ch as u32;
-    spec.iter().any(|(bot, top)| *bot <= codepoint && codepoint <= *top)
+/// the category's name, as written in ixml
+impl fmt::Display for UnicodeRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{self:?}")
+    }
+}
+
+#[test]
+fn test_two_letter_categories() {
+    let cases = [
+        ("Lu", 'Q', 'q'), ("Ll", '\u{e9}', 'E'), ("Lt", '\u{1c5}', 'D'), ("Lm", '\u{2b0}', 'h'), ("Lo", '\u{5d0}', 'a'),
+        ("Mn", '\u{301}', 'a'), ("Mc", '\u{903}', '\u{301}'), ("Me", '\u{20dd}', 'o'),
+        ("Nd", '7', 'x'), ("Nl", '\u{2163}', '4'), ("No", '\u{bd}', '5'),
+        ("Pc", '_', '-'), ("Pd", '-', '_'), ("Ps", '(', ')'), ("Pe", ']', '['),
+        ("Pi", '\u{ab}', '\u{bb}'), ("Pf", '\u{bb}', '\u{ab}'), ("Po", '!', '('),
+        ("Sm", '+', '-'), ("Sc", '$', 'S'), ("Sk", '^', '~'), ("So", '\u{a9}', 'c'),
+        ("Zs", ' ', '\t'), ("Zl", '\u{2028}', '\n'), ("Zp", '\u{2029}', '\n'),
+        ("Cc", '\t', ' '), ("Cf", '\u{200b}', ' '), ("Co", '\u{e000}', 'a'), ("Cn", '\u{378}', 'a'),
+    ];
+    for (name, member, nonmember) in cases {
+        let range = UnicodeRange::new(name).unwrap();
+        assert!(range.accept(member), "{name} should accept {member:?}");
+        assert!(!range.accept(nonmember), "{name} should reject {nonmember:?}");
+    }
+}
+
+#[test]
+fn test_umbrella_categories() {
+    let cases = [
+        ("L", "aZ\u{5d0}", "1 "), ("M", "\u{301}\u{903}\u{20dd}", "a"), ("N", "7\u{2163}\u{bd}", "a"),
+        ("P", "_-()\u{ab}!", "+a"), ("S", "+$^\u{a9}", "!a"), ("Z", " \u{2028}\u{2029}", "\t"),
+        ("C", "\t\u{200b}\u{e000}\u{378}", "a "),
+    ];
+    for (name, members, nonmembers) in cases {
+        let range = UnicodeRange::new(name).unwrap();
+        assert!(members.chars().all(|ch| range.accept(ch)), "{name} should accept all of {members:?}");
+        assert!(!nonmembers.chars().any(|ch| range.accept(ch)), "{name} should reject all of {nonmembers:?}");
+    }
+    assert!(UnicodeRange::new("Xx").is_err());
+    assert!(UnicodeRange::new("LC").is_err());
 }
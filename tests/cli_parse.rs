@@ -0,0 +1,80 @@
+use std::process::Command;
+use std::fs;
+
+static GRAMMAR: &str = r#"doc = letter+. letter = "a"; "b"."#;
+
+/// run `eb --quiet parse -g <grammar> -i <input> [extra args]` with everything under a fresh temp dir
+fn run_parse(dir: &std::path::Path, input: &str, extra_args: &[&str]) -> std::process::Output {
+    let grammar = dir.join("grammar.ixml");
+    fs::write(&grammar, GRAMMAR).unwrap();
+    Command::new(env!("CARGO_BIN_EXE_eb"))
+        .args(["--quiet", "parse", "-g"])
+        .arg(&grammar)
+        .arg("-i")
+        .arg(dir.join(input))
+        .args(extra_args)
+        .output()
+        .expect("failed to run eb")
+}
+
+#[test]
+fn test_parse_single_file() {
+    let dir = std::env::temp_dir().join(format!("eb-parse-single-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(dir.join("one.txt"), "ab").unwrap();
+
+    let output = run_parse(&dir, "one.txt", &[]);
+    fs::remove_dir_all(&dir).unwrap();
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "<doc><letter>a</letter><letter>b</letter></doc>\n");
+}
+
+#[test]
+fn test_parse_glob() {
+    let dir = std::env::temp_dir().join(format!("eb-parse-glob-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(dir.join("one.txt"), "ab").unwrap();
+    fs::write(dir.join("two.txt"), "ba").unwrap();
+    fs::write(dir.join("ignored.dat"), "c").unwrap();
+
+    let output = run_parse(&dir, "*.txt", &[]);
+    let one = fs::read_to_string(dir.join("one.xml"));
+    let two = fs::read_to_string(dir.join("two.xml"));
+    let ignored = dir.join("ignored.xml").exists();
+    fs::remove_dir_all(&dir).unwrap();
+
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(one.unwrap(), "<doc><letter>a</letter><letter>b</letter></doc>\n");
+    assert_eq!(two.unwrap(), "<doc><letter>b</letter><letter>a</letter></doc>\n");
+    assert!(!ignored);
+    assert_eq!(String::from_utf8(output.stdout).unwrap().lines().count(), 2);
+}
+
+#[test]
+fn test_parse_measure() {
+    let dir = std::env::temp_dir().join(format!("eb-parse-measure-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(dir.join("one.txt"), "abab").unwrap();
+
+    let output = run_parse(&dir, "one.txt", &["--measure"]);
+    fs::remove_dir_all(&dir).unwrap();
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    // the parse output itself is unaffected
+    assert!(String::from_utf8(output.stdout).unwrap().starts_with("<doc>"));
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.lines().next().unwrap().ends_with(" tasks"), "{stderr}");
+    assert!(stderr.lines().any(|line| line.trim_start().ends_with("  letter")), "{stderr}");
+}
+
+#[test]
+fn test_parse_pretty() {
+    let dir = std::env::temp_dir().join(format!("eb-parse-pretty-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(dir.join("one.txt"), "ab").unwrap();
+
+    let output = run_parse(&dir, "one.txt", &["-o", "XML-PRETTY"]);
+    fs::remove_dir_all(&dir).unwrap();
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(String::from_utf8(output.stdout).unwrap(),
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<doc>\n  <letter>a</letter>\n  <letter>b</letter>\n</doc>\n");
+}
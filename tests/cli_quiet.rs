@@ -0,0 +1,48 @@
+use std::process::Command;
+use std::fs;
+
+static CATALOG: &str =
+r##"<test-catalog xmlns='https://github.com/invisibleXML/ixml/test-catalog'>
+  <test-set name='quiet'>
+    <ixml-grammar>doc = "a".</ixml-grammar>
+    <test-case name='single'>
+      <test-string>a</test-string>
+      <assert-xml><doc>a</doc></assert-xml>
+    </test-case>
+  </test-set>
+</test-catalog>"##;
+
+static EXPECTED_STDOUT: &str = "Total tests: 1. (1 passed, 0 failed, 0 aborted)\nFailures:\n\n";
+
+/// run `eb [extra args] suite <catalog>` against a one-test catalog, with all logging enabled
+fn run_suite(name: &str, extra_args: &[&str]) -> std::process::Output {
+    let dir = std::env::temp_dir().join(format!("eb-{name}-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let catalog = dir.join("test-catalog.xml");
+    fs::write(&catalog, CATALOG).unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_eb"))
+        .env("RUST_LOG", "trace")
+        .args(extra_args)
+        .arg("suite")
+        .arg(&catalog)
+        .output()
+        .expect("failed to run eb");
+    fs::remove_dir_all(&dir).unwrap();
+    output
+}
+
+#[test]
+fn test_quiet_suite_output() {
+    let output = run_suite("quiet", &["--quiet"]);
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), EXPECTED_STDOUT);
+    assert!(output.stderr.is_empty(), "{}", String::from_utf8_lossy(&output.stderr));
+}
+
+/// even at trace level, diagnostics (including from parsing & `tree_to_testfmt`) never touch stdout
+#[test]
+fn test_logging_stays_off_stdout() {
+    let output = run_suite("trace", &[]);
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), EXPECTED_STDOUT);
+    assert!(!output.stderr.is_empty());
+}
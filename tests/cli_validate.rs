@@ -0,0 +1,37 @@
+use std::process::Command;
+use std::fs;
+
+/// run `eb --quiet validate -g <file>` on the given grammar source
+fn run_validate(name: &str, ixml: &str) -> std::process::Output {
+    let path = std::env::temp_dir().join(format!("eb-validate-{name}-{}.ixml", std::process::id()));
+    fs::write(&path, ixml).unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_eb"))
+        .args(["--quiet", "validate", "-g"])
+        .arg(&path)
+        .output()
+        .expect("failed to run eb");
+    fs::remove_file(&path).unwrap();
+    output
+}
+
+#[test]
+fn test_validate_ok() {
+    let output = run_validate("ok", r#"doc = a, "b". a = "a"."#);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert!(String::from_utf8(output.stdout).unwrap().ends_with("2 rules OK\n"));
+}
+
+#[test]
+fn test_validate_undefined_reference() {
+    let output = run_validate("undefined", r#"doc = a, missing. a = "a"."#);
+    assert_eq!(output.status.code(), Some(1));
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("missing (referenced from doc)"), "{stderr}");
+}
+
+#[test]
+fn test_validate_unused_rule_warns() {
+    let output = run_validate("unused", r#"doc = "a". extra = "b"."#);
+    assert!(output.status.success());
+    assert!(String::from_utf8(output.stdout).unwrap().contains("warning: rule extra is never used"));
+}
@@ -1,6 +1,6 @@
 use earleybird::builtin_grammars::all_builtin_tests;
 use earleybird::ixml_grammar::ixml_str_to_grammar;
-use earleybird::parser::Parser;
+use earleybird::parser::{Parser, Content};
 use earleybird::testsuite_utils::{TestResult, TestGrammar, xml_canonicalize};
 
 #[test]
@@ -29,17 +29,18 @@ fn test_all_builtin() {
 
         let expecteds = testcase.expected;
         // for purposes here, assume only one valid result
-        let expected = if let Some(TestResult::AssertXml(x)) = expecteds.get(0) {
-            x
-        } else {
-            panic!("Tests not set up for ambiguity");
-        };
-
-        // run the test
         let mut parser = Parser::new(grammar);
-        let arena = parser.parse(&testcase.input).unwrap_or_else(|e| panic!("{e}"));
-        let result = Parser::tree_to_testfmt(&arena);
-        assert_eq!(xml_canonicalize(&result), xml_canonicalize(expected), " on test {name}");
+        match expecteds.get(0) {
+            Some(TestResult::AssertXml(expected)) => {
+                let arena = parser.parse(&testcase.input).unwrap_or_else(|e| panic!("{e}"));
+                let result = Parser::tree_to_testfmt(&arena);
+                assert_eq!(xml_canonicalize(&result), xml_canonicalize(expected), " on test {name}");
+            }
+            Some(TestResult::AssertNotASentence) => {
+                assert!(parser.parse(&testcase.input).is_err(), "expected no parse on test {name}");
+            }
+            _ => panic!("Tests not set up for ambiguity"),
+        }
     }
 }
 
@@ -99,3 +100,28 @@ fn test_ixml_parser() {
 
     }
 }
+
+#[test]
+fn test_no_synthesized_names_in_output() {
+    let _ = env_logger::builder().is_test(true).try_init();
+
+    for testcase in all_builtin_tests().into_iter() {
+        let name = testcase.name;
+        for grammar in testcase.grammars {
+            let g = match grammar {
+                TestGrammar::Parsed(g) => g,
+                TestGrammar::Unparsed(_) => continue,
+            };
+            let mut parser = Parser::new(g);
+            let arena = match parser.parse(&testcase.input) {
+                Ok(arena) => arena,
+                Err(_) => continue,
+            };
+            for node in arena.iter() {
+                if let Content::Element(n) | Content::Attribute(n, _) = node.get() {
+                    assert!(!n.starts_with('-') && !n.contains('.'), "synthesized name {n} leaked into output on test {name}");
+                }
+            }
+        }
+    }
+}